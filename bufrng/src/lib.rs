@@ -1,6 +1,8 @@
 pub use rand;
 pub use rand::RngCore;
 
+pub mod shrink;
+
 pub struct BufRng<'a> {
     buf: &'a [u8],
 }
@@ -89,7 +91,7 @@ mod tests {
         assert_eq!(i, 1);
 
         let b: bool = rng.gen_bool(0.5);
-        assert_eq!(b, true);
+        assert!(b);
 
         for _ in 0..16 {
             let i: u16 = rng.gen();
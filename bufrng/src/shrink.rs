@@ -0,0 +1,252 @@
+//! Shrinking `BufRng` byte-seeds.
+//!
+//! A generator driven by a `BufRng` turns every byte-seed into an output.
+//! Shorter seeds and smaller bytes make smaller choices: once the buffer is
+//! exhausted every value is zero, which picks the first alternative and the
+//! fewest iterations. `shrink` deletes chunks, zeroes and lowers bytes of a
+//! seed as long as the output it generates stays interesting, so reproducers
+//! become small and choose minimal alternatives.
+
+use std::collections::HashMap;
+
+use crate::BufRng;
+
+/// The result of `shrink`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shrunk {
+    /// The smallest byte-seed whose output is interesting
+    pub buf: Vec<u8>,
+
+    /// The output generated from `buf`
+    pub output: Vec<u8>,
+
+    /// Number of times the predicate was run
+    pub tests: usize,
+}
+
+/// Sizes of the chunks that are deleted and zeroed. Most values are read as
+/// a `u32` or `u64`, so these line up with whole choices.
+const CHUNK_SIZES: [usize; 4] = [8, 4, 2, 1];
+
+struct Shrinker<G, P> {
+    generate: G,
+    interesting: P,
+
+    /// The smallest interesting seed so far, without trailing zeros
+    best: Vec<u8>,
+    output: Vec<u8>,
+
+    /// Whether the outputs that were already tested are interesting, so
+    /// seeds generating the same output as another one are not tested again
+    verdicts: HashMap<Vec<u8>, bool>,
+    tests: usize,
+}
+
+impl<G, P> Shrinker<G, P>
+where
+    G: FnMut(&mut BufRng, &mut Vec<u8>),
+    P: FnMut(&[u8]) -> bool,
+{
+    /// Replace the best seed with `candidate` if it is smaller and its output
+    /// is interesting
+    fn consider(&mut self, mut candidate: Vec<u8>) -> bool {
+        // Reading past the end of the buffer gives zeros, so trailing zeros
+        // do not change the output
+        while candidate.last() == Some(&0) {
+            candidate.pop();
+        }
+        if !shortlex_less(&candidate, &self.best) {
+            return false;
+        }
+
+        let mut output = Vec::new();
+        (self.generate)(&mut BufRng::new(&candidate), &mut output);
+        let interesting = match self.verdicts.get(&output) {
+            Some(&interesting) => interesting,
+            None => {
+                self.tests += 1;
+                let interesting = (self.interesting)(&output);
+                self.verdicts.insert(output.clone(), interesting);
+                interesting
+            }
+        };
+        if !interesting {
+            return false;
+        }
+
+        self.best = candidate;
+        self.output = output;
+        true
+    }
+
+    /// Delete chunks of bytes, starting at the end
+    fn delete_chunks(&mut self) -> bool {
+        let mut progress = false;
+        for &size in CHUNK_SIZES.iter() {
+            let mut start = self.best.len().saturating_sub(size);
+            loop {
+                if start + size <= self.best.len() {
+                    let mut candidate = self.best.clone();
+                    candidate.drain(start..start + size);
+                    progress |= self.consider(candidate);
+                }
+                if start == 0 {
+                    break;
+                }
+                start -= 1;
+            }
+        }
+        progress
+    }
+
+    /// Set chunks of bytes to zero
+    fn zero_chunks(&mut self) -> bool {
+        let mut progress = false;
+        for &size in CHUNK_SIZES.iter() {
+            let mut start = 0;
+            while start + size <= self.best.len() {
+                if self.best[start..start + size].iter().any(|&byte| byte != 0) {
+                    let mut candidate = self.best.clone();
+                    candidate[start..start + size].fill(0);
+                    progress |= self.consider(candidate);
+                }
+                start += size;
+            }
+        }
+        progress
+    }
+
+    /// Lower single bytes, trying the smallest values first. Values that
+    /// differ in a single bit are tried too, as bits further down a value
+    /// can decide whether a sample is rejected and drawn again.
+    fn lower_bytes(&mut self) -> bool {
+        let mut progress = false;
+        let mut idx = 0;
+        while idx < self.best.len() {
+            let value = self.best[idx];
+            let top_bit = 0x80u8 >> value.leading_zeros().min(7);
+            let mut lower = [
+                value & !top_bit,
+                value >> 1,
+                value & value.wrapping_sub(1),
+                value.saturating_sub(1),
+            ];
+            lower.sort_unstable();
+            for lower in lower {
+                if lower < self.best.get(idx).copied().unwrap_or(0) {
+                    let mut candidate = self.best.clone();
+                    candidate[idx] = lower;
+                    progress |= self.consider(candidate);
+                }
+            }
+            idx += 1;
+        }
+        progress
+    }
+}
+
+/// Whether `a` is shorter than `b`, or as long and lexicographically smaller
+fn shortlex_less(a: &[u8], b: &[u8]) -> bool {
+    (a.len(), a) < (b.len(), b)
+}
+
+/// Shrink the byte-seed `buf`, whose output is interesting, to a smaller one
+/// whose output `generate` creates from a `BufRng` is still interesting,
+/// e.g.
+///
+/// ```ignore
+/// let shrunk = shrink(
+///     &crash,
+///     |rng, out| GrammarGenerator::generate_into(out, None, rng),
+///     |input| target_crashes(input),
+/// );
+/// ```
+///
+/// The predicate is only run once for every distinct output.
+pub fn shrink<G, P>(buf: &[u8], generate: G, interesting: P) -> Shrunk
+where
+    G: FnMut(&mut BufRng, &mut Vec<u8>),
+    P: FnMut(&[u8]) -> bool,
+{
+    let mut shrinker = Shrinker {
+        generate,
+        interesting,
+        best: buf.to_vec(),
+        output: Vec::new(),
+        verdicts: HashMap::new(),
+        tests: 0,
+    };
+    (shrinker.generate)(&mut BufRng::new(buf), &mut shrinker.output);
+    shrinker.verdicts.insert(shrinker.output.clone(), true);
+
+    // Trailing zeros are dropped without testing, their output is the same
+    let mut normalized = buf.to_vec();
+    while normalized.last() == Some(&0) {
+        normalized.pop();
+    }
+    shrinker.best = normalized;
+
+    loop {
+        let mut progress = shrinker.delete_chunks();
+        progress |= shrinker.zero_chunks();
+        progress |= shrinker.lower_bytes();
+        if !progress {
+            break;
+        }
+    }
+
+    Shrunk {
+        buf: shrinker.best,
+        output: shrinker.output,
+        tests: shrinker.tests,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Up to 16 words of up to 8 lowercase letters, separated by spaces
+    fn words(rng: &mut BufRng, out: &mut Vec<u8>) {
+        out.clear();
+        for _ in 0..rng.gen_range(0..16) {
+            for _ in 0..rng.gen_range(1..8) {
+                out.push(b'a' + rng.gen_range(0..26u8));
+            }
+            out.push(b' ');
+        }
+    }
+
+    #[test]
+    fn shrinks_to_minimal_choices() {
+        // A seed from a random generator whose output contains an `x`
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut output = Vec::new();
+        let buf = loop {
+            let buf: Vec<u8> = (0..256).map(|_| rng.gen()).collect();
+            words(&mut BufRng::new(&buf), &mut output);
+            if output.contains(&b'x') {
+                break buf;
+            }
+        };
+
+        let shrunk = shrink(&buf, words, |output| output.contains(&b'x'));
+        assert!(shrunk.buf.len() < buf.len());
+        assert!(shrunk.tests > 0);
+
+        let mut output = Vec::new();
+        words(&mut BufRng::new(&shrunk.buf), &mut output);
+        assert_eq!(output, shrunk.output);
+        assert_eq!(shrunk.output, b"x ");
+
+        // Junk after the bytes that are read generates the same output, so
+        // the shorter seed is accepted with the verdict of that output
+        let mut padded = shrunk.buf.clone();
+        padded.extend((0..40).map(|_| rng.gen_range(1..=255u8)));
+        let reshrunk = shrink(&padded, words, |output| output.contains(&b'x'));
+        assert_eq!(reshrunk.buf, shrunk.buf);
+        assert_eq!(reshrunk.output, b"x ");
+    }
+}
@@ -1,9 +1,27 @@
-pub mod generator;
+fzero::grammar!("../../grammars/simplehttp.json", max_depth = 128);
+
+use rand::SeedableRng;
 use std::io::{self, Write};
 
+/// Usage: `http [--seed <seed>]`, with the seed in hex as it is printed. A
+/// random seed is used by default.
 fn main() -> io::Result<()> {
-    let mut rng = rand::thread_rng();
-    let out = generator::GrammarGenerator::generate_new(None, &mut rng);
+    let args: Vec<String> = std::env::args().collect();
+    let seed = match args.get(1).map(String::as_str) {
+        Some("--seed") => match args.get(2).and_then(|seed| fzero::drive::parse_seed(seed)) {
+            Some(seed) => seed,
+            None => {
+                eprintln!("usage: {} [--seed <hex seed>]", args[0]);
+                std::process::exit(2);
+            }
+        },
+        _ => rand::random(),
+    };
+    eprintln!("seed {:016x}", seed);
+
+    let mut rng = rand_pcg::Pcg64::seed_from_u64(seed);
+    let mut out = Vec::new();
+    generator::GrammarGenerator::generate_into(&mut out, None, &mut rng);
 
     let mut stdout = io::stdout().lock();
     stdout.write_all(&out)?;
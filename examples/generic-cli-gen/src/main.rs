@@ -1,5 +1,9 @@
-pub mod generator;
+fzero_gen::include_generator!();
+
 use clap::Parser;
+use clap::ValueEnum;
+use fzero_gen::drive::{self, Driver, LengthDelimited};
+use rand::SeedableRng;
 use std::{
     io::{self, Write},
     path::PathBuf,
@@ -8,9 +12,10 @@ use std::{
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Number of times to greet
-    #[arg(short, long, default_value_t = 1)]
-    count: u8,
+    /// Number of inputs to generate, 1 by default and unbounded with
+    /// `--stream`
+    #[arg(short, long)]
+    count: Option<u64>,
 
     #[arg(
         short,
@@ -21,42 +26,125 @@ struct Args {
 
     #[arg(short, long, help = "max depth passed to grammar generator")]
     max_depth: Option<usize>,
+
+    #[arg(
+        short,
+        long,
+        help = "number of generation threads, default one per CPU"
+    )]
+    threads: Option<usize>,
+
+    #[arg(
+        short,
+        long,
+        value_parser = parse_seed,
+        help = "master seed in hex as it is printed, random by default"
+    )]
+    seed: Option<u64>,
+
+    #[arg(
+        short,
+        long,
+        value_parser = parse_seed,
+        help = "regenerate the single input with this seed, in hex as in its file name"
+    )]
+    replay: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["outpath", "threads", "replay"],
+        help = "stream inputs to stdout one after another on one thread, without holding an input in memory"
+    )]
+    stream: Option<Delimiter>,
+}
+
+/// How streamed inputs are told apart
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Delimiter {
+    /// Every input is followed by a newline
+    Newline,
+
+    /// Every input is a series of chunks, each preceded by its length as a
+    /// little-endian u32, and ends with an empty chunk
+    Length,
+}
+
+fn parse_seed(seed: &str) -> Result<u64, String> {
+    drive::parse_seed(seed).ok_or_else(|| format!("invalid seed {:?}", seed))
 }
 
 fn main() -> io::Result<()> {
     let args = Args::parse();
-    let mut rng = rand::thread_rng();
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let max_depth = args.max_depth;
 
-    let mut out = Vec::with_capacity(1024);
+    if let Some(delimiter) = args.stream {
+        return stream(args.count, seed, max_depth, delimiter);
+    }
 
-    if let Some(outpath) = args.outpath {
-        if args.count == 1 && !outpath.exists() {
-            if let Some(parent) = outpath.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            generator::GrammarGenerator::generate_into(&mut out, args.max_depth, &mut rng);
-            std::fs::write(outpath, &out)?;
-        } else {
-            if !outpath.exists() {
-                std::fs::create_dir_all(&outpath)?;
-            }
-            let mut filepath = outpath.clone();
-            for i in 0..args.count {
-                generator::GrammarGenerator::generate_into(&mut out, args.max_depth, &mut rng);
-                filepath.push(format!("{}", i));
-                std::fs::write(&outpath, &out)?;
-                filepath.pop();
+    let count = args.count.unwrap_or(1);
+    if count == 1 || args.replay.is_some() {
+        let input_seed = args.replay.unwrap_or_else(|| drive::input_seed(seed, 0));
+        let out = drive::replay(input_seed, |out, rng| {
+            generator::GrammarGenerator::generate_into(out, max_depth, rng)
+        });
+        eprintln!("seed {:016x}", input_seed);
+        match args.outpath {
+            Some(outpath) if !outpath.is_dir() => {
+                if let Some(parent) = outpath.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(outpath, &out)?;
             }
+            Some(outpath) => std::fs::write(outpath.join(drive::file_name(0, input_seed)), &out)?,
+            None => io::stdout().lock().write_all(&out)?,
         }
-    } else {
-        let mut stdout = io::stdout().lock();
-        for _ in 0..args.count {
-            generator::GrammarGenerator::generate_into(&mut out, args.max_depth, &mut rng);
-            stdout.write_all(&out)?;
-            if args.count > 1 {
-                stdout.write(b"\n")?;
+        return Ok(());
+    }
+
+    let mut driver = Driver::new(count).seed(seed);
+    if let Some(threads) = args.threads {
+        driver = driver.threads(threads);
+    }
+    driver = match args.outpath {
+        Some(outpath) => driver.directory(outpath),
+        None => driver.stream(io::stdout()),
+    };
+
+    let report =
+        driver.run(|out, rng| generator::GrammarGenerator::generate_into(out, max_depth, rng))?;
+    eprintln!("seed {:016x}: {}", seed, report);
+
+    Ok(())
+}
+
+/// Stream `count` inputs, or until stdout is closed, to stdout. Input `i` is
+/// generated from the seed `drive::input_seed(seed, i)`, like in a corpus.
+fn stream(
+    count: Option<u64>,
+    seed: u64,
+    max_depth: Option<usize>,
+    delimiter: Delimiter,
+) -> io::Result<()> {
+    eprintln!("seed {:016x}", seed);
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let result = (0..count.unwrap_or(u64::MAX)).try_for_each(|index| {
+        let mut rng = rand_pcg::Pcg64::seed_from_u64(drive::input_seed(seed, index));
+        match delimiter {
+            Delimiter::Newline => {
+                generator::GrammarGenerator::generate_to_writer(&mut out, max_depth, &mut rng)?;
+                out.write_all(b"\n")
+            }
+            Delimiter::Length => {
+                let mut framed = LengthDelimited::new(&mut out);
+                generator::GrammarGenerator::generate_to_writer(&mut framed, max_depth, &mut rng)?;
+                framed.end()
             }
         }
+    });
+    match result.and_then(|()| out.flush()) {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
     }
-    Ok(())
 }
@@ -0,0 +1,11 @@
+//! The fzero generator library (`fzero_gen`) together with the `grammar!`
+//! macro of `fzero-macros`, so both are available under one name.
+//!
+//! ```ignore
+//! fzero::grammar!("grammars/http.json", start = "<start>", max_depth = 128);
+//!
+//! let input = generator::GrammarGenerator::generate_new(None, &mut rng);
+//! ```
+
+pub use fzero_gen::*;
+pub use fzero_macros::grammar;
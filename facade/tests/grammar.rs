@@ -0,0 +1,16 @@
+use rand::SeedableRng;
+
+fzero::grammar!("../grammars/numbers.json", start = "<integer>");
+
+#[test]
+fn grammar_macro_is_reexported() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let out = generator::GrammarGenerator::generate_new(None, &mut rng);
+        assert!(!out.is_empty());
+        assert!(out.iter().all(|b| b.is_ascii_digit() || *b == b'-'));
+    }
+
+    // The library is re-exported as well
+    assert_eq!(fzero::drive::parse_seed("ff"), Some(0xff));
+}
@@ -0,0 +1,167 @@
+//! Generate the code of a grammar at compile time, without a `build.rs`
+//! that writes into the source tree.
+//!
+//! ```ignore
+//! fzero_macros::grammar!("grammars/http.json", start = "<start>", max_depth = 128);
+//!
+//! let input = generator::GrammarGenerator::generate_new(None, &mut rng);
+//! ```
+
+use std::path::PathBuf;
+
+use fzero_gen::frontend;
+use fzero_gen::{Backend, GrammarRust, OptLevel};
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{Ident, LitBool, LitInt, LitStr, Token};
+
+/// Arguments of `grammar!`
+struct Args {
+    /// Path of the grammar, relative to the crate using the macro
+    path: LitStr,
+
+    /// Name of the start rule
+    start: Option<LitStr>,
+
+    /// Default max depth of the generated functions
+    max_depth: usize,
+
+    /// Name of the generated module
+    module: Ident,
+
+    /// Optimization level, as given with `-O`
+    opt: OptLevel,
+
+    /// Shape of the generated code
+    backend: Backend,
+
+    /// Whether the generated code counts the choices of every alternative
+    coverage: bool,
+
+    /// Whether the generated code supports adaptive generation
+    adaptive: bool,
+
+    /// Largest size of sized sampling, if the generated code supports it
+    sized: Option<usize>,
+
+    /// Whether the generated code can write inputs to an `io::Write`
+    streaming: bool,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args {
+            path: input.parse()?,
+            start: None,
+            max_depth: 128,
+            module: format_ident!("generator"),
+            opt: OptLevel::default(),
+            backend: Backend::default(),
+            coverage: false,
+            adaptive: false,
+            sized: None,
+            streaming: false,
+        };
+
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "start" => args.start = Some(input.parse()?),
+                "max_depth" => args.max_depth = input.parse::<LitInt>()?.base10_parse()?,
+                "module" => args.module = input.parse()?,
+                "opt" => {
+                    let level: LitInt = input.parse()?;
+                    args.opt = OptLevel::from_name(&level.to_string())
+                        .ok_or_else(|| syn::Error::new(level.span(), "expected 0, 1 or 2"))?;
+                }
+                "coverage" => args.coverage = input.parse::<LitBool>()?.value,
+                "adaptive" => args.adaptive = input.parse::<LitBool>()?.value,
+                "sized" => args.sized = Some(input.parse::<LitInt>()?.base10_parse()?),
+                "streaming" => args.streaming = input.parse::<LitBool>()?.value,
+                "backend" => {
+                    let name: LitStr = input.parse()?;
+                    args.backend = Backend::from_name(&name.value()).ok_or_else(|| {
+                        syn::Error::new(name.span(), "expected \"functions\" or \"table\"")
+                    })?;
+                }
+                _ => {
+                    return Err(syn::Error::new(
+                        key.span(),
+                        "expected one of `start`, `max_depth`, `module`, `opt`, `backend`, `coverage`, `adaptive`, `sized` or `streaming`",
+                    ))
+                }
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Expand to a module (`generator` unless given with `module = name`)
+/// containing the code generated for a grammar. The grammar is loaded
+/// relative to the directory of the crate's `Cargo.toml`, in any format
+/// supported by `fzero_gen::frontend`, and the crate is rebuilt whenever it
+/// changes.
+///
+/// Optional arguments are `start = "<rule>"`, `max_depth = N` (default 128),
+/// `opt = 0|1|2`, `backend = "functions"|"table"`, `coverage = true`,
+/// `adaptive = true`, `sized = N` and `streaming = true`.
+#[proc_macro]
+pub fn grammar(input: TokenStream) -> TokenStream {
+    let args = syn::parse_macro_input!(input as Args);
+    match generate(&args) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn generate(args: &Args) -> syn::Result<proc_macro2::TokenStream> {
+    let error = |message: String| syn::Error::new(args.path.span(), message);
+
+    let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    path.push(args.path.value());
+    let grammar = frontend::load(&path, None)
+        .map_err(|err| error(format!("failed to load {}: {}", path.display(), err)))?;
+
+    let start = args
+        .start
+        .as_ref()
+        .map_or_else(|| "<start>".to_string(), LitStr::value);
+    if !grammar.contains_rule(&start) {
+        return Err(error(format!("the grammar has no rule {}", start)));
+    }
+
+    let mut gram = GrammarRust::try_new(&grammar, Some(&start))
+        .map_err(|err| error(format!("invalid grammar {}: {}", path.display(), err)))?;
+    gram.backend = args.backend;
+    gram.coverage = args.coverage;
+    gram.adaptive = args.adaptive;
+    gram.sized_sampling = args.sized;
+    gram.streaming = args.streaming;
+    gram.optimize_level(args.opt);
+
+    // Lints of the generated code are of no use to the crate using the macro
+    let items: proc_macro2::TokenStream = gram
+        .module_source(args.max_depth)
+        .parse()
+        .map_err(|err| error(format!("generated code does not parse: {}", err)))?;
+
+    let module = &args.module;
+    let tracked = path.to_string_lossy().into_owned();
+    Ok(quote! {
+        #[allow(unused, clippy::all)]
+        pub mod #module {
+            // Rebuild when the grammar changes
+            const _: &[u8] = include_bytes!(#tracked);
+
+            #items
+        }
+    })
+}
@@ -0,0 +1,195 @@
+use rand::{Rng, SeedableRng};
+
+fzero_macros::grammar!(
+    "../grammars/simplehttp.json",
+    max_depth = 64,
+    adaptive = true,
+    sized = 200,
+    streaming = true
+);
+fzero_macros::grammar!(
+    "../grammars/numbers.json",
+    start = "<integer>",
+    module = numbers,
+    backend = "table",
+    coverage = true,
+    streaming = true
+);
+fzero_macros::grammar!(
+    "../grammars/simplehttp.json",
+    max_depth = 64,
+    module = simplehttp_table,
+    backend = "table"
+);
+fzero_macros::grammar!("tests/digits.json", module = digits, sized = 32);
+fzero_macros::grammar!(
+    "tests/large_repeat.json",
+    module = large_repeat,
+    backend = "table",
+    streaming = true
+);
+
+#[test]
+fn generates_requests() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let out = generator::GrammarGenerator::generate_new(None, &mut rng);
+        assert!(out.ends_with(b"\r\n\r\n"));
+    }
+}
+
+#[test]
+fn adapts_to_feedback() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut state = generator::Adaptive::new();
+    for _ in 0..100 {
+        let out = generator::GrammarGenerator::generate_adaptive_new(None, &mut rng, &mut state);
+        assert!(out.ends_with(b"\r\n\r\n"));
+        state.feedback(if out.starts_with(b"GET") { 1.0 } else { 0.0 });
+    }
+    assert_eq!(state.counts().len(), generator::CHOICE_POINTS);
+    assert!(state.counts().iter().any(|&count| count > 0));
+}
+
+#[test]
+fn backends_generate_the_same_inputs() {
+    for seed in 0..200 {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let functions = generator::GrammarGenerator::generate_new(None, &mut rng);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let table = simplehttp_table::GrammarGenerator::generate_new(None, &mut rng);
+        assert_eq!(functions, table);
+    }
+}
+
+#[test]
+fn repeats_push_one_frame() {
+    // The iterations are below the depth limit, so none of them are
+    // expanded and no frame is pushed per iteration
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let out = large_repeat::GrammarGenerator::generate_new(Some(1), &mut rng);
+    assert!(out.is_empty());
+
+    let mut streamed = Vec::new();
+    let len = large_repeat::GrammarGenerator::generate_to_writer(&mut streamed, Some(1), &mut rng)
+        .unwrap();
+    assert_eq!(len, 0);
+}
+
+#[test]
+fn generates_from_start_rule() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let out = numbers::GrammarGenerator::generate_new(None, &mut rng);
+        assert!(!out.is_empty());
+        assert!(out.iter().all(|b| b.is_ascii_digit() || *b == b'-'));
+    }
+
+    let mut counters = Vec::new();
+    numbers::GrammarGenerator::dump_coverage(&mut counters).unwrap();
+    assert!(String::from_utf8(counters)
+        .unwrap()
+        .starts_with("<digit>\t0\t"));
+}
+
+#[test]
+fn samples_by_size() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let sizes: Vec<usize> = (0..=generator::MAX_SIZE)
+        .filter(|&size| generator::GrammarGenerator::sized_count(size) > 0.0)
+        .collect();
+    assert!(!sizes.is_empty());
+    for &size in sizes.iter() {
+        let out = generator::GrammarGenerator::generate_sized_new(size, &mut rng).unwrap();
+        assert!(out.ends_with(b"\r\n\r\n"));
+    }
+    assert!(generator::GrammarGenerator::generate_sized_new(0, &mut rng).is_none());
+}
+
+#[test]
+fn streams_the_same_inputs() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut streamed_rng = rng.clone();
+    for _ in 0..100 {
+        let out = generator::GrammarGenerator::generate_new(None, &mut rng);
+        let mut streamed = Vec::new();
+        let len =
+            generator::GrammarGenerator::generate_to_writer(&mut streamed, None, &mut streamed_rng)
+                .unwrap();
+        assert_eq!(streamed, out);
+        assert_eq!(len, out.len() as u64);
+
+        let out = numbers::GrammarGenerator::generate_new(Some(8), &mut rng);
+        let mut streamed = Vec::new();
+        numbers::GrammarGenerator::generate_to_writer(&mut streamed, Some(8), &mut streamed_rng)
+            .unwrap();
+        assert_eq!(streamed, out);
+    }
+}
+
+/// The grammar as interpreted by `GrammarRust`, optimized like the macro does
+fn interpreted(path: &str, start: &str) -> fzero_gen::GrammarRust {
+    let grammar = fzero_gen::frontend::load(path, None).unwrap();
+    let mut gram = fzero_gen::GrammarRust::new(&grammar, Some(start));
+    gram.optimize_level(fzero_gen::OptLevel::default());
+    gram
+}
+
+#[test]
+fn matches_the_interpreter() {
+    let simplehttp = interpreted("../grammars/simplehttp.json", "<start>");
+    let numbers = interpreted("../grammars/numbers.json", "<integer>");
+
+    for seed in 0..200 {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut interpreter_rng = rng.clone();
+        assert_eq!(
+            generator::GrammarGenerator::generate_new(None, &mut rng),
+            simplehttp.generate_new(64, &mut interpreter_rng)
+        );
+        assert_eq!(
+            numbers::GrammarGenerator::generate_new(Some(12), &mut rng),
+            numbers.generate_new(12, &mut interpreter_rng)
+        );
+    }
+
+    // Byte-seeds of a `BufRng` are replayed the same way
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    for len in 0..200 {
+        let seed: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        assert_eq!(
+            generator::GrammarGenerator::generate_new(None, &mut bufrng::BufRng::new(&seed)),
+            simplehttp.generate_new(64, &mut bufrng::BufRng::new(&seed))
+        );
+        assert_eq!(
+            numbers::GrammarGenerator::generate_new(Some(12), &mut bufrng::BufRng::new(&seed)),
+            numbers.generate_new(12, &mut bufrng::BufRng::new(&seed))
+        );
+    }
+}
+
+#[test]
+fn sized_sampling_matches_the_sampler() {
+    use fzero_gen::sample::SizedSampler;
+
+    let simplehttp = interpreted("../grammars/simplehttp.json", "<start>");
+    let sampler = SizedSampler::new(&simplehttp, 200);
+    assert_eq!(sampler.max_size(), generator::MAX_SIZE);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+    let mut sampler_rng = rng.clone();
+    let mut sampled = Vec::new();
+    for size in 0..=generator::MAX_SIZE {
+        let out = generator::GrammarGenerator::generate_sized_new(size, &mut rng);
+        let found = sampler.sample_into(size, &mut sampled, &mut sampler_rng);
+        assert_eq!(out, Some(sampled.clone()).filter(|_| found));
+    }
+
+    // Repetitions longer than the largest size are cut off the same way
+    let digits = interpreted("tests/digits.json", "<start>");
+    let sampler = SizedSampler::new(&digits, 32);
+    for size in 0..=digits::MAX_SIZE {
+        let out = digits::GrammarGenerator::generate_sized_new(size, &mut rng);
+        let found = sampler.sample_into(size, &mut sampled, &mut sampler_rng);
+        assert_eq!(out, Some(sampled.clone()).filter(|_| found));
+    }
+}
@@ -0,0 +1,276 @@
+//! Generating code from a `build.rs` into `OUT_DIR`, so the source tree is
+//! never written to.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() -> std::io::Result<()> {
+//!     fzero_gen::build::Builder::new("grammars/url.json")
+//!         .env_override("GRAMMAR")
+//!         .max_depth(128)
+//!         .generate()?;
+//!     Ok(())
+//! }
+//!
+//! // src/main.rs
+//! fzero_gen::include_generator!();
+//! ```
+//!
+//! Builtin modules such as `<!numbers.digit>` are compiled into `fzero_gen`,
+//! so cargo already reruns the build script when they change.
+
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::{frontend, Backend, GrammarRust, OptLevel};
+
+/// Generates the code of a grammar from a build script
+#[derive(Clone, Debug)]
+pub struct Builder {
+    /// Path of the grammar, relative to the crate's `Cargo.toml`
+    grammar: PathBuf,
+
+    /// Environment variable that replaces the path of the grammar if set
+    env_override: Option<String>,
+
+    /// Name of the start rule
+    start: Option<String>,
+
+    /// Rules that can be generated on their own besides the start rule
+    roots: Vec<String>,
+
+    /// Default max depth of the generated functions
+    max_depth: usize,
+
+    /// Optimization level
+    opt: OptLevel,
+
+    /// Shape of the generated code
+    backend: Backend,
+
+    /// Whether the generated code contains `RULE_NAMES`
+    rule_names: bool,
+
+    /// Whether the generated code counts the choices of every alternative
+    coverage: bool,
+
+    /// Whether the generated code supports adaptive generation
+    adaptive: bool,
+
+    /// Largest size of sized sampling, if the generated code supports it
+    sized_sampling: Option<usize>,
+
+    /// Whether the generated code can write inputs to an `io::Write`
+    streaming: bool,
+
+    /// Directory to write to instead of `OUT_DIR`
+    out_dir: Option<PathBuf>,
+
+    /// Name of the generated file
+    file_name: String,
+}
+
+impl Builder {
+    /// Generate code for the grammar at `grammar`, in any format supported by
+    /// `frontend::load`
+    pub fn new(grammar: impl AsRef<Path>) -> Self {
+        Self {
+            grammar: grammar.as_ref().to_path_buf(),
+            env_override: None,
+            start: None,
+            roots: Vec::new(),
+            max_depth: 128,
+            opt: OptLevel::default(),
+            backend: Backend::default(),
+            rule_names: false,
+            coverage: false,
+            adaptive: false,
+            sized_sampling: None,
+            streaming: false,
+            out_dir: None,
+            file_name: "generator.rs".to_string(),
+        }
+    }
+
+    /// Use the grammar at the path in environment variable `name` instead,
+    /// if it is set
+    pub fn env_override(mut self, name: &str) -> Self {
+        self.env_override = Some(name.to_string());
+        self
+    }
+
+    /// Start generating from `rule` instead of `<start>`
+    pub fn start(mut self, rule: &str) -> Self {
+        self.start = Some(rule.to_string());
+        self
+    }
+
+    /// Keep `rule` during optimization, see `GrammarRust::add_root`
+    pub fn root(mut self, rule: &str) -> Self {
+        self.roots.push(rule.to_string());
+        self
+    }
+
+    /// Default max depth of the generated code, 128 if not set
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Optimization level
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.opt = level;
+        self
+    }
+
+    /// Shape of the generated code
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Include `RULE_NAMES` in the generated code
+    pub fn rule_names(mut self, rule_names: bool) -> Self {
+        self.rule_names = rule_names;
+        self
+    }
+
+    /// Count the choices of every alternative, see `coverage`
+    pub fn coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage;
+        self
+    }
+
+    /// Support adaptive generation, see `GrammarRust::adaptive`
+    pub fn adaptive(mut self, adaptive: bool) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
+    /// Support sampling by size up to `max_size`, see
+    /// `GrammarRust::sized_sampling`
+    pub fn sized_sampling(mut self, max_size: usize) -> Self {
+        self.sized_sampling = Some(max_size);
+        self
+    }
+
+    /// Support writing inputs to an `io::Write` in chunks, see
+    /// `GrammarRust::streaming`
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Write to `dir` instead of `OUT_DIR`
+    pub fn out_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.out_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Name of the generated file, `generator.rs` if not set
+    pub fn file_name(mut self, name: &str) -> Self {
+        self.file_name = name.to_string();
+        self
+    }
+
+    /// Generate the code and tell cargo to rerun the build script when the
+    /// grammar changes. Returns the path of the generated file.
+    pub fn generate(self) -> std::io::Result<PathBuf> {
+        let mut grammar_path = self.grammar.clone();
+        if let Some(name) = &self.env_override {
+            println!("cargo:rerun-if-env-changed={}", name);
+            if let Some(path) = std::env::var_os(name) {
+                grammar_path = PathBuf::from(path);
+            }
+        }
+        println!("cargo:rerun-if-changed={}", grammar_path.display());
+
+        let grammar = frontend::load(&grammar_path, None)?;
+        let start = self.start.as_deref().unwrap_or("<start>");
+        for rule in self.roots.iter().map(String::as_str).chain([start]) {
+            if !grammar.contains_rule(rule) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} has no rule {}", grammar_path.display(), rule),
+                ));
+            }
+        }
+
+        let mut gram = GrammarRust::try_new(&grammar, Some(start)).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", grammar_path.display(), err),
+            )
+        })?;
+        for root in self.roots.iter() {
+            gram.add_root(root);
+        }
+        gram.backend = self.backend;
+        gram.rule_names = self.rule_names;
+        gram.coverage = self.coverage;
+        gram.adaptive = self.adaptive;
+        gram.sized_sampling = self.sized_sampling;
+        gram.streaming = self.streaming;
+        gram.optimize_level(self.opt);
+
+        let out_dir = match self.out_dir {
+            Some(dir) => dir,
+            None => std::env::var_os("OUT_DIR")
+                .map(PathBuf::from)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "OUT_DIR is not set"))?,
+        };
+        let path = out_dir.join(&self.file_name);
+        std::fs::write(&path, gram.module_source(self.max_depth))?;
+
+        Ok(path)
+    }
+}
+
+/// Include the code generated by `build::Builder` as a module named
+/// `generator`, or the given name. If the builder was given a `file_name`,
+/// pass it as second argument.
+#[macro_export]
+macro_rules! include_generator {
+    () => {
+        $crate::include_generator!(generator);
+    };
+    ($module:ident) => {
+        $crate::include_generator!($module, "generator.rs");
+    };
+    ($module:ident, $file_name:expr) => {
+        #[allow(unused, clippy::all)]
+        pub mod $module {
+            include!(concat!(env!("OUT_DIR"), "/", $file_name));
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_into_out_dir() {
+        let dir = std::env::temp_dir().join(format!("fzero-build-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = Builder::new("grammars/numbers.json")
+            .start("<integer>")
+            .out_dir(&dir)
+            .file_name("numbers.rs")
+            .generate()
+            .unwrap();
+        assert_eq!(path, dir.join("numbers.rs"));
+        let source = std::fs::read_to_string(&path).unwrap();
+        assert!(!source.contains("#!["));
+        assert!(source.contains("pub struct GrammarGenerator;"));
+
+        let err = Builder::new("grammars/numbers.json")
+            .root("<nope>")
+            .out_dir(&dir)
+            .generate()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,47 +6,38 @@
 
 use lazy_static::lazy_static;
 
-use crate::{Fragment, FragmentId, Grammar, GrammarRust};
+use crate::{Fragment, FragmentId, Grammar, GrammarRust, Origin};
 
 lazy_static! {
     static ref STRING: GrammarRust = {
         let bytes = include_bytes!("../grammars/string.json");
         let grammar: Grammar = serde_json::from_slice(bytes).unwrap();
-        GrammarRust::construct(&grammar)
+        GrammarRust::construct(&grammar).unwrap()
     };
     static ref NUMBERS: GrammarRust = {
         let bytes = include_bytes!("../grammars/numbers.json");
         let grammar: Grammar = serde_json::from_slice(bytes).unwrap();
-        GrammarRust::construct(&grammar)
+        GrammarRust::construct(&grammar).unwrap()
     };
     static ref URL: GrammarRust = {
         let bytes = include_bytes!("../grammars/url.json");
         let grammar: Grammar = serde_json::from_slice(bytes).unwrap();
-        GrammarRust::construct(&grammar)
+        GrammarRust::construct(&grammar).unwrap()
     };
     static ref JSON: GrammarRust = {
         let bytes = include_bytes!("../grammars/json.json");
         let grammar: Grammar = serde_json::from_slice(bytes).unwrap();
-        GrammarRust::construct(&grammar)
+        GrammarRust::construct(&grammar).unwrap()
     };
     static ref HTTP: GrammarRust = {
         let bytes = include_bytes!("../grammars/http.json");
         let grammar: Grammar = serde_json::from_slice(bytes).unwrap();
-        GrammarRust::construct(&grammar)
+        GrammarRust::construct(&grammar).unwrap()
     };
 }
 
-fn extend_and_rename(
-    gram: &mut GrammarRust,
-    with: &GrammarRust,
-    rename_prefix: &str,
-    search_for: &str,
-) -> FragmentId {
-    log::debug!(
-        "loading builtin {} searching for {}",
-        rename_prefix,
-        search_for
-    );
+fn extend_and_rename(gram: &mut GrammarRust, with: &GrammarRust, module: &str) {
+    log::debug!("loading builtin {}", module);
 
     let off = gram.fragments.len();
     gram.fragments
@@ -57,52 +48,76 @@ fn extend_and_rename(
             Fragment::Expression(f) => {
                 Fragment::Expression(f.into_iter().map(|fid| FragmentId(fid.0 + off)).collect())
             }
+            Fragment::Repeat { fragment, min, max } => Fragment::Repeat {
+                fragment: FragmentId(fragment.0 + off),
+                min,
+                max,
+            },
             _ => f,
         }));
+    gram.labels.extend(
+        with.labels
+            .iter()
+            .map(|label| format!("{}_{}", module, label)),
+    );
 
-    let mut found = None;
-    for (name, fragment_id) in with.name_to_fragment.iter() {
-        let rename = if !name.starts_with("<!") {
-            name.replace("<", rename_prefix)
+    let rename_prefix = format!("<!{}.", module);
+    let rename = |name: &str| {
+        if !name.starts_with("<!") {
+            name.replace('<', &rename_prefix)
         } else {
-            name.clone()
-        };
-        log::debug!("renamed {} => {}", name, rename);
-        let new_fragment_id = FragmentId(fragment_id.0 + off);
-
-        if name == search_for || rename == search_for {
-            found = Some(new_fragment_id);
+            name.to_string()
         }
+    };
+    gram.origins
+        .extend(with.origins.iter().map(|origin| Origin {
+            rule: rename(&origin.rule),
+            ..origin.clone()
+        }));
 
-        gram.name_to_fragment.insert(rename, new_fragment_id);
-        // }
-    }
+    for (name, fragment_id) in with.name_to_fragment.iter() {
+        let rename = rename(name);
+        log::debug!("renamed {} => {}", name, rename);
 
-    if let Some(found) = found {
-        return found;
-    } else {
-        panic!(
-            "attempted to call invalid builtin {}{}>",
-            rename_prefix, search_for
-        );
+        gram.name_to_fragment
+            .insert(rename, FragmentId(fragment_id.0 + off));
     }
 }
 
-pub fn load_if_builtin(option: &str, gram: &mut GrammarRust) -> Option<FragmentId> {
-    if option.starts_with("<!") && option.ends_with(">") {
+/// Get the module name of a reference to a builtin rule like
+/// `<!numbers.digit>`, if the module exists
+pub fn builtin_module(option: &str) -> Option<&str> {
+    if option.starts_with("<!") && option.ends_with('>') {
         let option = &option[2..option.len() - 1];
-        if let Some(point_idx) = option.find(".") {
-            let (module, rule) = option.split_at(point_idx);
-            let rule = &format!("<{}>", &rule[1..]);
-            return match module {
-                "string" => Some(extend_and_rename(gram, &STRING, "<!string.", rule)),
-                "numbers" => Some(extend_and_rename(gram, &NUMBERS, "<!numbers.", rule)),
-                "url" => Some(extend_and_rename(gram, &URL, "<!url.", rule)),
-                "json" => Some(extend_and_rename(gram, &JSON, "<!json.", rule)),
-                "http" => Some(extend_and_rename(gram, &HTTP, "<!http.", rule)),
-                _ => None,
-            };
+        if let Some((module, _)) = option.split_once('.') {
+            if matches!(module, "string" | "numbers" | "url" | "json" | "http") {
+                return Some(module);
+            }
         }
     }
     None
 }
+
+/// Import all rules of a builtin module, renamed to `<!module.rule>`, unless
+/// the module was already imported (possibly through another builtin)
+pub fn import(gram: &mut GrammarRust, module: &str) {
+    let prefix = format!("<!{}.", module);
+    if gram
+        .name_to_fragment
+        .range(prefix.clone()..)
+        .next()
+        .is_some_and(|(name, _)| name.starts_with(&prefix))
+    {
+        return;
+    }
+
+    let with: &GrammarRust = match module {
+        "string" => &STRING,
+        "numbers" => &NUMBERS,
+        "url" => &URL,
+        "json" => &JSON,
+        "http" => &HTTP,
+        _ => unreachable!("unknown builtin module {}", module),
+    };
+    extend_and_rename(gram, with, module);
+}
@@ -1,36 +1,589 @@
+use fzero_gen::frontend::{self, Format};
 use fzero_gen::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "usage:
+    fzero_cli <grammar> <output Rust file> [default max depth] [-O 0|1|2] [--no-opt] [--verify N] [--root <rule>]... [--rule-names] [--coverage] [--adaptive] [--sized N] [--streaming] [--backend functions|table]
+    fzero_cli convert <grammar> <output json> [--format abnf|antlr|json]
+    fzero_cli export <grammar> <output> --format nautilus|antlr|gramatron|dot [--stack-limit N]
+    fzero_cli diff <old grammar> <new grammar> [--seeds <dir>] [--depth D]
+    fzero_cli coverage <grammar> <counters>... [-O 0|1|2] [--root <rule>]... [--merge <output>]
+    fzero_cli enumerate <grammar> <output dir> [--max-depth N] [--limit M] [-O 0|1|2] [--k-path K]
+    fzero_cli stats <grammar> [-n N] [--depth D] [-O 0|1|2] [--top K]
+    fzero_cli reduce <grammar> <input> <output> -- <command> [args]...";
+
+/// Turn a grammar into fragments, reporting an invalid grammar as an error
+fn build(grammar: &Grammar) -> std::io::Result<GrammarRust> {
+    GrammarRust::try_new(grammar, None)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Load a grammar file in any format and turn it into fragments
+fn load(path: &str) -> std::io::Result<GrammarRust> {
+    build(&frontend::load(path, None)?)
+}
+
+/// Remove `--name <value>` from the argument list and return the value
+fn take_flag(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let idx = args.iter().position(|arg| arg == name)?;
+    if idx + 1 >= args.len() {
+        log::warn!("missing value for {}", name);
+        return None;
+    }
+    args.remove(idx);
+    Some(args.remove(idx))
+}
+
+/// Remove `--name` from the argument list and return whether it was given
+fn take_switch(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove the optimization level, given as `-O <level>`, `-O<level>` or
+/// `--no-opt`, from the argument list. Returns `None` if it is invalid.
+fn take_opt_level(args: &mut Vec<String>) -> Option<OptLevel> {
+    let mut level = OptLevel::default();
+    if take_switch(args, "--no-opt") {
+        level = OptLevel::None;
+    }
+    if let Some(idx) = args.iter().position(|arg| arg.starts_with("-O")) {
+        let name = match args.remove(idx).as_str() {
+            "-O" if idx < args.len() => args.remove(idx),
+            arg => arg[2..].to_string(),
+        };
+        level = match OptLevel::from_name(&name) {
+            Some(level) => level,
+            None => {
+                log::warn!("unknown optimization level {:?}", name);
+                return None;
+            }
+        };
+    }
+    Some(level)
+}
+
+/// Convert a grammar in any supported input format to the JSON format
+fn convert(mut args: Vec<String>) -> std::io::Result<()> {
+    let format = match take_flag(&mut args, "--format") {
+        Some(name) => match Format::from_name(&name) {
+            Some(format) => Some(format),
+            None => {
+                log::warn!("unknown grammar format {:?}", name);
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    if args.len() != 2 {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    let grammar = frontend::load(&args[0], format)?;
+    log::info!("Parsed grammar from {}", args[0]);
+
+    std::fs::write(&args[1], frontend::to_json(&grammar))?;
+    log::info!("Wrote JSON grammar to {}", args[1]);
+
+    Ok(())
+}
+
+/// Export a grammar to the format of another fuzzer
+fn export(mut args: Vec<String>) -> std::io::Result<()> {
+    let format = take_flag(&mut args, "--format").and_then(|name| export::Format::from_name(&name));
+    let stack_limit = take_flag(&mut args, "--stack-limit").map(|limit| limit.parse::<usize>());
+    let (format, stack_limit) = match (format, stack_limit, args.len()) {
+        (Some(format), None, 2) => (format, 16),
+        (Some(format), Some(Ok(stack_limit)), 2) => (format, stack_limit),
+        _ => {
+            log::warn!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let grammar = frontend::load(&args[0], None)?;
+    let mut gram = build(&grammar)?;
+
+    let output = match format {
+        export::Format::Nautilus => export::nautilus(&gram),
+        export::Format::Antlr => export::antlr(&gram, "Fzero"),
+        export::Format::Gramatron => export::gramatron(&gram, stack_limit),
+        export::Format::Dot => {
+            // The fragment graph is most useful in its optimized form
+            gram.optimize();
+            export::dot(&gram)
+        }
+    };
+    std::fs::write(&args[1], output)?;
+    log::info!("Exported grammar to {}", args[1]);
+
+    Ok(())
+}
+
+/// Report the rules that changed between two versions of a grammar and the
+/// saved byte-seeds that now generate a different input
+fn diff(mut args: Vec<String>) -> std::io::Result<()> {
+    let seeds = take_flag(&mut args, "--seeds");
+    let max_depth = take_flag(&mut args, "--depth")
+        .map(|depth| depth.parse().expect("Invalid digit in max depth"))
+        .unwrap_or(256);
+    if args.len() != 2 {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut old = load(&args[0])?;
+    let mut new = load(&args[1])?;
+
+    let changes = diff::compare(&old, &new);
+    for (rule, rule_changes) in changes.iter() {
+        for change in rule_changes {
+            let description = match change {
+                diff::RuleChange::Added => "added".to_string(),
+                diff::RuleChange::Removed => "removed".to_string(),
+                diff::RuleChange::Alternatives { old, new } => {
+                    format!("alternatives {} -> {}", old, new)
+                }
+                diff::RuleChange::Arity {
+                    alternative,
+                    old,
+                    new,
+                } => format!("alternative {} arity {} -> {}", alternative, old, new),
+                diff::RuleChange::Elements { alternative } => {
+                    format!("alternative {} changed", alternative)
+                }
+            };
+            println!("{}: {}", rule, description);
+        }
+    }
+    println!("{} rules changed", changes.len());
+
+    if let Some(seeds) = seeds {
+        // Seeds are replayed through the optimized grammars, which is what
+        // the generated fuzzers use
+        old.optimize();
+        new.optimize();
+
+        let changed = diff::replay_seeds(&old, &new, &seeds, max_depth)?;
+        for path in changed.iter() {
+            println!("changed seed: {}", path.display());
+        }
+        println!("{} seeds generate a different input", changed.len());
+    }
+
+    Ok(())
+}
+
+/// Write all inputs of a grammar up to a depth, or inputs covering all
+/// k-paths, into a directory
+fn enumerate(mut args: Vec<String>) -> std::io::Result<()> {
+    let level = match take_opt_level(&mut args) {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+    let max_depth = take_flag(&mut args, "--max-depth")
+        .map(|depth| depth.parse().expect("Invalid digit in max depth"))
+        .unwrap_or(8);
+    let limit = take_flag(&mut args, "--limit")
+        .map(|limit| limit.parse().expect("Invalid limit"))
+        .unwrap_or(1000);
+    let k = take_flag(&mut args, "--k-path").map(|k| k.parse().expect("Invalid k"));
+    if args.len() != 2 || k == Some(0) {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut gram = load(&args[0])?;
+    let inputs: Vec<Vec<u8>> = match k {
+        // k-paths are made of the rules of the grammar, which optimizations
+        // would forward away
+        Some(k) => {
+            let cover = enumerate::k_path_cover(&gram, k);
+            log::info!(
+                "{} inputs use all {} {}-paths",
+                cover.inputs.len(),
+                cover.paths,
+                k
+            );
+            cover.inputs
+        }
+        None => {
+            // Depths are counted like in the generated code of the same -O
+            // level
+            gram.optimize_level(level);
+            enumerate::Enumerator::new(&gram, max_depth, limit).collect()
+        }
+    };
+
+    std::fs::create_dir_all(&args[1])?;
+    for (idx, input) in inputs.iter().enumerate() {
+        std::fs::write(Path::new(&args[1]).join(format!("{:06}", idx)), input)?;
+    }
+    println!("wrote {} inputs to {}", inputs.len(), args[1]);
+
+    Ok(())
+}
+
+/// Report the size and recursion of a grammar before and after optimizing,
+/// and statistics of inputs sampled from it
+fn stats(mut args: Vec<String>) -> std::io::Result<()> {
+    let level = match take_opt_level(&mut args) {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+    let samples = take_flag(&mut args, "-n")
+        .map(|samples| samples.parse().expect("Invalid number of samples"))
+        .unwrap_or(10000);
+    let max_depth = take_flag(&mut args, "--depth")
+        .map(|depth| depth.parse().expect("Invalid digit in max depth"))
+        .unwrap_or(128);
+    let top = take_flag(&mut args, "--top")
+        .map(|top| top.parse().expect("Invalid number of terminals"))
+        .unwrap_or(10);
+    if args.len() != 1 {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut gram = load(&args[0])?;
+    let before = stats::Shape::of(&gram);
+    let recursion = stats::recursion(&gram);
+    gram.optimize_level(level);
+    let after = stats::Shape::of(&gram);
+
+    println!("{:<14}{:>10}{:>10}", "", "before", "after");
+    for (name, before, after) in [
+        ("fragments", before.fragments, after.fragments),
+        ("rules", before.rules, after.rules),
+        ("choices", before.choices, after.choices),
+        ("alternatives", before.alternatives, after.alternatives),
+        ("terminals", before.terminals, after.terminals),
+    ] {
+        println!("{:<14}{:>10}{:>10}", name, before, after);
+    }
+
+    println!();
+    println!("{} groups of recursive rules", recursion.len());
+    for group in recursion.iter() {
+        println!("  {}", group.join(" "));
+    }
+
+    // Sampled like the generated code of the same -O level, with a fixed
+    // seed so reports of two versions of a grammar can be compared
+    let sampled = stats::sample(&gram, samples, max_depth, &mut StdRng::seed_from_u64(0));
+    println!();
+    println!(
+        "output length over {} samples: min {} avg {:.1} max {}",
+        samples,
+        sampled.min_len,
+        sampled.avg_len(),
+        sampled.max_len
+    );
+
+    // Only the depths where some but not all inputs are cut short
+    println!();
+    println!("truncation rate by max depth:");
+    for depth in 1..=max_depth {
+        let rate = sampled.truncation_rate(depth);
+        if rate < 1.0 && (rate > 0.0 || depth == max_depth) {
+            println!("  {:>6} {:>7.3}%", depth, rate * 100.0);
+        }
+    }
+
+    println!();
+    println!("top terminals:");
+    for (value, uses) in sampled.top_terminals(top) {
+        println!("  {:>10} {:?}", uses, String::from_utf8_lossy(value));
+    }
+
+    Ok(())
+}
+
+/// Create a new, empty file in the temporary directory, under a name no
+/// other file had, so no one else can prepare or read it
+fn scratch_file() -> std::io::Result<PathBuf> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "fzero-reduce-{}-{:016x}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        match options.open(&path) {
+            Ok(_) => return Ok(path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Check if `command` exits successfully for `input`, which is passed as a
+/// file in place of `@@` or else on stdin
+fn run_predicate(command: &[String], input: &[u8], path: &Path) -> std::io::Result<bool> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+    let file_arg = args.contains(&"@@");
+    let path_str = path.to_string_lossy();
+    if file_arg {
+        std::fs::write(path, input)?;
+        for arg in args.iter_mut() {
+            if *arg == "@@" {
+                *arg = &path_str;
+            }
+        }
+    }
+
+    let mut child = Command::new(&command[0])
+        .args(args)
+        .stdin(if file_arg {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command may exit without reading all of its input
+        let _ = stdin.write_all(input);
+    }
+    Ok(child.wait()?.success())
+}
+
+/// Reduce an input of a grammar for which a command succeeds, e.g. a script
+/// checking that a target still crashes
+fn reduce(mut args: Vec<String>) -> std::io::Result<()> {
+    let command = match args.iter().position(|arg| arg == "--") {
+        Some(split) => args.split_off(split)[1..].to_vec(),
+        None => Vec::new(),
+    };
+    if args.len() != 3 || command.is_empty() {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    // Rules are kept as they are, so subtrees can be replaced by rule
+    let gram = load(&args[0])?;
+    let input = std::fs::read(&args[1])?;
+    let scratch = scratch_file()?;
+    let original = run_predicate(&command, &input, &scratch);
+    if !matches!(original, Ok(true)) {
+        let _ = std::fs::remove_file(&scratch);
+        original?;
+        log::warn!("the command does not succeed for {}", args[1]);
+        return Ok(());
+    }
+
+    let mut error = None;
+    let reduction = reduce::reduce(&gram, &input, |candidate| {
+        match run_predicate(&command, candidate, &scratch) {
+            Ok(interesting) => interesting,
+            Err(err) => {
+                error.get_or_insert(err);
+                false
+            }
+        }
+    });
+    let _ = std::fs::remove_file(&scratch);
+    if let Some(err) = error {
+        return Err(err);
+    }
+
+    let reduction = match reduction {
+        Some(reduction) => reduction,
+        None => {
+            log::warn!("the grammar can not generate {}", args[1]);
+            return Ok(());
+        }
+    };
+    std::fs::write(&args[2], &reduction.input)?;
+    println!(
+        "reduced {} to {} bytes with {} tests",
+        input.len(),
+        reduction.input.len(),
+        reduction.tests
+    );
+
+    Ok(())
+}
+
+/// Report the alternatives that were never taken according to the coverage
+/// counters dumped by generated code
+fn coverage(mut args: Vec<String>) -> std::io::Result<()> {
+    let level = match take_opt_level(&mut args) {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+    let mut roots = Vec::new();
+    while let Some(root) = take_flag(&mut args, "--root") {
+        roots.push(root);
+    }
+    let merge = take_flag(&mut args, "--merge");
+    if args.len() < 2 {
+        log::warn!("{}", USAGE);
+        return Ok(());
+    }
+
+    let mut counters = coverage::Coverage::default();
+    for path in args[1..].iter() {
+        counters.merge(&coverage::Coverage::parse(&std::fs::read_to_string(path)?)?);
+    }
+    if let Some(merge) = merge {
+        std::fs::write(&merge, counters.to_string())?;
+        log::info!("Wrote merged coverage counters to {}", merge);
+    }
+
+    // The grammar has to be optimized just like for the instrumented code
+    let mut gram = load(&args[0])?;
+    for root in roots.iter() {
+        if gram.fragment_id(root).is_none() {
+            log::warn!("unknown rule {:?}", root);
+            return Ok(());
+        }
+        gram.add_root(root);
+    }
+    gram.optimize_level(level);
+
+    let report = coverage::report(&gram, &counters);
+    for rule in report.rules_never_taken.iter() {
+        println!("rule never taken: {}", rule);
+    }
+    for (rule, alternative) in report.alternatives_never_taken.iter() {
+        println!(
+            "alternative never taken: {} alternative {}",
+            rule, alternative
+        );
+    }
+    println!(
+        "{} of {} alternatives taken",
+        report.taken, report.alternatives
+    );
+
+    Ok(())
+}
 
 fn main() -> std::io::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     // Get access to the command line arguments
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("convert") => return convert(args[2..].to_vec()),
+        Some("export") => return export(args[2..].to_vec()),
+        Some("diff") => return diff(args[2..].to_vec()),
+        Some("coverage") => return coverage(args[2..].to_vec()),
+        Some("enumerate") => return enumerate(args[2..].to_vec()),
+        Some("stats") => return stats(args[2..].to_vec()),
+        Some("reduce") => return reduce(args[2..].to_vec()),
+        _ => {}
+    }
+
+    let level = match take_opt_level(&mut args) {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+    let rule_names = take_switch(&mut args, "--rule-names");
+    let coverage = take_switch(&mut args, "--coverage");
+    let adaptive = take_switch(&mut args, "--adaptive");
+    let sized = take_flag(&mut args, "--sized").map(|size| size.parse().expect("Invalid size"));
+    let streaming = take_switch(&mut args, "--streaming");
+    let backend = match take_flag(&mut args, "--backend") {
+        Some(name) => match Backend::from_name(&name) {
+            Some(backend) => backend,
+            None => {
+                log::warn!("unknown backend {:?}", name);
+                return Ok(());
+            }
+        },
+        None => Backend::default(),
+    };
+    let mut roots = Vec::new();
+    while let Some(root) = take_flag(&mut args, "--root") {
+        roots.push(root);
+    }
+    let verify_samples = take_flag(&mut args, "--verify")
+        .map(|samples| samples.parse().expect("Invalid number of samples"));
+
     if !(3..=4).contains(&args.len()) {
-        log::warn!("usage: fzero <grammar json> <output Rust file> [default max depth]");
+        log::warn!("{}", USAGE);
         return Ok(());
     }
 
     // Load up a grammar file
-    let grammar: Grammar = serde_json::from_slice(&std::fs::read(&args[1])?)?;
-    log::info!("Loaded grammar json; parsing grammar into in-memory format.");
+    let grammar: Grammar = frontend::load(&args[1], None)?;
+    log::info!("Loaded grammar; parsing grammar into in-memory format.");
 
     // Convert the grammar file to the Rust structures
-    let mut gram = GrammarRust::new(&grammar, None);
+    let mut gram = build(&grammar)?;
+    gram.rule_names = rule_names;
+    gram.coverage = coverage;
+    gram.adaptive = adaptive;
+    gram.sized_sampling = sized;
+    gram.streaming = streaming;
+    gram.backend = backend;
+    for root in roots.iter() {
+        if gram.fragment_id(root).is_none() {
+            log::warn!("unknown rule {:?}", root);
+            return Ok(());
+        }
+        gram.add_root(root);
+    }
     log::info!("Converted grammar to in-memory format; optimizing now.");
 
+    let max_depth = args
+        .get(3)
+        .unwrap_or(&("256".to_string()))
+        .parse()
+        .expect("Invalid digit in max depth");
+    let lines_before = gram.source(max_depth).lines().count();
+    let original = verify_samples.map(|_| gram.clone());
+
     // Optimize the grammar
-    gram.optimize();
-    log::info!("Optimized grammar; generating code.");
+    let stats = gram.optimize_level(level);
+    log::info!(
+        "Optimized grammar in {} rounds: {} -> {} fragments; generating code.",
+        stats.rounds,
+        stats.fragments_before,
+        stats.fragments_after
+    );
+    for (pass, changes) in stats.passes.iter() {
+        log::info!("    {}: {} changes", pass, changes);
+    }
+
+    // Check that the optimized grammar still generates the same language
+    if let (Some(original), Some(samples)) = (original, verify_samples) {
+        match optimize::verify(&original, &gram, samples, max_depth) {
+            Ok(checked) => log::info!("Verified optimized grammar on {} inputs", checked),
+            Err(err) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("optimized grammar differs: {}", err),
+                ))
+            }
+        }
+    }
 
     // Generate a Rust application
-    gram.program(
-        &args[2],
-        args.get(3)
-            .unwrap_or(&("256".to_string()))
-            .parse()
-            .expect("Invalid digit in max depth"),
+    gram.program(&args[2], max_depth);
+    log::info!(
+        "Generated Rust source file: {} -> {} lines",
+        lines_before,
+        gram.source(max_depth).lines().count()
     );
-    log::info!("Generated Rust source file");
 
     Ok(())
 }
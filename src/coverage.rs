@@ -0,0 +1,204 @@
+//! Coverage of the alternatives of a grammar.
+//!
+//! Code generated with `GrammarRust::coverage` counts how often every
+//! alternative of every rule was chosen, and writes the counters with
+//! `GrammarGenerator::dump_coverage` as one `rule\talternative\tcount` line
+//! per alternative. Counters of several runs can be merged, and compared
+//! against the grammar to find alternatives that were never taken.
+//!
+//! Only choice points are counted: a rule with a single alternative involves
+//! no choice, has no counter and is never reported, even if it was never
+//! reached.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::GrammarRust;
+
+/// Number of times every alternative of every rule was chosen
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    counts: BTreeMap<(String, usize), u64>,
+}
+
+impl Coverage {
+    /// Parse counters written by `GrammarGenerator::dump_coverage`
+    pub fn parse(text: &str) -> std::io::Result<Self> {
+        let mut coverage = Self::default();
+        for (idx, line) in text.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid coverage counter on line {}: {:?}", idx + 1, line),
+                )
+            };
+            let mut fields = line.rsplitn(3, '\t');
+            let count = fields.next().and_then(|count| count.parse().ok());
+            let alternative = fields.next().and_then(|alt| alt.parse().ok());
+            match (fields.next(), alternative, count) {
+                (Some(rule), Some(alternative), Some(count)) => {
+                    coverage.add(rule, alternative, count);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(coverage)
+    }
+
+    /// Add `count` choices of an alternative
+    pub fn add(&mut self, rule: &str, alternative: usize, count: u64) {
+        *self
+            .counts
+            .entry((rule.to_string(), alternative))
+            .or_default() += count;
+    }
+
+    /// Add all counters of `other`
+    pub fn merge(&mut self, other: &Coverage) {
+        for ((rule, alternative), &count) in other.counts.iter() {
+            self.add(rule, *alternative, count);
+        }
+    }
+
+    /// Number of times an alternative of a rule was chosen
+    pub fn count(&self, rule: &str, alternative: usize) -> u64 {
+        self.counts
+            .get(&(rule.to_string(), alternative))
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for ((rule, alternative), count) in self.counts.iter() {
+            writeln!(f, "{}\t{}\t{}", rule, alternative, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Alternatives and rules that were never chosen, among the rules with more
+/// than one alternative
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Number of alternatives with a counter
+    pub alternatives: usize,
+
+    /// Number of alternatives that were chosen at least once
+    pub taken: usize,
+
+    /// Rules none of whose alternatives was chosen
+    pub rules_never_taken: Vec<String>,
+
+    /// Alternatives that were never chosen, of rules that were taken
+    pub alternatives_never_taken: Vec<(String, usize)>,
+}
+
+/// Compare counters against the grammar they were collected for, which has
+/// to be optimized the same way as for the instrumented code. Rules that were
+/// deduplicated share the counters of the rule they were merged into, and are
+/// only reported under its name. Rules with a single alternative have no
+/// counter and are not part of the report.
+pub fn report(gram: &GrammarRust, coverage: &Coverage) -> Report {
+    // Every counter of the instrumented code, so aliases of the same
+    // fragment are only counted once
+    let mut rules: BTreeMap<String, usize> = BTreeMap::new();
+    for (rule, _) in gram.coverage_points().0 {
+        *rules.entry(rule).or_default() += 1;
+    }
+
+    let mut report = Report::default();
+    for (name, options) in rules {
+        let never_taken: Vec<usize> = (0..options)
+            .filter(|&alt| coverage.count(&name, alt) == 0)
+            .collect();
+        report.alternatives += options;
+        report.taken += options - never_taken.len();
+        if never_taken.len() == options {
+            report.rules_never_taken.push(name);
+        } else {
+            report
+                .alternatives_never_taken
+                .extend(never_taken.into_iter().map(|alt| (name.clone(), alt)));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    #[test]
+    fn reports_alternatives_never_taken() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<b>", "<a>"]], "<a>": [["x"], ["y", "<a>"]], "<b>": [["u"], ["v", "<b>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+
+        let mut coverage = Coverage::parse("<start>\t0\t3\n<a>\t0\t2\n").unwrap();
+        coverage.merge(&Coverage::parse("<start>\t0\t1\n<a>\t0\t1\n").unwrap());
+        assert_eq!(coverage.count("<start>", 0), 4);
+        assert_eq!(Coverage::parse(&coverage.to_string()).unwrap(), coverage);
+        assert!(Coverage::parse("<a>\tx\t1").is_err());
+
+        let report = report(&gram, &coverage);
+        assert_eq!(report.alternatives, 6);
+        assert_eq!(report.taken, 2);
+        assert_eq!(report.rules_never_taken, vec!["<b>".to_string()]);
+        assert_eq!(
+            report.alternatives_never_taken,
+            vec![("<a>".to_string(), 1), ("<start>".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn deduplicated_rules_are_counted_once() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>", "<b>"]], "<a>": [["x"], ["y", "<a>"]], "<b>": [["x"], ["y", "<b>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+        assert_eq!(gram.fragment_id("<a>"), gram.fragment_id("<b>"));
+
+        let coverage = Coverage::parse("<a>\t0\t3\n").unwrap();
+        let report = report(&gram, &coverage);
+        assert_eq!(report.alternatives, 2);
+        assert_eq!(report.taken, 1);
+        assert!(report.rules_never_taken.is_empty());
+        assert_eq!(
+            report.alternatives_never_taken,
+            vec![("<a>".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn single_alternatives_are_not_reported() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<c>"]], "<a>": [["x"], ["y", "<a>"]], "<c>": [["z", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+
+        // `<c>` was never reached, but it is no choice point
+        let coverage = Coverage::parse("<start>\t0\t1\n<a>\t0\t1\n").unwrap();
+        let report = report(&gram, &coverage);
+        assert_eq!(report.alternatives, 4);
+        assert_eq!(
+            report.alternatives_never_taken,
+            vec![("<a>".to_string(), 1), ("<start>".to_string(), 1)]
+        );
+        assert!(report.rules_never_taken.is_empty());
+    }
+}
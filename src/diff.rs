@@ -0,0 +1,245 @@
+//! Comparison of two versions of a grammar.
+//!
+//! Reproducers saved as `BufRng` byte buffers only describe a sequence of
+//! random choices. Whenever a rule changes, the same bytes may select a
+//! different derivation. This module reports which rules changed between two
+//! grammar versions and replays saved byte-seeds through both versions to
+//! find reproducers that now generate a different input.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use bufrng::BufRng;
+
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// How a rule differs between two versions of a grammar
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleChange {
+    /// The rule only exists in the new grammar
+    Added,
+
+    /// The rule only exists in the old grammar
+    Removed,
+
+    /// The number of alternatives changed. Byte-seeds that make a choice in
+    /// this rule will likely select a different alternative.
+    Alternatives { old: usize, new: usize },
+
+    /// The number of elements of an alternative changed
+    Arity {
+        alternative: usize,
+        old: usize,
+        new: usize,
+    },
+
+    /// The elements of an alternative changed without changing its length
+    Elements { alternative: usize },
+}
+
+/// Compare the rules reachable from the start nodes of two grammars. Both
+/// grammars should not be optimized, so the rules are still intact.
+pub fn compare(old: &GrammarRust, new: &GrammarRust) -> BTreeMap<String, Vec<RuleChange>> {
+    let old_rules = rule_alternatives(old);
+    let new_rules = rule_alternatives(new);
+
+    let mut changes = BTreeMap::new();
+    for (name, old_alts) in old_rules.iter() {
+        let new_alts = match new_rules.get(name) {
+            Some(new_alts) => new_alts,
+            None => {
+                changes.insert(name.clone(), vec![RuleChange::Removed]);
+                continue;
+            }
+        };
+
+        let mut rule_changes = Vec::new();
+        if old_alts.len() != new_alts.len() {
+            rule_changes.push(RuleChange::Alternatives {
+                old: old_alts.len(),
+                new: new_alts.len(),
+            });
+        }
+        for (alternative, (old_alt, new_alt)) in old_alts.iter().zip(new_alts.iter()).enumerate() {
+            if old_alt.len() != new_alt.len() {
+                rule_changes.push(RuleChange::Arity {
+                    alternative,
+                    old: old_alt.len(),
+                    new: new_alt.len(),
+                });
+            } else if old_alt != new_alt {
+                rule_changes.push(RuleChange::Elements { alternative });
+            }
+        }
+
+        if !rule_changes.is_empty() {
+            changes.insert(name.clone(), rule_changes);
+        }
+    }
+    for name in new_rules.keys() {
+        if !old_rules.contains_key(name) {
+            changes.insert(name.clone(), vec![RuleChange::Added]);
+        }
+    }
+
+    changes
+}
+
+/// The alternatives of every rule reachable from the start node, with every
+/// element described by the rule it refers to or the terminal it emits
+fn rule_alternatives(gram: &GrammarRust) -> BTreeMap<String, Vec<Vec<String>>> {
+    let mut names = BTreeMap::new();
+    for (name, id) in gram.name_to_fragment.iter() {
+        names.entry(id.0).or_insert_with(|| name.clone());
+    }
+
+    let describe = |id: FragmentId| -> String {
+        if let Some(name) = names.get(&id.0) {
+            return name.clone();
+        }
+        match &gram.fragments[id.0] {
+            Fragment::NonTerminal(options) if options.len() == 1 => names
+                .get(&options[0].0)
+                .cloned()
+                .unwrap_or_else(|| format!("{:?}", gram.fragments[options[0].0])),
+            Fragment::Terminal(value) => format!("{:?}", String::from_utf8_lossy(value)),
+            Fragment::Repeat { fragment, min, max } => {
+                let inner = match &gram.fragments[fragment.0] {
+                    Fragment::NonTerminal(options) if options.len() == 1 => options[0],
+                    _ => *fragment,
+                };
+                format!(
+                    "{}{{{},{}}}",
+                    names.get(&inner.0).cloned().unwrap_or_default(),
+                    min,
+                    max
+                )
+            }
+            other => format!("{:?}", other),
+        }
+    };
+
+    let mut rules = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+    let mut worklist = vec![gram.start.unwrap()];
+    while let Some(id) = worklist.pop() {
+        if !seen.insert(id.0) {
+            continue;
+        }
+        match &gram.fragments[id.0] {
+            Fragment::NonTerminal(options) => worklist.extend(options.iter().copied()),
+            Fragment::Expression(expr) => worklist.extend(expr.iter().copied()),
+            Fragment::Repeat { fragment, .. } => worklist.push(*fragment),
+            Fragment::Terminal(_) | Fragment::Nop | Fragment::Unreachable => {}
+        }
+
+        let name = match names.get(&id.0) {
+            Some(name) => name.clone(),
+            None => continue,
+        };
+        let alternatives = match &gram.fragments[id.0] {
+            Fragment::NonTerminal(options) => options
+                .iter()
+                .map(|&option| match &gram.fragments[option.0] {
+                    Fragment::Expression(expr) => expr.iter().map(|&e| describe(e)).collect(),
+                    _ => vec![describe(option)],
+                })
+                .collect(),
+            _ => vec![vec![describe(id)]],
+        };
+        rules.insert(name, alternatives);
+    }
+
+    rules
+}
+
+/// Replay every byte-seed in `seeds_dir` through both grammars, returning the
+/// seed files for which the generated inputs differ. The grammars should be
+/// optimized the same way as the generators the seeds were saved from.
+pub fn replay_seeds(
+    old: &GrammarRust,
+    new: &GrammarRust,
+    seeds_dir: impl AsRef<Path>,
+    max_depth: usize,
+) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(seeds_dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    let mut old_out = Vec::new();
+    let mut new_out = Vec::new();
+    let mut changed = Vec::new();
+    for path in paths {
+        let seed = std::fs::read(&path)?;
+        old.generate_into(&mut old_out, max_depth, &mut BufRng::new(&seed));
+        new.generate_into(&mut new_out, max_depth, &mut BufRng::new(&seed));
+        if old_out != new_out {
+            changed.push(path);
+        }
+    }
+
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn grammar(json: &str) -> GrammarRust {
+        let grammar: Grammar = serde_json::from_str(json).unwrap();
+        GrammarRust::new(&grammar, None)
+    }
+
+    #[test]
+    fn reports_changed_rules() {
+        let old =
+            grammar(r#"{"<start>": [["<a>", "<b>"]], "<a>": [["x"], ["y"]], "<b>": [["z"]]}"#);
+        let new = grammar(
+            r#"{"<start>": [["<a>", "<c>"]], "<a>": [["x"], ["y"], ["w"]], "<c>": [["z", "z"]]}"#,
+        );
+
+        let changes = compare(&old, &new);
+        assert_eq!(
+            changes["<a>"],
+            vec![RuleChange::Alternatives { old: 2, new: 3 }]
+        );
+        assert_eq!(changes["<b>"], vec![RuleChange::Removed]);
+        assert_eq!(changes["<c>"], vec![RuleChange::Added]);
+        assert_eq!(
+            changes["<start>"],
+            vec![RuleChange::Elements { alternative: 0 }]
+        );
+    }
+
+    #[test]
+    fn replays_seeds_through_both_grammars() {
+        let mut old = grammar(
+            r#"{"<start>": [["<a>", "<b>"]], "<a>": [["x"], ["y"]], "<b>": [["0"], ["1"]]}"#,
+        );
+        let mut new = grammar(
+            r#"{"<start>": [["<a>", "<b>"]], "<a>": [["x"], ["z"]], "<b>": [["0"], ["1"]]}"#,
+        );
+        old.optimize();
+        new.optimize();
+
+        // Every choice reads 4 bytes, zeros choose the first alternative and
+        // 0x80 bytes the second one
+        let dir = std::env::temp_dir().join(format!("fzero-diff-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("first"), b"").unwrap();
+        std::fs::write(dir.join("second"), [0x80; 8]).unwrap();
+        std::fs::write(dir.join("mixed"), [0, 0, 0, 0, 0x80, 0x80, 0x80, 0x80]).unwrap();
+
+        let mut out = Vec::new();
+        new.generate_into(&mut out, 16, &mut BufRng::new(&[0x80; 8]));
+        assert_eq!(out, b"z1");
+
+        let changed = replay_seeds(&old, &new, &dir, 16).unwrap();
+        assert_eq!(changed, vec![dir.join("second")]);
+        assert!(replay_seeds(&old, &old, &dir, 16).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,395 @@
+//! Generating large corpora on several threads.
+//!
+//! The generated `GrammarGenerator` has no global state besides the optional
+//! coverage counters, so any number of threads can generate at once. Every
+//! input is generated from its own `Pcg64`, seeded from a master seed and the
+//! number of the input, so a run can be repeated with the same master seed
+//! and any number of threads, and every input can be regenerated on its own
+//! with `replay`.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+/// Size of the batches threads collect before writing to a shared stream
+const STREAM_BATCH: usize = 1 << 20;
+
+/// Where generated inputs go
+pub enum Output {
+    /// Only generate, e.g. to measure throughput
+    Discard,
+
+    /// One file per input, in a subdirectory per thread. The name of every
+    /// file is the number of the input and its seed in hex, e.g.
+    /// `00000042-9e3779b97f4a7c15`.
+    Directory(PathBuf),
+
+    /// All inputs to one stream, each followed by a newline. Inputs of
+    /// different threads are interleaved in no particular order.
+    Stream(Box<dyn Write + Send>),
+}
+
+/// Derive the seed of input `index` from the master seed. This is SplitMix64,
+/// so seeds of neighbouring inputs are unrelated.
+pub fn input_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Name of the file of an input in an output directory
+pub fn file_name(index: u64, seed: u64) -> String {
+    format!("{:08}-{:016x}", index, seed)
+}
+
+/// Parse the seed of an input as written in its file name, in hex with an
+/// optional `0x` prefix
+pub fn parse_seed(seed: &str) -> Option<u64> {
+    u64::from_str_radix(seed.trim_start_matches("0x"), 16).ok()
+}
+
+/// Regenerate the input with the seed `seed`, as given by `input_seed`,
+/// exactly like it was generated by `Driver::run`
+pub fn replay<F>(seed: u64, generate: F) -> Vec<u8>
+where
+    F: Fn(&mut Vec<u8>, &mut Pcg64),
+{
+    let mut out = Vec::new();
+    generate(&mut out, &mut Pcg64::seed_from_u64(seed));
+    out
+}
+
+/// Frames every write to the inner writer as a chunk of a length-delimited
+/// input: its length as a little-endian `u32`, then its bytes. `end` writes
+/// an empty chunk, which ends the input. Inputs can be streamed like this
+/// without knowing their length up front, e.g. with the chunks of
+/// `GrammarGenerator::generate_to_writer`.
+pub struct LengthDelimited<W> {
+    inner: W,
+}
+
+impl<W: Write> LengthDelimited<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    /// End the current input
+    pub fn end(&mut self) -> io::Result<()> {
+        self.inner.write_all(&0u32.to_le_bytes())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for LengthDelimited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the input
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(u32::MAX as usize);
+        self.inner.write_all(&(len as u32).to_le_bytes())?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Generates a number of inputs on several threads
+pub struct Driver {
+    count: u64,
+    threads: usize,
+    seed: u64,
+    output: Output,
+}
+
+impl Driver {
+    /// Generate `count` inputs, by default on one thread per CPU, with
+    /// master seed 0 and discarding the inputs
+    pub fn new(count: u64) -> Self {
+        Self {
+            count,
+            threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+            seed: 0,
+            output: Output::Discard,
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Write every input into its own file below `path`, named after its
+    /// number and seed. Thread `t` writes the inputs `t`, `t + threads`, ...
+    /// into `path/t`.
+    pub fn directory(mut self, path: impl AsRef<Path>) -> Self {
+        self.output = Output::Directory(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Write all inputs to `stream`, separated by newlines
+    pub fn stream(mut self, stream: impl Write + Send + 'static) -> Self {
+        self.output = Output::Stream(Box::new(stream));
+        self
+    }
+
+    /// Run `generate` on every thread until all inputs are generated, e.g.
+    /// with `|out, rng| GrammarGenerator::generate_into(out, None, rng)`
+    pub fn run<F>(self, generate: F) -> io::Result<Report>
+    where
+        F: Fn(&mut Vec<u8>, &mut Pcg64) + Sync,
+    {
+        let start = Instant::now();
+        let threads = self.threads;
+        let count = self.count;
+        let seed = self.seed;
+        let (directory, stream) = match self.output {
+            Output::Discard => (None, None),
+            Output::Directory(path) => (Some(path), None),
+            Output::Stream(stream) => (None, Some(Mutex::new(stream))),
+        };
+
+        let results: Vec<io::Result<(u64, u64)>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|thread| {
+                    let generate = &generate;
+                    let directory = directory.as_deref();
+                    let stream = stream.as_ref();
+                    scope.spawn(move || -> io::Result<(u64, u64)> {
+                        let shard = match directory {
+                            Some(directory) => {
+                                let shard = directory.join(thread.to_string());
+                                std::fs::create_dir_all(&shard)?;
+                                Some(shard)
+                            }
+                            None => None,
+                        };
+
+                        let mut out = Vec::new();
+                        let mut batch = Vec::new();
+                        let mut inputs = 0;
+                        let mut bytes = 0;
+                        for index in (thread as u64..count).step_by(threads) {
+                            let input_seed = input_seed(seed, index);
+                            generate(&mut out, &mut Pcg64::seed_from_u64(input_seed));
+                            inputs += 1;
+                            bytes += out.len() as u64;
+
+                            if let Some(shard) = shard.as_ref() {
+                                std::fs::write(shard.join(file_name(index, input_seed)), &out)?;
+                            } else if let Some(stream) = stream {
+                                batch.extend_from_slice(&out);
+                                batch.push(b'\n');
+                                if batch.len() >= STREAM_BATCH {
+                                    stream.lock().map_err(poisoned)?.write_all(&batch)?;
+                                    batch.clear();
+                                }
+                            }
+                        }
+                        if let Some(stream) = stream {
+                            stream.lock().map_err(poisoned)?.write_all(&batch)?;
+                        }
+                        Ok((inputs, bytes))
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("generation thread panicked"))
+                .collect()
+        });
+
+        let mut report = Report {
+            inputs: 0,
+            bytes: 0,
+            elapsed: Duration::default(),
+        };
+        for result in results {
+            let (inputs, bytes) = result?;
+            report.inputs += inputs;
+            report.bytes += bytes;
+        }
+        if let Some(stream) = stream {
+            stream.into_inner().map_err(poisoned)?.flush()?;
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+}
+
+/// The error for a shared stream that another thread panicked while writing
+/// to, so the remaining threads stop instead of panicking as well
+fn poisoned<T>(_: PoisonError<T>) -> io::Error {
+    io::Error::other("a thread panicked while writing to the stream")
+}
+
+/// Throughput of a `Driver` run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// Number of generated inputs
+    pub inputs: u64,
+
+    /// Total size of the generated inputs
+    pub bytes: u64,
+
+    /// Time from starting the first thread until the last one finished
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn inputs_per_sec(&self) -> f64 {
+        self.inputs as f64 / self.elapsed.as_secs_f64()
+    }
+
+    pub fn mib_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1024.0 * 1024.0) / self.elapsed.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "generated {} inputs ({:.1} MiB) in {:.2}s: {:.0} inputs/s, {:.1} MiB/s",
+            self.inputs,
+            self.bytes as f64 / (1024.0 * 1024.0),
+            self.elapsed.as_secs_f64(),
+            self.inputs_per_sec(),
+            self.mib_per_sec()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Grammar, GrammarRust};
+
+    #[test]
+    fn inputs_are_reproducible() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>", "<start>"], ["<digit>"]], "<digit>": [["0"], ["1"], ["2"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+        let generate = |out: &mut Vec<u8>, rng: &mut Pcg64| gram.generate_into(out, 32, rng);
+
+        let dir = std::env::temp_dir().join(format!("fzero-drive-{}", std::process::id()));
+        let report = Driver::new(10)
+            .threads(3)
+            .seed(7)
+            .directory(&dir)
+            .run(generate)
+            .unwrap();
+        assert_eq!(report.inputs, 10);
+
+        // Thread 1 generated the inputs 1, 4 and 7, which can be replayed
+        // from the seeds in their names
+        let mut names: Vec<String> = std::fs::read_dir(dir.join("1"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 3);
+        let mut total = 0;
+        for (&index, name) in [1u64, 4, 7].iter().zip(names) {
+            assert_eq!(name, file_name(index, input_seed(7, index)));
+            let seed = parse_seed(name.split('-').nth(1).unwrap()).unwrap();
+            let input = std::fs::read(dir.join("1").join(&name)).unwrap();
+            assert_eq!(input, replay(seed, generate));
+            total += input.len();
+        }
+        assert!(report.bytes >= total as u64);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The inputs do not depend on the number of threads
+        let one = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let many = std::sync::Arc::new(Mutex::new(Vec::new()));
+        for &(threads, stream) in [(1, &one), (4, &many)].iter() {
+            Driver::new(50)
+                .threads(threads)
+                .seed(7)
+                .stream(Shared(stream.clone()))
+                .run(generate)
+                .unwrap();
+        }
+        let mut one: Vec<Vec<u8>> = one
+            .lock()
+            .unwrap()
+            .split(|&b| b == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        let mut many: Vec<Vec<u8>> = many
+            .lock()
+            .unwrap()
+            .split(|&b| b == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect();
+        one.sort();
+        many.sort();
+        assert_eq!(one, many);
+    }
+
+    #[test]
+    fn frames_chunks_by_length() {
+        let mut framed = LengthDelimited::new(Vec::new());
+        framed.write_all(b"abc").unwrap();
+        framed.write_all(b"").unwrap();
+        framed.write_all(b"de").unwrap();
+        framed.end().unwrap();
+        framed.end().unwrap();
+        assert_eq!(
+            framed.into_inner(),
+            b"\x03\0\0\0abc\x02\0\0\0de\0\0\0\0\0\0\0\0"
+        );
+    }
+
+    #[test]
+    fn poisoned_streams_are_errors() {
+        let stream = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let poison = stream.clone();
+        std::thread::spawn(move || {
+            let _guard = poison.lock().unwrap();
+            panic!("poisoning the stream");
+        })
+        .join()
+        .unwrap_err();
+
+        let err = Driver::new(10)
+            .threads(2)
+            .stream(Shared(stream))
+            .run(|out, _| out.push(b'x'))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    /// A stream the test can read after the driver is done
+    struct Shared(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().map_err(poisoned)?.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
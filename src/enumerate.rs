@@ -0,0 +1,542 @@
+//! Systematic generation of inputs, instead of random sampling.
+//!
+//! `Enumerator` yields every distinct input a grammar can generate within a
+//! depth limit, and `k_path_cover` builds a small set of inputs that together
+//! use every chain of `k` rules that can occur in a derivation.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// Strings keyed by their length first, so they are ordered by size
+type Strings = BTreeSet<(usize, Vec<u8>)>;
+
+/// Insert into a set of strings that only keeps the `limit` smallest ones
+fn insert_capped(set: &mut Strings, value: Vec<u8>, limit: usize) {
+    set.insert((value.len(), value));
+    if set.len() > limit {
+        set.pop_last();
+    }
+}
+
+/// The `limit` smallest concatenations of a string of `a` with a string of
+/// `b`. Every one of them is the concatenation of one of the `limit`
+/// smallest strings of both, so the inputs may be capped as well.
+fn concat(a: &Strings, b: &Strings, limit: usize) -> Strings {
+    let mut out = Strings::new();
+    for (len_a, value_a) in a.iter() {
+        for (len_b, value_b) in b.iter() {
+            if out.len() >= limit && len_a + len_b > out.last().unwrap().0 {
+                break;
+            }
+            let mut value = value_a.clone();
+            value.extend_from_slice(value_b);
+            insert_capped(&mut out, value, limit);
+        }
+    }
+    out
+}
+
+/// Iterator over all inputs of a grammar that are generated without running
+/// into the depth limit, with the same notion of depth as the generated
+/// code. Inputs are yielded in order of the depth they need and then by size,
+/// every distinct input once.
+pub struct Enumerator<'a> {
+    gram: &'a GrammarRust,
+    max_depth: usize,
+
+    /// Number of inputs still to yield
+    remaining: usize,
+
+    /// The smallest strings of every fragment that can be generated within
+    /// `budget` levels
+    layer: Vec<Strings>,
+    budget: usize,
+
+    /// Inputs that were already yielded
+    seen: HashSet<Vec<u8>>,
+
+    /// Inputs of the current depth that were not yielded yet
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl<'a> Enumerator<'a> {
+    /// Enumerate the inputs of the start node of `gram` up to `max_depth`,
+    /// stopping after `limit` inputs
+    pub fn new(gram: &'a GrammarRust, max_depth: usize, limit: usize) -> Self {
+        Self {
+            gram,
+            max_depth,
+            remaining: limit,
+            layer: vec![Strings::new(); gram.fragments.len()],
+            budget: 0,
+            seen: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Compute the strings of every fragment with one more level of depth.
+    /// Only the strings needed for the remaining inputs are kept.
+    fn next_layer(&mut self) {
+        let limit = self.remaining + self.seen.len();
+        let empty = || {
+            let mut set = Strings::new();
+            set.insert((0, Vec::new()));
+            set
+        };
+
+        let layer = &self.layer;
+        let next = self
+            .gram
+            .fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::NonTerminal(options) => {
+                    let mut out = Strings::new();
+                    for option in options.iter() {
+                        for (_, value) in layer[option.0].iter() {
+                            insert_capped(&mut out, value.clone(), limit);
+                        }
+                    }
+                    out
+                }
+                Fragment::Expression(expr) => expr
+                    .iter()
+                    .fold(empty(), |acc, exp| concat(&acc, &layer[exp.0], limit)),
+                &Fragment::Repeat { fragment, min, max } => {
+                    // A string needing more than `limit` iterations beyond
+                    // `min` is longer than the strings of `limit` smaller
+                    // counts, so it is never among the smallest ones
+                    let mut out = Strings::new();
+                    let mut acc = empty();
+                    for count in 0..=max.min(min.saturating_add(limit)) {
+                        if count > 0 {
+                            acc = concat(&acc, &layer[fragment.0], limit);
+                        }
+                        if acc.is_empty() {
+                            break;
+                        }
+                        if count >= min {
+                            for (_, value) in acc.iter() {
+                                insert_capped(&mut out, value.clone(), limit);
+                            }
+                        }
+                    }
+                    out
+                }
+                Fragment::Terminal(value) => {
+                    let mut out = Strings::new();
+                    out.insert((value.len(), value.clone()));
+                    out
+                }
+                Fragment::Nop => empty(),
+                Fragment::Unreachable => Strings::new(),
+            })
+            .collect();
+
+        self.layer = next;
+        self.budget += 1;
+    }
+}
+
+impl<'a> Iterator for Enumerator<'a> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        while self.pending.is_empty() {
+            if self.budget >= self.max_depth {
+                return None;
+            }
+            self.next_layer();
+
+            let start = self.gram.start.unwrap();
+            for (_, value) in self.layer[start.0].iter() {
+                if self.seen.insert(value.clone()) {
+                    self.pending.push_back(value.clone());
+                }
+            }
+        }
+
+        self.remaining -= 1;
+        self.pending.pop_front()
+    }
+}
+
+/// A set of inputs that use every k-path of a grammar. It is small, but not
+/// necessarily the smallest such set.
+#[derive(Clone, Debug, Default)]
+pub struct KPathCover {
+    /// The inputs
+    pub inputs: Vec<Vec<u8>>,
+
+    /// Number of k-paths of the grammar that can be reached from the start
+    /// node, all of which are used by one of the inputs
+    pub paths: usize,
+}
+
+/// Derivations of a grammar that pass through given rules
+struct Deriver<'a> {
+    gram: &'a GrammarRust,
+    k: usize,
+
+    /// Fragments of rules
+    rules: HashSet<FragmentId>,
+
+    /// Height of the smallest derivation tree of every fragment, `None` if
+    /// it can not generate anything
+    height: Vec<Option<usize>>,
+
+    /// Rules that are used by the alternatives below a fragment, without
+    /// passing through another rule
+    uses: Vec<BTreeSet<FragmentId>>,
+}
+
+impl<'a> Deriver<'a> {
+    fn new(gram: &'a GrammarRust, k: usize) -> Self {
+        let rules: HashSet<FragmentId> = gram.name_to_fragment.values().copied().collect();
+
+        // Find the heights and used rules by iterating to a fixed point
+        let mut height: Vec<Option<usize>> = vec![None; gram.fragments.len()];
+        let mut uses = vec![BTreeSet::new(); gram.fragments.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, fragment) in gram.fragments.iter().enumerate() {
+                let children = fragment.children();
+                let new_height = match fragment {
+                    Fragment::NonTerminal(_) => {
+                        children.iter().filter_map(|child| height[child.0]).min()
+                    }
+                    Fragment::Expression(_) => children
+                        .iter()
+                        .map(|child| height[child.0])
+                        .try_fold(0, |acc, child| child.map(|child| acc.max(child))),
+                    Fragment::Repeat { min: 0, .. } => Some(0),
+                    Fragment::Repeat { fragment, .. } => height[fragment.0],
+                    Fragment::Terminal(_) | Fragment::Nop => Some(0),
+                    Fragment::Unreachable => None,
+                }
+                .map(|child| child + 1);
+                if new_height.is_some() && (height[idx].is_none() || new_height < height[idx]) {
+                    height[idx] = new_height;
+                    changed = true;
+                }
+
+                for child in children.iter() {
+                    let used: Vec<FragmentId> = if rules.contains(child) {
+                        vec![*child]
+                    } else {
+                        uses[child.0].iter().copied().collect()
+                    };
+                    for rule in used {
+                        changed |= uses[idx].insert(rule);
+                    }
+                }
+            }
+        }
+
+        Self {
+            gram,
+            k,
+            rules,
+            height,
+            uses,
+        }
+    }
+
+    /// Check if expanding `id` can directly lead to the rule `target`
+    fn leads_to(&self, id: FragmentId, target: FragmentId) -> bool {
+        self.height[id.0].is_some()
+            && if self.rules.contains(&id) {
+                id == target
+            } else {
+                self.uses[id.0].contains(&target)
+            }
+    }
+
+    /// Productive rules directly used by a rule
+    fn successors(&self, rule: FragmentId) -> impl Iterator<Item = FragmentId> + '_ {
+        self.uses[rule.0]
+            .iter()
+            .copied()
+            .filter(move |used| self.height[used.0].is_some())
+    }
+
+    /// Expand `id` into `out`, passing through the chain of rules `targets`,
+    /// where every rule is used directly by the one before. Everything else
+    /// is expanded with the smallest derivation tree. The k-paths of the
+    /// derivation are added to `covered`.
+    fn derive(
+        &self,
+        id: FragmentId,
+        targets: &[FragmentId],
+        stack: &mut Vec<FragmentId>,
+        covered: &mut HashSet<Vec<FragmentId>>,
+        out: &mut Vec<u8>,
+    ) {
+        let is_rule = self.rules.contains(&id);
+        if is_rule {
+            stack.push(id);
+            if stack.len() >= self.k {
+                covered.insert(stack[stack.len() - self.k..].to_vec());
+            }
+        }
+
+        // Pass the next target on to the child that leads to it, a rule
+        // being reached consumes it
+        let carry = |child: FragmentId| -> Option<&[FragmentId]> {
+            let target = *targets.first()?;
+            if !self.leads_to(child, target) {
+                return None;
+            }
+            Some(if self.rules.contains(&child) {
+                &targets[1..]
+            } else {
+                targets
+            })
+        };
+
+        match &self.gram.fragments[id.0] {
+            Fragment::NonTerminal(options) => {
+                let (option, rest) = options
+                    .iter()
+                    .find_map(|&option| carry(option).map(|rest| (option, rest)))
+                    .unwrap_or_else(|| {
+                        let option = *options
+                            .iter()
+                            .filter(|option| self.height[option.0].is_some())
+                            .min_by_key(|option| self.height[option.0])
+                            .unwrap();
+                        (option, &[][..])
+                    });
+                self.derive(option, rest, stack, covered, out);
+            }
+            Fragment::Expression(expr) => {
+                let mut carried = false;
+                for &exp in expr.iter() {
+                    match carry(exp).filter(|_| !carried) {
+                        Some(rest) => {
+                            carried = true;
+                            self.derive(exp, rest, stack, covered, out);
+                        }
+                        None => self.derive(exp, &[], stack, covered, out),
+                    }
+                }
+            }
+            &Fragment::Repeat { fragment, min, .. } => match carry(fragment) {
+                Some(rest) => {
+                    self.derive(fragment, rest, stack, covered, out);
+                    for _ in 1..min {
+                        self.derive(fragment, &[], stack, covered, out);
+                    }
+                }
+                None => {
+                    for _ in 0..min {
+                        self.derive(fragment, &[], stack, covered, out);
+                    }
+                }
+            },
+            Fragment::Terminal(value) => out.extend_from_slice(value),
+            Fragment::Nop | Fragment::Unreachable => {}
+        }
+
+        if is_rule {
+            stack.pop();
+        }
+    }
+}
+
+/// Build a small set of inputs that together use every k-path of `gram`:
+/// every chain of `k` rules where each rule is used directly by an
+/// alternative of the one before, and the first one can be reached from the
+/// start node. The set is built greedily, one input for every k-path that is
+/// not used by the inputs built so far, and inputs whose k-paths are all used
+/// by later ones are dropped afterwards. Finding the smallest set is a set
+/// cover problem, so the result is not necessarily minimal.
+///
+/// The rules are the fragments of the named rules, so this is best used on
+/// a grammar that was not optimized.
+pub fn k_path_cover(gram: &GrammarRust, k: usize) -> KPathCover {
+    assert!(k > 0, "k-paths need at least one rule");
+    let deriver = Deriver::new(gram, k);
+    let start = gram.start.unwrap();
+    let mut cover = KPathCover::default();
+    if deriver.height[start.0].is_none() {
+        return cover;
+    }
+
+    // Shortest chains of rules from the start node to every reachable rule
+    let mut parent: HashMap<FragmentId, FragmentId> = HashMap::new();
+    let mut reachable = vec![start];
+    let mut queue = VecDeque::from(vec![start]);
+    while let Some(rule) = queue.pop_front() {
+        for used in deriver.successors(rule) {
+            if used != start && !parent.contains_key(&used) {
+                parent.insert(used, rule);
+                reachable.push(used);
+                queue.push_back(used);
+            }
+        }
+    }
+    reachable.sort();
+
+    // All k-paths starting at a reachable rule
+    let mut paths = Vec::new();
+    let mut stack: Vec<Vec<FragmentId>> = reachable.iter().map(|&rule| vec![rule]).collect();
+    while let Some(path) = stack.pop() {
+        if path.len() == k {
+            paths.push(path);
+            continue;
+        }
+        for used in deriver.successors(*path.last().unwrap()) {
+            let mut longer = path.clone();
+            longer.push(used);
+            stack.push(longer);
+        }
+    }
+    paths.sort();
+    cover.paths = paths.len();
+
+    // The k-paths used by every input
+    let mut inputs: Vec<(Vec<u8>, HashSet<Vec<FragmentId>>)> = Vec::new();
+    let mut covered = HashSet::new();
+    for path in paths.iter() {
+        if covered.contains(path) {
+            continue;
+        }
+
+        // Reach the first rule of the path, then follow the path
+        let mut chain = path.clone();
+        while let Some(&prev) = parent.get(&chain[0]) {
+            chain.insert(0, prev);
+        }
+        let mut out = Vec::new();
+        let mut used = HashSet::new();
+        deriver.derive(start, &chain[1..], &mut Vec::new(), &mut used, &mut out);
+        assert!(
+            used.contains(path),
+            "derivation for a k-path does not use it: {:?}",
+            path
+        );
+        covered.extend(used.iter().cloned());
+        inputs.push((out, used));
+    }
+
+    // Later inputs cover the paths they were built for and often more, so
+    // drop earlier inputs that became redundant
+    let mut users: HashMap<&Vec<FragmentId>, usize> = HashMap::new();
+    for (_, used) in inputs.iter() {
+        for path in used.iter() {
+            *users.entry(path).or_default() += 1;
+        }
+    }
+    let mut keep = vec![true; inputs.len()];
+    for (idx, (_, used)) in inputs.iter().enumerate() {
+        if used.iter().all(|path| users[path] > 1) {
+            keep[idx] = false;
+            for path in used.iter() {
+                *users.get_mut(path).unwrap() -= 1;
+            }
+        }
+    }
+    cover.inputs = inputs
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|((input, _), _)| input)
+        .collect();
+
+    cover
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Recognizer;
+    use crate::Grammar;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn enumerates_by_depth_and_size() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>"], ["-", "<digit>"], ["<digit>", "<start>"]], "<digit>": [["0"], ["1"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+
+        let inputs: Vec<Vec<u8>> = Enumerator::new(&gram, 6, 1000).collect();
+        assert_eq!(
+            &inputs[..4],
+            &[b"0".to_vec(), b"1".to_vec(), b"-0".to_vec(), b"-1".to_vec()]
+        );
+        assert_eq!(inputs.iter().collect::<HashSet<_>>().len(), inputs.len());
+
+        // Every input the generated code can produce without being cut short
+        // is enumerated
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut out = Vec::new();
+        for _ in 0..1000 {
+            if gram.generate_complete(&mut out, 6, &mut rng) {
+                assert!(inputs.contains(&out), "{:?}", out);
+            }
+        }
+
+        let recognizer = Recognizer::new(&gram);
+        assert!(inputs.iter().all(|input| recognizer.accepts(input)));
+
+        let limited: Vec<Vec<u8>> = Enumerator::new(&gram, 6, 5).collect();
+        assert_eq!(&limited[..], &inputs[..5]);
+    }
+
+    #[test]
+    fn covers_all_k_paths() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<b>"]], "<a>": [["x"], ["(", "<b>", ")"]], "<b>": [["y"], ["[", "<a>", "]"], ["<c>{0,2}"]], "<c>": [["z"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let cover = k_path_cover(&gram, 2);
+        // start-a, start-b, a-b, b-a, b-c
+        assert_eq!(cover.paths, 5);
+        assert!(cover.inputs.len() <= 5);
+
+        let recognizer = Recognizer::new(&gram);
+        assert!(cover.inputs.iter().all(|input| recognizer.accepts(input)));
+        assert!(cover.inputs.iter().any(|input| input.contains(&b'z')));
+    }
+
+    #[test]
+    fn long_repetitions_are_capped() {
+        let grammar: Grammar =
+            serde_json::from_str(r#"{"<start>": [["<e>{0,4000000000}"]], "<e>": [[], ["a"]]}"#)
+                .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let inputs: Vec<Vec<u8>> = Enumerator::new(&gram, 8, 4).collect();
+        assert_eq!(
+            inputs,
+            vec![b"".to_vec(), b"a".to_vec(), b"aa".to_vec(), b"aaa".to_vec()]
+        );
+    }
+
+    #[test]
+    fn covers_json_with_few_inputs() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/grammars/json.json");
+        let grammar = crate::frontend::load(path, None).unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let cover = k_path_cover(&gram, 3);
+        assert_eq!(cover.paths, 73);
+        assert_eq!(cover.inputs.len(), 13);
+
+        let recognizer = Recognizer::new(&gram);
+        assert!(cover.inputs.iter().all(|input| recognizer.accepts(input)));
+    }
+}
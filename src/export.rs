@@ -0,0 +1,718 @@
+//! Serializers from `GrammarRust` to the grammar formats of other fuzzers.
+//!
+//! The textual formats (Nautilus and ANTLR4/Grammarinator) are produced from
+//! a rule-level view of the fragment graph: named fragments become rules,
+//! anonymous sequences and choices become auxiliary rules `f<id>`. They are
+//! most readable when exported from a grammar that was not optimized.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+use serde::Serialize;
+
+use crate::{Fragment, FragmentId, GrammarRust, UNBOUNDED_REPEAT_SPAN};
+
+/// The formats a grammar can be exported to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Nautilus' Python grammar format (`ctx.rule(...)`)
+    Nautilus,
+
+    /// ANTLR4 combined grammar, as consumed by Grammarinator
+    Antlr,
+
+    /// Gramatron-style automaton in the JSON layout of LibAFL's `Automaton`
+    Gramatron,
+
+    /// Graphviz DOT graph of the fragments
+    Dot,
+}
+
+impl Format {
+    /// Parse a format name as given on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nautilus" => Some(Format::Nautilus),
+            "antlr" | "antlr4" | "g4" | "grammarinator" => Some(Format::Antlr),
+            "gramatron" | "automaton" => Some(Format::Gramatron),
+            "dot" | "graphviz" => Some(Format::Dot),
+            _ => None,
+        }
+    }
+}
+
+/// Element of a rule alternative in the rule-level view
+enum Item {
+    Terminal(Vec<u8>),
+    Rule(usize),
+    Repeat(Box<Item>, usize, usize),
+}
+
+/// Rule-level view of the fragments reachable from the start node
+struct Rules {
+    /// Rule names and alternatives, the start rule first
+    rules: Vec<(String, Vec<Vec<Item>>)>,
+}
+
+struct RulesBuilder<'a> {
+    gram: &'a GrammarRust,
+    names: BTreeMap<usize, &'a str>,
+    index: BTreeMap<usize, usize>,
+    rules: Vec<(String, Vec<Vec<Item>>)>,
+    worklist: VecDeque<usize>,
+}
+
+impl Rules {
+    fn new(gram: &GrammarRust) -> Self {
+        let mut names = BTreeMap::new();
+        for (name, id) in gram.name_to_fragment.iter() {
+            names.entry(id.0).or_insert_with(|| name.as_str());
+        }
+
+        let mut builder = RulesBuilder {
+            gram,
+            names,
+            index: BTreeMap::new(),
+            rules: Vec::new(),
+            worklist: VecDeque::new(),
+        };
+        builder.rule(gram.start.unwrap().0);
+        while let Some(id) = builder.worklist.pop_front() {
+            let alternatives = builder.alternatives(id);
+            builder.rules[builder.index[&id]].1 = alternatives;
+        }
+
+        Rules {
+            rules: builder.rules,
+        }
+    }
+}
+
+impl RulesBuilder<'_> {
+    /// Get the rule index for a fragment, creating the rule if needed
+    fn rule(&mut self, id: usize) -> usize {
+        if let Some(&idx) = self.index.get(&id) {
+            return idx;
+        }
+
+        let name = match self.names.get(&id) {
+            Some(name) => name
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string(),
+            None => format!("f{}", id),
+        };
+        self.index.insert(id, self.rules.len());
+        self.rules.push((name, Vec::new()));
+        self.worklist.push_back(id);
+
+        self.rules.len() - 1
+    }
+
+    fn alternatives(&mut self, id: usize) -> Vec<Vec<Item>> {
+        match &self.gram.fragments[id] {
+            Fragment::NonTerminal(options) => options
+                .iter()
+                .map(|option| self.sequence(option.0))
+                .collect(),
+            Fragment::Expression(expr) => {
+                vec![expr.iter().map(|item| self.item(item.0, 0)).collect()]
+            }
+            Fragment::Terminal(_) | Fragment::Repeat { .. } => vec![vec![self.inline(id, 0)]],
+            Fragment::Nop | Fragment::Unreachable => vec![Vec::new()],
+        }
+    }
+
+    /// Items of one alternative of a choice
+    fn sequence(&mut self, id: usize) -> Vec<Item> {
+        if self.names.contains_key(&id) {
+            return vec![self.item(id, 0)];
+        }
+        match &self.gram.fragments[id] {
+            Fragment::Expression(expr) => expr.iter().map(|item| self.item(item.0, 0)).collect(),
+            Fragment::Nop => Vec::new(),
+            _ => vec![self.item(id, 0)],
+        }
+    }
+
+    /// A reference to a fragment, inlining anonymous trivial fragments
+    fn item(&mut self, id: usize, depth: usize) -> Item {
+        if self.names.contains_key(&id) || depth > 32 {
+            return Item::Rule(self.rule(id));
+        }
+        self.inline(id, depth)
+    }
+
+    fn inline(&mut self, id: usize, depth: usize) -> Item {
+        match &self.gram.fragments[id] {
+            Fragment::Terminal(value) => Item::Terminal(value.clone()),
+            Fragment::Nop => Item::Terminal(Vec::new()),
+            Fragment::NonTerminal(options) if options.len() == 1 => {
+                self.item(options[0].0, depth + 1)
+            }
+            &Fragment::Repeat { fragment, min, max } => {
+                Item::Repeat(Box::new(self.item(fragment.0, depth + 1)), min, max)
+            }
+            _ => Item::Rule(self.rule(id)),
+        }
+    }
+}
+
+/// Make rule names unique identifiers, avoiding the `reserved` names
+fn identifiers(rules: &Rules, reserved: &[&str], lowercase_start: bool) -> Vec<String> {
+    let mut used: BTreeSet<String> = reserved.iter().map(|s| s.to_string()).collect();
+
+    rules
+        .rules
+        .iter()
+        .map(|(name, _)| {
+            let mut ident: String = name
+                .trim_start_matches('!')
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            if ident.is_empty()
+                || ident.starts_with(|c: char| c.is_ascii_digit())
+                || (lowercase_start && !ident.starts_with(|c: char| c.is_ascii_lowercase()))
+            {
+                ident.insert_str(0, "r_");
+            }
+
+            let mut unique = ident.clone();
+            let mut counter = 1;
+            while used.contains(&unique) {
+                counter += 1;
+                unique = format!("{}_{}", ident, counter);
+            }
+            used.insert(unique.clone());
+
+            unique
+        })
+        .collect()
+}
+
+/// Export to Nautilus' Python grammar format. Repetitions become an auxiliary
+/// rule with the minimum number of copies, followed by a chain of optional
+/// iterations. Like in the ANTLR export, repetitions with a span of at least
+/// `UNBOUNDED_REPEAT_SPAN` become unbounded.
+pub fn nautilus(gram: &GrammarRust) -> String {
+    let rules = Rules::new(gram);
+    let names = identifiers(&rules, &["START"], false);
+
+    let mut out = String::from("# Grammar exported by fzero\n");
+    out += &format!("ctx.rule(\"START\", \"{{{}}}\")\n", names[0]);
+
+    // Auxiliary rules for repetitions and non UTF-8 terminals
+    let mut aux = Vec::new();
+
+    fn text(item: &Item, names: &[String], aux: &mut Vec<(String, String)>) -> String {
+        match item {
+            Item::Terminal(value) => match std::str::from_utf8(value) {
+                Ok(s) => s
+                    .chars()
+                    .map(|c| match c {
+                        '"' => "\\\"".to_string(),
+                        '\\' => "\\\\".to_string(),
+                        '{' => "\\\\{".to_string(),
+                        '}' => "\\\\}".to_string(),
+                        ' '..='~' => c.to_string(),
+                        c if (c as u32) < 0x100 => format!("\\x{:02x}", c as u32),
+                        c => format!("\\U{:08x}", c as u32),
+                    })
+                    .collect(),
+                Err(_) => {
+                    let name = format!("__bytes_{}", aux.len());
+                    let bytes: String = value.iter().map(|b| format!("\\x{:02x}", b)).collect();
+                    aux.push((
+                        name.clone(),
+                        format!("ctx.bytes(\"{}\", b\"{}\")", name, bytes),
+                    ));
+                    format!("{{{}}}", name)
+                }
+            },
+            Item::Rule(idx) => format!("{{{}}}", names[*idx]),
+            Item::Repeat(inner, min, max) => {
+                let inner = text(inner, names, aux);
+                let name = format!("__repeat_{}", aux.len());
+                let rule = |name: &str, body: &str| format!("ctx.rule(\"{}\", \"{}\")", name, body);
+
+                // `min` copies followed by a chain of optional iterations,
+                // or a recursive rule for (practically) unbounded ones
+                let span = max - min;
+                let mut head = inner.repeat(*min);
+                let mut rules = Vec::new();
+                if span > 0 {
+                    head += &format!("{{{}_1}}", name);
+                }
+                rules.push(rule(&name, &head));
+                if span >= UNBOUNDED_REPEAT_SPAN {
+                    let tail = format!("{}_1", name);
+                    rules.push(rule(&tail, ""));
+                    rules.push(rule(&tail, &format!("{}{{{}}}", inner, tail)));
+                } else {
+                    for k in 1..=span {
+                        let tail = format!("{}_{}", name, k);
+                        rules.push(rule(&tail, ""));
+                        if k < span {
+                            let next = format!("{}{{{}_{}}}", inner, name, k + 1);
+                            rules.push(rule(&tail, &next));
+                        } else {
+                            rules.push(rule(&tail, &inner));
+                        }
+                    }
+                }
+                aux.push((name.clone(), rules.join("\n")));
+                format!("{{{}}}", name)
+            }
+        }
+    }
+
+    for (idx, (_, alternatives)) in rules.rules.iter().enumerate() {
+        for alternative in alternatives {
+            let body: String = alternative
+                .iter()
+                .map(|item| text(item, &names, &mut aux))
+                .collect();
+            out += &format!("ctx.rule(\"{}\", \"{}\")\n", names[idx], body);
+        }
+    }
+    for (_, rule) in aux {
+        out += &rule;
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Names that can not be used as rule names in ANTLR or in the Python code
+/// Grammarinator generates from it
+const ANTLR_RESERVED: &[&str] = &[
+    "grammar", "options", "tokens", "channels", "import", "fragment", "lexer", "parser", "returns",
+    "locals", "throws", "catch", "finally", "mode", "and", "as", "assert", "break", "class",
+    "continue", "def", "del", "elif", "else", "except", "for", "from", "global", "if", "in", "is",
+    "lambda", "not", "or", "pass", "raise", "return", "try", "while", "with", "yield",
+];
+
+/// Export to an ANTLR4 combined grammar named `name`, e.g. for Grammarinator
+pub fn antlr(gram: &GrammarRust, name: &str) -> String {
+    let rules = Rules::new(gram);
+    let names = identifiers(&rules, ANTLR_RESERVED, true);
+
+    fn literal(value: &[u8]) -> String {
+        let s = String::from_utf8_lossy(value);
+        let escaped: String = s
+            .chars()
+            .map(|c| match c {
+                '\'' => "\\'".to_string(),
+                '\\' => "\\\\".to_string(),
+                '\n' => "\\n".to_string(),
+                '\r' => "\\r".to_string(),
+                '\t' => "\\t".to_string(),
+                ' '..='~' => c.to_string(),
+                c if (c as u32) <= 0xffff => format!("\\u{:04X}", c as u32),
+                c => format!("\\u{{{:X}}}", c as u32),
+            })
+            .collect();
+        format!("'{}'", escaped)
+    }
+
+    fn text(item: &Item, names: &[String]) -> Option<String> {
+        match item {
+            Item::Terminal(value) if value.is_empty() => None,
+            Item::Terminal(value) => Some(literal(value)),
+            Item::Rule(idx) => Some(names[*idx].clone()),
+            Item::Repeat(inner, min, max) => {
+                let inner = text(inner, names)?;
+                let atom = if inner.contains(' ') {
+                    format!("({})", inner)
+                } else {
+                    inner
+                };
+
+                let mut parts = vec![atom.clone(); *min];
+                if max - min >= UNBOUNDED_REPEAT_SPAN {
+                    if parts.pop().is_some() {
+                        parts.push(format!("{}+", atom));
+                    } else {
+                        parts.push(format!("{}*", atom));
+                    }
+                } else {
+                    parts.extend(std::iter::repeat_n(format!("{}?", atom), max - min));
+                }
+                Some(parts.join(" "))
+            }
+        }
+    }
+
+    let mut out = format!("grammar {};\n", name);
+    for (idx, (_, alternatives)) in rules.rules.iter().enumerate() {
+        let alternatives: Vec<String> = alternatives
+            .iter()
+            .map(|alt| {
+                alt.iter()
+                    .filter_map(|item| text(item, &names))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect();
+        out += &format!("\n{}\n", names[idx]);
+        for (alt, text) in alternatives.iter().enumerate() {
+            let sep = if alt == 0 { ":" } else { "|" };
+            out += format!("    {} {}", sep, text).trim_end();
+            out.push('\n');
+        }
+        out += "    ;\n";
+    }
+
+    out
+}
+
+/// Transition of a Gramatron automaton
+#[derive(Serialize)]
+struct Trigger {
+    dest: usize,
+    term: String,
+}
+
+/// A Gramatron automaton, in the layout of LibAFL's `Automaton`
+#[derive(Serialize)]
+struct Automaton {
+    final_state: usize,
+    init_state: usize,
+    pda: Vec<Vec<Trigger>>,
+}
+
+/// Maximum number of states of an exported automaton
+pub const MAX_AUTOMATON_STATES: usize = 100_000;
+
+/// Maximum number of expansion steps spent on the transitions of one state,
+/// so nested repetitions and wide choices can not blow up exponentially
+pub const MAX_EXPANSION_STEPS: usize = 10_000;
+
+/// Export to a Gramatron-style finite state automaton.
+///
+/// The pushdown automaton of the grammar is approximated by bounding its
+/// stack to `stack_limit` pending fragments: every state is a stack of
+/// fragments still to be expanded, and every transition emits one terminal.
+/// Derivations that need a deeper stack are dropped, as are states that can
+/// no longer reach the final state.
+pub fn gramatron(gram: &GrammarRust, stack_limit: usize) -> String {
+    type Stack = Vec<FragmentId>;
+
+    /// Expand the top of `stack` until a terminal is on top, collecting
+    /// `(terminal, remaining stack)` pairs. `budget` bounds the depth of the
+    /// expansion and `steps` the total number of expansions.
+    fn expand(
+        gram: &GrammarRust,
+        mut stack: Stack,
+        stack_limit: usize,
+        budget: usize,
+        steps: &mut usize,
+        out: &mut Vec<(Vec<u8>, Stack)>,
+    ) {
+        if stack.len() > stack_limit || budget == 0 || *steps == 0 {
+            return;
+        }
+        *steps -= 1;
+        let top = match stack.pop() {
+            Some(top) => top,
+            None => {
+                out.push((Vec::new(), stack));
+                return;
+            }
+        };
+
+        match &gram.fragments[top.0] {
+            Fragment::Terminal(value) => out.push((value.clone(), stack)),
+            Fragment::NonTerminal(options) => {
+                for &option in options {
+                    let mut next = stack.clone();
+                    next.push(option);
+                    expand(gram, next, stack_limit, budget - 1, steps, out);
+                }
+            }
+            Fragment::Expression(expr) => {
+                stack.extend(expr.iter().rev());
+                expand(gram, stack, stack_limit, budget - 1, steps, out);
+            }
+            &Fragment::Repeat { fragment, min, max } => {
+                // More iterations than fit on the stack are dropped anyway
+                let max = max.min(stack_limit.saturating_sub(stack.len()));
+                for count in min..=max {
+                    let mut next = stack.clone();
+                    next.extend(std::iter::repeat_n(fragment, count));
+                    expand(gram, next, stack_limit, budget - 1, steps, out);
+                }
+            }
+            Fragment::Nop | Fragment::Unreachable => {
+                expand(gram, stack, stack_limit, budget - 1, steps, out)
+            }
+        }
+    }
+
+    let key = |stack: &Stack| stack.iter().map(|id| id.0).collect::<Vec<_>>();
+
+    let mut states: HashMap<Vec<usize>, usize> = HashMap::new();
+    let mut transitions: Vec<Vec<(usize, Vec<u8>)>> = Vec::new();
+    let mut worklist = VecDeque::new();
+
+    // State 0 is the initial state and state 1 the final (empty stack) state
+    let init = vec![gram.start.unwrap()];
+    states.insert(key(&init), 0);
+    states.insert(Vec::new(), 1);
+    transitions.resize(2, Vec::new());
+    worklist.push_back(init);
+
+    while let Some(stack) = worklist.pop_front() {
+        let src = states[&key(&stack)];
+
+        let mut expanded = Vec::new();
+        let mut steps = MAX_EXPANSION_STEPS;
+        expand(gram, stack, stack_limit, 64, &mut steps, &mut expanded);
+
+        for (term, next) in expanded {
+            let dest = match states.get(&key(&next)) {
+                Some(&dest) => dest,
+                None if states.len() < MAX_AUTOMATON_STATES => {
+                    let dest = states.len();
+                    states.insert(key(&next), dest);
+                    transitions.push(Vec::new());
+                    worklist.push_back(next);
+                    dest
+                }
+                None => continue,
+            };
+            if !transitions[src].contains(&(dest, term.clone())) {
+                transitions[src].push((dest, term));
+            }
+        }
+    }
+    if states.len() >= MAX_AUTOMATON_STATES {
+        log::warn!("automaton truncated at {} states", MAX_AUTOMATON_STATES);
+    }
+
+    // Only keep states from which the final state is reachable
+    let mut live = vec![false; transitions.len()];
+    live[1] = true;
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for state in 0..transitions.len() {
+            if !live[state] && transitions[state].iter().any(|(dest, _)| live[*dest]) {
+                live[state] = true;
+                changed = true;
+            }
+        }
+    }
+    if !live[0] {
+        log::warn!(
+            "no derivation fits into a stack of {} fragments",
+            stack_limit
+        );
+    }
+
+    // Renumber the live states densely
+    let mut renumber = vec![0; transitions.len()];
+    let mut count = 0;
+    for state in 0..transitions.len() {
+        if live[state] {
+            renumber[state] = count;
+            count += 1;
+        }
+    }
+
+    let automaton = Automaton {
+        init_state: renumber[0],
+        final_state: renumber[1],
+        pda: transitions
+            .iter()
+            .enumerate()
+            .filter(|(state, _)| live[*state])
+            .map(|(_, triggers)| {
+                triggers
+                    .iter()
+                    .filter(|(dest, _)| live[*dest])
+                    .map(|(dest, term)| Trigger {
+                        dest: renumber[*dest],
+                        term: String::from_utf8_lossy(term).to_string(),
+                    })
+                    .collect()
+            })
+            .collect(),
+    };
+
+    serde_json::to_string(&automaton).unwrap()
+}
+
+/// Export the reachable fragments as a Graphviz DOT graph
+pub fn dot(gram: &GrammarRust) -> String {
+    let mut names: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for (name, id) in gram.name_to_fragment.iter() {
+        names.entry(id.0).or_default().push(name);
+    }
+
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+
+    let mut out = String::from("digraph fzero {\n    node [shape=box, fontname=monospace];\n");
+
+    let mut seen = BTreeSet::new();
+    let mut worklist = vec![gram.start.unwrap().0];
+    while let Some(id) = worklist.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        let (kind, children): (String, Vec<usize>) = match &gram.fragments[id] {
+            Fragment::NonTerminal(options) => {
+                ("choice".to_string(), options.iter().map(|o| o.0).collect())
+            }
+            Fragment::Expression(expr) => ("seq".to_string(), expr.iter().map(|e| e.0).collect()),
+            Fragment::Terminal(value) => {
+                let mut text: String = String::from_utf8_lossy(value).chars().take(32).collect();
+                if text.len() < value.len() {
+                    text += "...";
+                }
+                (format!("{:?}", text), Vec::new())
+            }
+            Fragment::Repeat { fragment, min, max } => {
+                (format!("repeat {{{},{}}}", min, max), vec![fragment.0])
+            }
+            Fragment::Nop => ("nop".to_string(), Vec::new()),
+            Fragment::Unreachable => ("unreachable".to_string(), Vec::new()),
+        };
+
+        let mut label = escape(&format!("{}: {}", id, kind));
+        if let Some(names) = names.get(&id) {
+            label = format!("{}\\n{}", escape(&names.join(" ")), label);
+        }
+        out += &format!("    f{} [label=\"{}\"];\n", id, label);
+
+        let ordered = !matches!(gram.fragments[id], Fragment::Repeat { .. });
+        for (idx, child) in children.iter().enumerate() {
+            if ordered && children.len() > 1 {
+                out += &format!("    f{} -> f{} [label=\"{}\"];\n", id, child, idx);
+            } else {
+                out += &format!("    f{} -> f{};\n", id, child);
+            }
+        }
+        worklist.extend(children.into_iter().rev());
+    }
+    out += "}\n";
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enumerate::Enumerator;
+    use crate::Grammar;
+
+    fn grammar() -> GrammarRust {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<greeting>", "<name>{1,2}"]], "<greeting>": [["hi"], ["hello"]], "<name>": [["bob"], ["al"]]}"#,
+        )
+        .unwrap();
+        GrammarRust::new(&grammar, None)
+    }
+
+    fn language(gram: &GrammarRust) -> BTreeSet<Vec<u8>> {
+        Enumerator::new(gram, 16, 1000).collect()
+    }
+
+    #[test]
+    fn nautilus_rules() {
+        let out = nautilus(&grammar());
+        assert!(out.starts_with("# Grammar exported by fzero\nctx.rule(\"START\", \"{start}\")\n"));
+        for line in [
+            "ctx.rule(\"start\", \"{greeting}{__repeat_0}\")",
+            "ctx.rule(\"greeting\", \"hi\")",
+            "ctx.rule(\"greeting\", \"hello\")",
+            "ctx.rule(\"name\", \"al\")",
+            "ctx.rule(\"__repeat_0\", \"{name}{__repeat_0_1}\")",
+            "ctx.rule(\"__repeat_0_1\", \"\")",
+            "ctx.rule(\"__repeat_0_1\", \"{name}\")",
+        ] {
+            assert!(out.lines().any(|l| l == line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn nautilus_large_repetitions() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>{2,4000000000}", "<a>{0,10}"]], "<a>": [["x"]]}"#,
+        )
+        .unwrap();
+        let out = nautilus(&GrammarRust::new(&grammar, None));
+        assert!(out.len() < 2000, "{}", out.len());
+        for line in [
+            "ctx.rule(\"__repeat_0\", \"{a}{a}{__repeat_0_1}\")",
+            "ctx.rule(\"__repeat_0_1\", \"{a}{__repeat_0_1}\")",
+            "ctx.rule(\"__repeat_1_9\", \"{a}{__repeat_1_10}\")",
+            "ctx.rule(\"__repeat_1_10\", \"{a}\")",
+        ] {
+            assert!(out.lines().any(|l| l == line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn antlr_round_trips() {
+        let gram = grammar();
+        let out = antlr(&gram, "Fzero");
+        assert!(out.starts_with("grammar Fzero;\n"));
+        assert!(out.contains("start\n    : greeting name name?\n    ;\n"));
+        assert!(out.contains("greeting\n    : 'hi'\n    | 'hello'\n    ;\n"));
+
+        let imported = crate::frontend::antlr::parse(&out).unwrap();
+        let imported = GrammarRust::new(&imported, None);
+        assert_eq!(language(&imported), language(&gram));
+    }
+
+    #[test]
+    fn gramatron_accepts_the_language() {
+        let gram = grammar();
+        let automaton: serde_json::Value = serde_json::from_str(&gramatron(&gram, 16)).unwrap();
+        let init = automaton["init_state"].as_u64().unwrap() as usize;
+        let last = automaton["final_state"].as_u64().unwrap() as usize;
+        let pda = automaton["pda"].as_array().unwrap();
+
+        // The automaton is acyclic here, so all its paths can be walked
+        let mut accepted = BTreeSet::new();
+        let mut paths = vec![(init, Vec::new())];
+        while let Some((state, input)) = paths.pop() {
+            if state == last {
+                accepted.insert(input.clone());
+            }
+            for trigger in pda[state].as_array().unwrap() {
+                let mut next: Vec<u8> = input.clone();
+                next.extend_from_slice(trigger["term"].as_str().unwrap().as_bytes());
+                paths.push((trigger["dest"].as_u64().unwrap() as usize, next));
+            }
+        }
+        assert_eq!(accepted, language(&gram));
+    }
+
+    #[test]
+    fn gramatron_bounds_nested_repetitions() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>{0,100000}"]], "<a>": [["<b>{0,1000}", "<b>{0,1000}"]], "<b>": [["x"], ["y"]]}"#,
+        )
+        .unwrap();
+        let automaton: serde_json::Value =
+            serde_json::from_str(&gramatron(&GrammarRust::new(&grammar, None), 8)).unwrap();
+        assert!(!automaton["pda"].as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dot_graph() {
+        // Exported like the command line does, after optimizing
+        let mut gram = grammar();
+        gram.optimize();
+        let out = dot(&gram);
+        assert!(out.starts_with("digraph fzero {\n"));
+        assert!(out.ends_with("}\n"));
+        assert!(out.contains("[label=\"<greeting>\\n0: choice\"];\n"));
+        assert!(out.contains("repeat {1,2}\"];\n"));
+        assert!(out.contains(": \\\"hello\\\"\"];\n"));
+        assert_eq!(out.matches(" -> ").count(), 7);
+    }
+}
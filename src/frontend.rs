@@ -0,0 +1,301 @@
+//! Frontends that convert grammars written in other formats into the JSON
+//! `Grammar` representation used by fzero.
+//!
+//! Both frontends parse their input into a small expression tree (`Expr`)
+//! which is then lowered into alternatives of sequences. Groups, character
+//! sets and nested alternatives become auxiliary rules named `<rule.N>`, and
+//! repetitions are expressed with the EBNF-style suffixes (`?`, `*`, `+`,
+//! `{m,n}`) understood by `GrammarRust`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::Grammar;
+
+pub mod abnf;
+pub mod antlr;
+
+/// Maximum number of characters an auxiliary rule for a character range is
+/// expanded to. Larger ranges (e.g. `%x80-10FFFF`) are sampled evenly.
+pub const MAX_CHAR_RANGE: usize = 256;
+
+/// An error that occured while parsing a grammar in one of the supported
+/// input formats
+#[derive(Debug)]
+pub struct ParseError {
+    /// Line (1-based) at which the error was detected
+    pub line: usize,
+
+    /// Description of the error
+    pub message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for std::io::Error {
+    fn from(err: ParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The grammar formats that can be converted into a `Grammar`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Augmented BNF as used in RFCs (RFC 5234 and RFC 7405)
+    Abnf,
+
+    /// ANTLR4 `.g4` combined, lexer or parser grammars (subset)
+    Antlr,
+
+    /// The fzero/F1-style JSON grammar format
+    Json,
+}
+
+impl Format {
+    /// Guess the format of a grammar file from its extension
+    pub fn from_path(path: impl AsRef<Path>) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "abnf" => Some(Format::Abnf),
+            "g4" => Some(Format::Antlr),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Parse a format name as given on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "abnf" => Some(Format::Abnf),
+            "antlr" | "antlr4" | "g4" => Some(Format::Antlr),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a grammar given in `format` into a `Grammar`
+pub fn parse(source: &str, format: Format) -> Result<Grammar, ParseError> {
+    match format {
+        Format::Abnf => abnf::parse(source),
+        Format::Antlr => antlr::parse(source),
+        Format::Json => serde_json::from_str(source).map_err(|err| ParseError {
+            line: err.line(),
+            message: err.to_string(),
+        }),
+    }
+}
+
+/// Load a grammar file in any supported format. If `format` is `None` the
+/// format is guessed from the file extension, falling back to JSON.
+pub fn load(path: impl AsRef<Path>, format: Option<Format>) -> std::io::Result<Grammar> {
+    let format = format
+        .or_else(|| Format::from_path(&path))
+        .unwrap_or(Format::Json);
+    let source = std::fs::read_to_string(path)?;
+
+    Ok(parse(&source, format)?)
+}
+
+/// Serialize a grammar to JSON in the layout used by the bundled grammars:
+/// one rule per line, with one alternative per line for long rules
+pub fn to_json(grammar: &Grammar) -> String {
+    let quote = |s: &String| serde_json::to_string(s).unwrap();
+
+    let mut rules = Vec::new();
+    for (name, alternatives) in grammar.0.iter() {
+        let alternatives: Vec<String> = alternatives
+            .iter()
+            .map(|alt| format!("[{}]", alt.iter().map(quote).collect::<Vec<_>>().join(", ")))
+            .collect();
+
+        let inline = format!("  {}: [{}]", quote(name), alternatives.join(", "));
+        if inline.len() <= 100 {
+            rules.push(inline);
+        } else {
+            rules.push(format!(
+                "  {}: [\n    {}\n  ]",
+                quote(name),
+                alternatives.join(",\n    ")
+            ));
+        }
+    }
+
+    format!("{{\n{}\n}}\n", rules.join(",\n"))
+}
+
+/// Grammar expression as produced by the frontends before lowering
+#[derive(Clone, Debug)]
+pub(crate) enum Expr {
+    /// Choose one of the expressions
+    Alt(Vec<Expr>),
+
+    /// Concatenate the expressions
+    Seq(Vec<Expr>),
+
+    /// Repeat the expression between `min` and `max` times, where `None`
+    /// means unbounded
+    Repeat(Box<Expr>, usize, Option<usize>),
+
+    /// A literal string
+    Literal(String),
+
+    /// Reference to a rule by its name (without angle brackets)
+    Rule(String),
+
+    /// Any one of the characters
+    Chars(Vec<char>),
+}
+
+impl Expr {
+    /// All characters in the inclusive range `lo..=hi`, evenly sampled down to
+    /// `MAX_CHAR_RANGE` characters for huge ranges. Callers check that `lo`
+    /// is not above `hi`.
+    pub(crate) fn char_range(lo: u32, hi: u32) -> Expr {
+        debug_assert!(lo <= hi);
+        let span = (hi - lo) as usize + 1;
+        let step = span.div_ceil(MAX_CHAR_RANGE).max(1);
+
+        let mut chars: Vec<char> = (lo..=hi).step_by(step).filter_map(char::from_u32).collect();
+        if let Some(hi) = char::from_u32(hi) {
+            if chars.last() != Some(&hi) {
+                chars.push(hi);
+            }
+        }
+
+        Expr::Chars(chars)
+    }
+}
+
+/// Lowers frontend expressions into the rules of a `Grammar`
+#[derive(Default)]
+pub(crate) struct Lowering {
+    /// The rules produced so far, keyed by `<name>`
+    rules: BTreeMap<String, Vec<Vec<String>>>,
+
+    /// Number of auxiliary rules created per rule
+    aux_count: BTreeMap<String, usize>,
+
+    /// Auxiliary rules that were already created for an identical expression
+    aux_cache: BTreeMap<String, String>,
+}
+
+impl Lowering {
+    /// Add the alternatives of `expr` to the rule `name`. If `separator` is
+    /// set it is inserted between all elements of a sequence, which is used
+    /// for parser rules where tokens are separated by ignored whitespace.
+    pub(crate) fn add_rule(&mut self, name: &str, expr: &Expr, separator: Option<&str>) {
+        let alternatives = self.alternatives(name, expr, separator);
+        self.rules
+            .entry(format!("<{}>", name))
+            .or_default()
+            .extend(alternatives);
+    }
+
+    /// Finish lowering, adding a `<start>` rule that refers to `start` unless
+    /// the grammar already defines one
+    pub(crate) fn finish(mut self, start: Option<&str>) -> Grammar {
+        if let Some(start) = start {
+            if !self.rules.contains_key("<start>") {
+                self.rules
+                    .insert("<start>".to_string(), vec![vec![format!("<{}>", start)]]);
+            }
+        }
+
+        Grammar(self.rules)
+    }
+
+    fn alternatives(&mut self, rule: &str, expr: &Expr, sep: Option<&str>) -> Vec<Vec<String>> {
+        match expr {
+            Expr::Alt(alts) => alts
+                .iter()
+                .map(|alt| {
+                    let mut seq = Vec::new();
+                    self.sequence(rule, alt, sep, &mut seq);
+                    seq
+                })
+                .collect(),
+            Expr::Chars(chars) => chars.iter().map(|c| vec![c.to_string()]).collect(),
+            _ => {
+                let mut seq = Vec::new();
+                self.sequence(rule, expr, sep, &mut seq);
+                vec![seq]
+            }
+        }
+    }
+
+    fn sequence(&mut self, rule: &str, expr: &Expr, sep: Option<&str>, out: &mut Vec<String>) {
+        match expr {
+            Expr::Seq(items) => {
+                for item in items {
+                    self.sequence(rule, item, sep, out);
+                }
+            }
+            Expr::Literal(s) if s.is_empty() => {}
+            _ => {
+                if let (Some(sep), false) = (sep, out.is_empty()) {
+                    out.push(sep.to_string());
+                }
+                out.push(self.symbol(rule, expr, sep));
+            }
+        }
+    }
+
+    /// Get a single grammar symbol for `expr`, creating auxiliary rules as
+    /// needed
+    fn symbol(&mut self, rule: &str, expr: &Expr, sep: Option<&str>) -> String {
+        match expr {
+            Expr::Literal(s) => s.clone(),
+            Expr::Rule(name) => format!("<{}>", name),
+            Expr::Chars(chars) if chars.len() == 1 => chars[0].to_string(),
+            Expr::Repeat(inner, min, max) => {
+                // Iterations of a separated sequence need the separator as
+                // well, so repeat an auxiliary rule that starts with it
+                let base = match (inner.as_ref(), sep) {
+                    (Expr::Rule(name), None) => format!("<{}>", name),
+                    _ => self.aux(rule, inner, sep, sep.is_some()),
+                };
+
+                match (min, max) {
+                    (0, Some(1)) => format!("{}?", base),
+                    (0, None) => format!("{}*", base),
+                    (1, None) => format!("{}+", base),
+                    (min, None) => format!("{}{{{},}}", base, min),
+                    (min, Some(max)) if min == max => format!("{}{{{}}}", base, min),
+                    (min, Some(max)) => format!("{}{{{},{}}}", base, min, max),
+                }
+            }
+            Expr::Alt(_) | Expr::Seq(_) | Expr::Chars(_) => self.aux(rule, expr, sep, false),
+        }
+    }
+
+    /// Create (or reuse) an auxiliary rule for `expr`. With `lead` set, every
+    /// alternative starts with the separator.
+    fn aux(&mut self, rule: &str, expr: &Expr, sep: Option<&str>, lead: bool) -> String {
+        let key = format!("{:?}/{:?}/{}", expr, sep, lead);
+        if let Some(name) = self.aux_cache.get(&key) {
+            return name.clone();
+        }
+
+        let count = self.aux_count.entry(rule.to_string()).or_default();
+        *count += 1;
+        let name = format!("{}.{}", rule, count);
+
+        let mut alternatives = self.alternatives(rule, expr, sep);
+        if let (Some(sep), true) = (sep, lead) {
+            for alternative in alternatives.iter_mut() {
+                alternative.insert(0, sep.to_string());
+            }
+        }
+        let name = format!("<{}>", name);
+        self.rules.insert(name.clone(), alternatives);
+        self.aux_cache.insert(key, name.clone());
+
+        name
+    }
+}
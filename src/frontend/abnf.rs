@@ -0,0 +1,551 @@
+//! Parser for Augmented BNF (RFC 5234, with the case-sensitive string syntax
+//! of RFC 7405).
+//!
+//! Rule names are case-insensitive in ABNF and are converted to lowercase.
+//! The core rules of RFC 5234 appendix B (`ALPHA`, `DIGIT`, `CRLF`, ...) are
+//! added automatically when they are referenced but not defined. The first
+//! rule of the input becomes the start rule.
+//!
+//! Terminal values are Unicode code points, as grammars are made of strings.
+//! Values from `%x80` to `%xFF` (and `OCTET`) usually mean single bytes,
+//! which a grammar can not express, so they are rejected. Ranges that go
+//! beyond `%xFF`, like `%x80-10FFFF`, are taken as code points.
+
+use std::collections::BTreeSet;
+
+use super::{Expr, Lowering, ParseError};
+use crate::Grammar;
+
+/// The core rules from RFC 5234 appendix B.1
+const CORE_RULES: &str = r#"
+ALPHA  = %x41-5A / %x61-7A
+BIT    = "0" / "1"
+CHAR   = %x01-7F
+CR     = %x0D
+CRLF   = CR LF
+CTL    = %x00-1F / %x7F
+DIGIT  = %x30-39
+DQUOTE = %x22
+HEXDIG = DIGIT / "A" / "B" / "C" / "D" / "E" / "F"
+HTAB   = %x09
+LF     = %x0A
+LWSP   = *(WSP / CRLF WSP)
+SP     = %x20
+VCHAR  = %x21-7E
+WSP    = SP / HTAB
+"#;
+
+/// Parse an ABNF grammar into a `Grammar`
+pub fn parse(source: &str) -> Result<Grammar, ParseError> {
+    let rules = Parser::new(source).rules()?;
+
+    let mut defined: BTreeSet<String> = rules.iter().map(|(name, _, _)| name.clone()).collect();
+    let mut referenced = BTreeSet::new();
+    for (_, expr, _) in &rules {
+        collect_references(expr, &mut referenced);
+    }
+
+    let mut lowering = Lowering::default();
+    for (name, expr, _) in &rules {
+        lowering.add_rule(name, expr, None);
+    }
+
+    // Pull in the core rules that are used but not defined, including the
+    // core rules those depend on
+    let core = Parser::new(CORE_RULES).rules()?;
+    loop {
+        let missing: Vec<_> = core
+            .iter()
+            .filter(|(name, _, _)| referenced.contains(name) && !defined.contains(name))
+            .collect();
+        if missing.is_empty() {
+            break;
+        }
+        for (name, expr, _) in missing {
+            collect_references(expr, &mut referenced);
+            defined.insert(name.clone());
+            lowering.add_rule(name, expr, None);
+        }
+    }
+
+    if let Some(undefined) = referenced.difference(&defined).next() {
+        return Err(ParseError {
+            line: rules
+                .iter()
+                .find(|(_, expr, _)| {
+                    let mut refs = BTreeSet::new();
+                    collect_references(expr, &mut refs);
+                    refs.contains(undefined)
+                })
+                .map_or(0, |(_, _, line)| *line),
+            message: if undefined == "octet" {
+                "OCTET stands for any byte, which a grammar can not express".to_string()
+            } else {
+                format!("reference to undefined rule {:?}", undefined)
+            },
+        });
+    }
+
+    Ok(lowering.finish(rules.first().map(|(name, _, _)| name.as_str())))
+}
+
+fn collect_references(expr: &Expr, refs: &mut BTreeSet<String>) {
+    match expr {
+        Expr::Alt(items) | Expr::Seq(items) => {
+            items.iter().for_each(|item| collect_references(item, refs))
+        }
+        Expr::Repeat(inner, _, _) => collect_references(inner, refs),
+        Expr::Rule(name) => {
+            refs.insert(name.clone());
+        }
+        Expr::Literal(_) | Expr::Chars(_) => {}
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            src: source.as_bytes(),
+            pos: 0,
+            line: 1,
+        }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line,
+            message: message.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == b'\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skip a comment up to (but not including) the end of the line
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some(b'\n')) {
+            self.bump();
+        }
+    }
+
+    /// Skip blank lines and comment lines between rules
+    fn skip_empty_lines(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') => {
+                    self.bump();
+                }
+                Some(b';') => self.skip_comment(),
+                _ => return,
+            }
+        }
+    }
+
+    /// Skip `c-wsp`: whitespace, comments and line breaks that are followed
+    /// by whitespace (i.e. continuation lines of the current rule)
+    fn skip_cwsp(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') => {
+                    self.bump();
+                }
+                Some(b';') => self.skip_comment(),
+                Some(b'\r') | Some(b'\n') => {
+                    // Only a continuation if the next non-empty line is
+                    // indented
+                    let mut ahead = self.pos;
+                    while matches!(self.src.get(ahead), Some(b'\r') | Some(b'\n')) {
+                        ahead += 1;
+                    }
+                    if !matches!(self.src.get(ahead), Some(b' ') | Some(b'\t')) {
+                        return;
+                    }
+                    while self.pos < ahead {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn rules(&mut self) -> Result<Vec<(String, Expr, usize)>, ParseError> {
+        let mut rules: Vec<(String, Expr, usize)> = Vec::new();
+
+        loop {
+            self.skip_empty_lines();
+            if self.peek().is_none() {
+                return Ok(rules);
+            }
+
+            let line = self.line;
+            let name = self.rulename()?;
+            self.skip_cwsp();
+            if !self.eat(b'=') {
+                return self.error(format!("expected `=` after rule name {:?}", name));
+            }
+            let incremental = self.eat(b'/');
+            self.skip_cwsp();
+            let expr = self.alternation()?;
+            self.skip_cwsp();
+            if !matches!(self.peek(), None | Some(b'\r') | Some(b'\n')) {
+                return self.error(format!(
+                    "unexpected character {:?} in rule {:?}",
+                    self.peek().unwrap() as char,
+                    name
+                ));
+            }
+
+            match rules.iter_mut().find(|(other, _, _)| *other == name) {
+                Some((_, existing, _)) if incremental => {
+                    let mut alts = match std::mem::replace(existing, Expr::Alt(Vec::new())) {
+                        Expr::Alt(alts) => alts,
+                        other => vec![other],
+                    };
+                    match expr {
+                        Expr::Alt(more) => alts.extend(more),
+                        other => alts.push(other),
+                    }
+                    *existing = Expr::Alt(alts);
+                }
+                Some(_) => {
+                    return self.error(format!("duplicate definition of rule {:?}", name));
+                }
+                None if incremental => {
+                    return self.error(format!("`=/` for undefined rule {:?}", name));
+                }
+                None => rules.push((name, expr, line)),
+            }
+        }
+    }
+
+    fn rulename(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        if !self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+            return self.error("expected rule name");
+        }
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'-')
+        {
+            self.bump();
+        }
+
+        Ok(String::from_utf8_lossy(&self.src[start..self.pos]).to_ascii_lowercase())
+    }
+
+    fn alternation(&mut self) -> Result<Expr, ParseError> {
+        let mut alts = vec![self.concatenation()?];
+        loop {
+            let save = (self.pos, self.line);
+            self.skip_cwsp();
+            if self.eat(b'/') {
+                self.skip_cwsp();
+                alts.push(self.concatenation()?);
+            } else {
+                (self.pos, self.line) = save;
+                break;
+            }
+        }
+
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Expr::Alt(alts)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Expr, ParseError> {
+        let mut items = vec![self.repetition()?];
+        loop {
+            let save = (self.pos, self.line);
+            self.skip_cwsp();
+            if self.pos > save.0 && self.starts_element() {
+                items.push(self.repetition()?);
+            } else {
+                (self.pos, self.line) = save;
+                break;
+            }
+        }
+
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn starts_element(&self) -> bool {
+        matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric()
+            || matches!(c, b'*' | b'(' | b'[' | b'"' | b'%' | b'<'))
+    }
+
+    /// Parse an optional number, failing if it does not fit into a `u32`
+    fn number(&mut self, radix: u32) -> Result<Option<u32>, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| (c as char).is_digit(radix)) {
+            self.bump();
+        }
+        if start == self.pos {
+            return Ok(None);
+        }
+        // The digits are ASCII, so only the value can be out of range
+        let digits = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        match u32::from_str_radix(digits, radix) {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => self.error(format!("number {} is too large", digits)),
+        }
+    }
+
+    fn repetition(&mut self) -> Result<Expr, ParseError> {
+        let min = self.number(10)?;
+        let (min, max) = if self.eat(b'*') {
+            (min.unwrap_or(0), self.number(10)?)
+        } else {
+            match min {
+                Some(count) => (count, Some(count)),
+                None => return self.element(),
+            }
+        };
+
+        if max.is_some_and(|max| max < min) {
+            return self.error("repetition with a minimum above its maximum");
+        }
+        let element = self.element()?;
+        Ok(Expr::Repeat(
+            Box::new(element),
+            min as usize,
+            max.map(|max| max as usize),
+        ))
+    }
+
+    fn element(&mut self) -> Result<Expr, ParseError> {
+        match self.peek() {
+            Some(c) if c.is_ascii_alphabetic() => Ok(Expr::Rule(self.rulename()?)),
+            Some(b'(') => {
+                self.bump();
+                self.skip_cwsp();
+                let inner = self.alternation()?;
+                self.skip_cwsp();
+                if !self.eat(b')') {
+                    return self.error("expected `)`");
+                }
+                Ok(inner)
+            }
+            Some(b'[') => {
+                self.bump();
+                self.skip_cwsp();
+                let inner = self.alternation()?;
+                self.skip_cwsp();
+                if !self.eat(b']') {
+                    return self.error("expected `]`");
+                }
+                Ok(Expr::Repeat(Box::new(inner), 0, Some(1)))
+            }
+            Some(b'"') => self.char_val(),
+            Some(b'%') => {
+                self.bump();
+                match self.peek().map(|c| c.to_ascii_lowercase()) {
+                    Some(b's') | Some(b'i') => {
+                        self.bump();
+                        self.char_val()
+                    }
+                    Some(b'x') => self.num_val(16),
+                    Some(b'd') => self.num_val(10),
+                    Some(b'b') => self.num_val(2),
+                    _ => self.error("expected `x`, `d`, `b`, `s` or `i` after `%`"),
+                }
+            }
+            Some(b'<') => {
+                // Prose values can not be generated, so emit the prose itself
+                self.bump();
+                let start = self.pos;
+                while !matches!(self.peek(), None | Some(b'>')) {
+                    self.bump();
+                }
+                let prose = String::from_utf8_lossy(&self.src[start..self.pos]).to_string();
+                if !self.eat(b'>') {
+                    return self.error("unterminated prose value");
+                }
+                log::warn!(
+                    "line {}: prose value <{}> emitted literally",
+                    self.line,
+                    prose
+                );
+                Ok(Expr::Literal(prose))
+            }
+            _ => self.error("expected element"),
+        }
+    }
+
+    fn char_val(&mut self) -> Result<Expr, ParseError> {
+        if !self.eat(b'"') {
+            return self.error("expected `\"`");
+        }
+        let start = self.pos;
+        while !matches!(self.peek(), None | Some(b'"') | Some(b'\n')) {
+            self.bump();
+        }
+        let value = String::from_utf8_lossy(&self.src[start..self.pos]).to_string();
+        if !self.eat(b'"') {
+            return self.error("unterminated string");
+        }
+
+        Ok(Expr::Literal(value))
+    }
+
+    fn num_val(&mut self, radix: u32) -> Result<Expr, ParseError> {
+        // Skip the base character
+        self.bump();
+
+        let first = match self.number(radix)? {
+            Some(first) => first,
+            None => return self.error("expected number"),
+        };
+
+        if self.eat(b'-') {
+            return match self.number(radix)? {
+                Some(last) if (0x80..=0xff).contains(&last) => self.error(format!(
+                    "range up to {:#x} stands for bytes, which a grammar can not express",
+                    last
+                )),
+                Some(last) if last >= first => match Expr::char_range(first, last) {
+                    // Surrogates and values above 0x10FFFF are no characters
+                    Expr::Chars(chars) if chars.is_empty() => self.error(format!(
+                        "range {:#x}-{:#x} contains no valid character",
+                        first, last
+                    )),
+                    range => Ok(range),
+                },
+                _ => self.error("invalid character range"),
+            };
+        }
+
+        let mut value = String::new();
+        let mut push = |parser: &Self, c: u32| match char::from_u32(c) {
+            Some(_) if (0x80..=0xff).contains(&c) => parser.error(format!(
+                "value {:#x} stands for a byte, which a grammar can not express",
+                c
+            )),
+            Some(c) => {
+                value.push(c);
+                Ok(())
+            }
+            None => parser.error(format!("invalid character value {:#x}", c)),
+        };
+        push(self, first)?;
+        while self.eat(b'.') {
+            match self.number(radix)? {
+                Some(c) => push(self, c)?,
+                None => return self.error("expected number after `.`"),
+            }
+        }
+
+        Ok(Expr::Literal(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_version() {
+        let grammar = parse(
+            "; a comment\n\
+             HTTP-version = HTTP-name \"/\" DIGIT \".\" DIGIT\n\
+             HTTP-name    = %x48.54.54.50 ; \"HTTP\"\n\
+             list         = 1*3( DIGIT / \"x\" )\n\
+             \x20              [ \";\" ]\n",
+        )
+        .unwrap();
+
+        assert_eq!(grammar.0["<start>"], vec![vec!["<http-version>"]]);
+        assert_eq!(
+            grammar.0["<http-version>"],
+            vec![vec!["<http-name>", "/", "<digit>", ".", "<digit>"]]
+        );
+        assert_eq!(grammar.0["<http-name>"], vec![vec!["HTTP"]]);
+        assert_eq!(
+            grammar.0["<list>"],
+            vec![vec!["<list.1>{1,3}", "<list.2>?"]]
+        );
+        assert_eq!(grammar.0["<list.1>"], vec![vec!["<digit>"], vec!["x"]]);
+        assert_eq!(grammar.0["<digit>"].len(), 10);
+    }
+
+    #[test]
+    fn undefined_rule() {
+        let err = parse("a = b\n").unwrap_err();
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn invalid_values() {
+        for source in ["a = %x7A-61\n", "a = 3*1\"x\"\n"] {
+            assert!(parse(source).is_err(), "{}", source);
+        }
+
+        // Counts that do not fit into a u32 are errors, not missing counts
+        for source in [
+            "a = 99999999999*\"x\"\n",
+            "a = *99999999999\"x\"\n",
+            "a = 99999999999\"x\"\n",
+            "a = %x100000000\n",
+        ] {
+            let err = parse(source).unwrap_err();
+            assert!(err.message.contains("too large"), "{}", source);
+        }
+
+        // Ranges without a single valid character
+        for source in ["a = %xD800-DFFF\n", "a = %x110000-110010\n"] {
+            let err = parse(source).unwrap_err();
+            assert!(err.message.contains("no valid character"), "{}", source);
+        }
+
+        // Bytes above 0x7F can not be written into a grammar
+        for source in [
+            "a = %xE9\n",
+            "a = %x41.FF\n",
+            "a = %x00-FF\n",
+            "a = OCTET\n",
+        ] {
+            let err = parse(source).unwrap_err();
+            assert!(err.message.contains("can not express"), "{}", source);
+        }
+
+        // Ranges beyond 0xFF are code points
+        let grammar = parse("a = %x80-10FFFF / %x100\n").unwrap();
+        assert!(grammar
+            .0
+            .values()
+            .flatten()
+            .flatten()
+            .any(|value| value == "\u{100}"));
+    }
+}
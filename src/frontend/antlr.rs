@@ -0,0 +1,688 @@
+//! Parser for a subset of ANTLR4 grammars (`.g4`).
+//!
+//! Lexer and parser rules of combined, lexer and parser grammars are
+//! supported, including `fragment` rules, labels, string ranges (`'a'..'z'`),
+//! character sets, negation (`~`) and the wildcard `.`. Actions, predicates,
+//! rule arguments, options and lexer modes are ignored. Negated sets and the
+//! wildcard are resolved against printable ASCII plus tab, newline and
+//! carriage return, plus a few multi-byte characters.
+//!
+//! ANTLR drops tokens routed to `skip` or a hidden channel, typically
+//! whitespace. If the grammar contains such a lexer rule, the elements of
+//! parser rules are separated by a single space in the generated output.
+//! The first parser rule becomes the start rule.
+
+use std::collections::BTreeSet;
+
+use super::{Expr, Lowering, ParseError};
+use crate::Grammar;
+
+/// Parse an ANTLR4 grammar into a `Grammar`
+pub fn parse(source: &str) -> Result<Grammar, ParseError> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        rules: Vec::new(),
+    };
+    parser.grammar()?;
+
+    let defined: BTreeSet<&str> = parser.rules.iter().map(|r| r.name.as_str()).collect();
+    for rule in &parser.rules {
+        let mut refs = BTreeSet::new();
+        collect_references(&rule.expr, &mut refs);
+        if let Some(undefined) = refs.iter().find(|r| !defined.contains(r.as_str())) {
+            return Err(ParseError {
+                line: rule.line,
+                message: format!("reference to undefined rule {:?}", undefined),
+            });
+        }
+    }
+
+    let separated = parser.rules.iter().any(|rule| rule.hidden);
+    let mut lowering = Lowering::default();
+    for rule in &parser.rules {
+        let separator = if separated && !is_lexer_rule(&rule.name) {
+            Some(" ")
+        } else {
+            None
+        };
+        lowering.add_rule(&rule.name, &rule.expr, separator);
+    }
+
+    let start = parser
+        .rules
+        .iter()
+        .find(|rule| !is_lexer_rule(&rule.name))
+        .or_else(|| parser.rules.first())
+        .map(|rule| rule.name.as_str());
+
+    Ok(lowering.finish(start))
+}
+
+fn is_lexer_rule(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+}
+
+fn collect_references(expr: &Expr, refs: &mut BTreeSet<String>) {
+    match expr {
+        Expr::Alt(items) | Expr::Seq(items) => {
+            items.iter().for_each(|item| collect_references(item, refs))
+        }
+        Expr::Repeat(inner, _, _) => collect_references(inner, refs),
+        Expr::Rule(name) => {
+            refs.insert(name.clone());
+        }
+        Expr::Literal(_) | Expr::Chars(_) => {}
+    }
+}
+
+/// The characters that negated sets and the wildcard choose from
+fn universe() -> impl Iterator<Item = char> {
+    const EXTRA: [char; 4] = ['\u{e9}', '\u{3bb}', '\u{4e2d}', '\u{1f600}'];
+
+    ['\t', '\n', '\r']
+        .iter()
+        .copied()
+        .chain(' '..='~')
+        .chain(EXTRA.iter().copied())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    /// Rule, token or keyword name
+    Ident(String),
+
+    /// Decoded string literal
+    Str(String),
+
+    /// Raw content of a `[...]` block, either a character set or arguments
+    Bracket(String),
+
+    /// Raw content of a `{...}` block
+    Action(String),
+
+    /// Punctuation
+    Punct(&'static str),
+}
+
+struct Token {
+    tok: Tok,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    const PUNCT: &[&str] = &[
+        "->", "..", "+=", "::", ":", ";", "|", "(", ")", "?", "*", "+", "~", ".", "=", "#", ",",
+        "@",
+    ];
+
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+
+    let error = |line, message: &str| {
+        Err(ParseError {
+            line,
+            message: message.to_string(),
+        })
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let start_line = line;
+
+        if c == '\n' {
+            line += 1;
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if source_starts_with(&chars, i, "//") {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if source_starts_with(&chars, i, "/*") {
+            i += 2;
+            while i < chars.len() && !source_starts_with(&chars, i, "*/") {
+                if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            i += 2;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token {
+                tok: Tok::Ident(chars[start..i].iter().collect()),
+                line,
+            });
+        } else if c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None | Some('\n') => return error(line, "unterminated string literal"),
+                    Some('\'') => break,
+                    Some('\\') => {
+                        let (escaped, len) = unescape(&chars[i..]).ok_or_else(|| ParseError {
+                            line,
+                            message: "invalid escape sequence".to_string(),
+                        })?;
+                        value.push(escaped);
+                        i += len;
+                    }
+                    Some(&c) => {
+                        value.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push(Token {
+                tok: Tok::Str(value),
+                line,
+            });
+        } else if c == '[' {
+            let start = i + 1;
+            i += 1;
+            while i < chars.len() && chars[i] != ']' {
+                if chars[i] == '\\' {
+                    i += 1;
+                } else if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return error(start_line, "unterminated `[`");
+            }
+            tokens.push(Token {
+                tok: Tok::Bracket(chars[start..i].iter().collect()),
+                line: start_line,
+            });
+            i += 1;
+        } else if c == '{' || c == '<' {
+            // Actions and element options, skipped with nesting
+            let (open, close) = if c == '{' { ('{', '}') } else { ('<', '>') };
+            let start = i + 1;
+            let mut nesting = 0;
+            while i < chars.len() {
+                if chars[i] == open {
+                    nesting += 1;
+                } else if chars[i] == close {
+                    nesting -= 1;
+                    if nesting == 0 {
+                        break;
+                    }
+                } else if chars[i] == '\n' {
+                    line += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                return error(start_line, "unterminated block");
+            }
+            if open == '{' {
+                tokens.push(Token {
+                    tok: Tok::Action(chars[start..i].iter().collect()),
+                    line: start_line,
+                });
+            }
+            i += 1;
+        } else if let Some(p) = PUNCT.iter().find(|p| source_starts_with(&chars, i, p)) {
+            tokens.push(Token {
+                tok: Tok::Punct(p),
+                line,
+            });
+            i += p.len();
+        } else {
+            return error(line, &format!("unexpected character {:?}", c));
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn source_starts_with(chars: &[char], at: usize, pattern: &str) -> bool {
+    pattern
+        .chars()
+        .enumerate()
+        .all(|(off, c)| chars.get(at + off) == Some(&c))
+}
+
+/// Decode the escape sequence at the start of `chars`, returning the
+/// character and the number of source characters consumed
+fn unescape(chars: &[char]) -> Option<(char, usize)> {
+    match chars.get(1)? {
+        'n' => Some(('\n', 2)),
+        'r' => Some(('\r', 2)),
+        't' => Some(('\t', 2)),
+        'b' => Some(('\u{8}', 2)),
+        'f' => Some(('\u{c}', 2)),
+        'u' if chars.get(2) == Some(&'{') => {
+            let end = chars.iter().position(|&c| c == '}')?;
+            let hex: String = chars[3..end].iter().collect();
+            Some((
+                char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?,
+                end + 1,
+            ))
+        }
+        'u' => {
+            let hex: String = chars.get(2..6)?.iter().collect();
+            Some((char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?, 6))
+        }
+        &c => Some((c, 2)),
+    }
+}
+
+/// Decode the content of a `[...]` character set, or describe why it is
+/// invalid
+fn char_set(raw: &str) -> Result<Vec<char>, &'static str> {
+    let chars: Vec<char> = raw.chars().collect();
+    let mut set = Vec::new();
+    let mut i = 0;
+
+    let next = |i: &mut usize| -> Option<char> {
+        if chars[*i] == '\\' {
+            let (c, len) = unescape(&chars[*i..])?;
+            *i += len;
+            Some(c)
+        } else {
+            *i += 1;
+            Some(chars[*i - 1])
+        }
+    };
+
+    while i < chars.len() {
+        let lo = next(&mut i).ok_or("invalid character set")?;
+        if i + 1 < chars.len() && chars[i] == '-' {
+            i += 1;
+            let hi = next(&mut i).ok_or("invalid character set")?;
+            if lo > hi {
+                return Err("invalid character range");
+            }
+            if let Expr::Chars(range) = Expr::char_range(lo as u32, hi as u32) {
+                set.extend(range);
+            }
+        } else {
+            set.push(lo);
+        }
+    }
+
+    Ok(set)
+}
+
+struct Rule {
+    name: String,
+    expr: Expr,
+    line: usize,
+
+    /// Lexer rule whose tokens are skipped or sent to a hidden channel
+    hidden: bool,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    rules: Vec<Rule>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|t| &t.tok)
+    }
+
+    fn peek_at(&self, off: usize) -> Option<&Tok> {
+        self.tokens.get(self.pos + off).map(|t| &t.tok)
+    }
+
+    fn line(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .or_else(|| self.tokens.last())
+            .map_or(0, |t| t.line)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError {
+            line: self.line(),
+            message: message.into(),
+        })
+    }
+
+    fn bump(&mut self) -> Option<Tok> {
+        let tok = self.tokens.get(self.pos).map(|t| t.tok.clone());
+        self.pos += 1;
+        tok
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Some(Tok::Punct(p)) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ParseError> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            self.error(format!("expected `{}`", punct))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, ParseError> {
+        match self.bump() {
+            Some(Tok::Ident(name)) => Ok(name),
+            _ => {
+                self.pos -= 1;
+                self.error("expected identifier")
+            }
+        }
+    }
+
+    /// Skip to the token after the next `;`
+    fn skip_statement(&mut self) {
+        while let Some(tok) = self.bump() {
+            if tok == Tok::Punct(";") {
+                break;
+            }
+        }
+    }
+
+    fn grammar(&mut self) -> Result<(), ParseError> {
+        while let Some(tok) = self.peek().cloned() {
+            match tok {
+                Tok::Ident(kw) if kw == "lexer" || kw == "parser" || kw == "grammar" => {
+                    self.skip_statement();
+                }
+                Tok::Ident(kw) if kw == "import" || kw == "mode" => self.skip_statement(),
+                Tok::Ident(kw)
+                    if (kw == "options" || kw == "channels")
+                        && matches!(self.peek_at(1), Some(Tok::Action(_))) =>
+                {
+                    self.pos += 2;
+                }
+                Tok::Ident(kw)
+                    if kw == "tokens" && matches!(self.peek_at(1), Some(Tok::Action(_))) =>
+                {
+                    // Tokens without a lexer rule expand to their name
+                    let line = self.line();
+                    if let Some(Tok::Action(names)) = self.peek_at(1).cloned() {
+                        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                            self.rules.push(Rule {
+                                name: name.to_string(),
+                                expr: Expr::Literal(name.to_string()),
+                                line,
+                                hidden: false,
+                            });
+                        }
+                    }
+                    self.pos += 2;
+                }
+                Tok::Punct("@") => {
+                    // Named actions like `@header {...}` or `@lexer::members {...}`
+                    while !matches!(self.bump(), Some(Tok::Action(_)) | None) {}
+                }
+                Tok::Ident(_) => self.rule()?,
+                _ => return self.error("expected rule"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rule(&mut self) -> Result<(), ParseError> {
+        let line = self.line();
+        if matches!(self.peek(), Some(Tok::Ident(kw)) if kw == "fragment") {
+            self.pos += 1;
+        }
+        let name = self.ident()?;
+
+        // Skip arguments, return values, locals, options and rule actions
+        while !self.eat(":") {
+            match self.bump() {
+                Some(Tok::Punct(";")) | None => {
+                    return self.error(format!("expected `:` in rule {:?}", name));
+                }
+                _ => {}
+            }
+        }
+
+        let mut hidden = false;
+        let expr = self.alternatives(&mut hidden)?;
+        self.expect(";")?;
+
+        // Skip exception handlers
+        while matches!(self.peek(), Some(Tok::Ident(kw)) if kw == "catch" || kw == "finally") {
+            while !matches!(self.bump(), Some(Tok::Action(_)) | None) {}
+        }
+
+        if let Some(existing) = self.rules.iter_mut().find(|r| r.name == name) {
+            // A lexer rule for a token from a `tokens` block
+            existing.expr = expr;
+            existing.hidden = hidden;
+        } else {
+            self.rules.push(Rule {
+                name,
+                expr,
+                line,
+                hidden,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn alternatives(&mut self, hidden: &mut bool) -> Result<Expr, ParseError> {
+        let mut alts = vec![self.alternative(hidden)?];
+        while self.eat("|") {
+            alts.push(self.alternative(hidden)?);
+        }
+
+        Ok(if alts.len() == 1 {
+            alts.pop().unwrap()
+        } else {
+            Expr::Alt(alts)
+        })
+    }
+
+    fn alternative(&mut self, hidden: &mut bool) -> Result<Expr, ParseError> {
+        let mut items = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Tok::Punct("|")) | Some(Tok::Punct(";")) | Some(Tok::Punct(")")) | None => {
+                    break
+                }
+                Some(Tok::Punct("#")) => {
+                    // Alternative label
+                    self.pos += 1;
+                    self.ident()?;
+                }
+                Some(Tok::Punct("->")) => {
+                    // Lexer commands
+                    self.pos += 1;
+                    loop {
+                        let command = self.ident()?;
+                        if command == "skip" || command == "channel" {
+                            *hidden = true;
+                        }
+                        if self.eat("(") {
+                            while !self.eat(")") {
+                                if self.bump().is_none() {
+                                    return self.error("expected `)`");
+                                }
+                            }
+                        }
+                        if !self.eat(",") {
+                            break;
+                        }
+                    }
+                }
+                Some(Tok::Action(_)) => {
+                    // Actions and semantic predicates
+                    self.pos += 1;
+                    self.eat("?");
+                }
+                _ => items.push(self.element()?),
+            }
+        }
+
+        Ok(if items.len() == 1 {
+            items.pop().unwrap()
+        } else {
+            Expr::Seq(items)
+        })
+    }
+
+    fn element(&mut self) -> Result<Expr, ParseError> {
+        // Skip element labels like `x=expr` and `xs+=expr`
+        if matches!(self.peek(), Some(Tok::Ident(_)))
+            && matches!(
+                self.peek_at(1),
+                Some(Tok::Punct("=")) | Some(Tok::Punct("+="))
+            )
+        {
+            self.pos += 2;
+        }
+
+        let atom = self.atom()?;
+
+        let (min, max) = if self.eat("?") {
+            (0, Some(1))
+        } else if self.eat("*") {
+            (0, None)
+        } else if self.eat("+") {
+            (1, None)
+        } else {
+            return Ok(atom);
+        };
+        // Non-greedy marker
+        self.eat("?");
+
+        Ok(Expr::Repeat(Box::new(atom), min, max))
+    }
+
+    fn atom(&mut self) -> Result<Expr, ParseError> {
+        match self.bump() {
+            Some(Tok::Ident(name)) if name == "EOF" => Ok(Expr::Literal(String::new())),
+            Some(Tok::Ident(name)) => Ok(Expr::Rule(name)),
+            Some(Tok::Str(lo)) => {
+                if !self.eat("..") {
+                    return Ok(Expr::Literal(lo));
+                }
+                match self.bump() {
+                    Some(Tok::Str(hi)) if lo.chars().count() == 1 && hi.chars().count() == 1 => {
+                        let (lo, hi) = (lo.chars().next().unwrap(), hi.chars().next().unwrap());
+                        if lo > hi {
+                            return self.error("invalid character range");
+                        }
+                        Ok(Expr::char_range(lo as u32, hi as u32))
+                    }
+                    _ => self.error("invalid character range"),
+                }
+            }
+            Some(Tok::Bracket(raw)) => match char_set(&raw) {
+                Ok(set) => Ok(Expr::Chars(set)),
+                Err(message) => self.error(message),
+            },
+            Some(Tok::Punct(".")) => Ok(Expr::Chars(universe().collect())),
+            Some(Tok::Punct("~")) => {
+                let negated = self.atom()?;
+                let mut excluded = BTreeSet::new();
+                if !excluded_chars(&negated, &mut excluded) {
+                    return self.error("only sets of single characters can be negated");
+                }
+                Ok(Expr::Chars(
+                    universe().filter(|c| !excluded.contains(c)).collect(),
+                ))
+            }
+            Some(Tok::Punct("(")) => {
+                // Rule level options of a subrule: `( options {...} : ... )`
+                if matches!(self.peek(), Some(Tok::Ident(kw)) if kw == "options") {
+                    self.pos += 2;
+                    self.expect(":")?;
+                }
+                let inner = self.alternatives(&mut false)?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            _ => {
+                self.pos -= 1;
+                self.error("expected element")
+            }
+        }
+    }
+}
+
+/// Collect the characters of a negated set, returning `false` if the
+/// expression is not a set of single characters
+fn excluded_chars(expr: &Expr, out: &mut BTreeSet<char>) -> bool {
+    match expr {
+        Expr::Chars(chars) => {
+            out.extend(chars.iter().copied());
+            true
+        }
+        Expr::Literal(s) if s.chars().count() == 1 => {
+            out.extend(s.chars());
+            true
+        }
+        Expr::Alt(alts) => alts.iter().all(|alt| excluded_chars(alt, out)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combined_grammar() {
+        let grammar = parse(
+            r#"
+grammar Expr;
+options { language = Java; }
+
+expr : term (('+' | '-') term)* # Sum
+     ;
+term : NUM | '(' expr ')' ;
+
+NUM : [0-9]+ ;
+fragment LETTER : ~[\u0000-\u007F] ;
+WS : [ \t\r\n]+ -> skip ;
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(grammar.0["<start>"], vec![vec!["<expr>"]]);
+        assert_eq!(grammar.0["<expr>"], vec![vec!["<term>", " ", "<expr.1>*"]]);
+        assert_eq!(
+            grammar.0["<expr.1>"],
+            vec![vec![" ", "<expr.2>", " ", "<term>"]]
+        );
+        assert_eq!(grammar.0["<expr.2>"], vec![vec!["+"], vec!["-"]]);
+        assert_eq!(
+            grammar.0["<term>"],
+            vec![vec!["<NUM>"], vec!["(", " ", "<expr>", " ", ")"]]
+        );
+        assert_eq!(grammar.0["<NUM>"], vec![vec!["<NUM.1>+"]]);
+        assert_eq!(grammar.0["<NUM.1>"].len(), 10);
+        assert_eq!(grammar.0["<LETTER>"].len(), 4);
+        assert_eq!(grammar.0["<WS>"], vec![vec!["<WS.1>+"]]);
+    }
+
+    #[test]
+    fn reversed_ranges() {
+        for source in ["grammar A; a : [z-a] ;", "grammar A; a : 'z'..'a' ;"] {
+            let err = parse(source).unwrap_err();
+            assert_eq!(err.message, "invalid character range");
+        }
+        assert!(parse("grammar A; a : [a-z] 'a'..'z' ;").is_ok());
+    }
+}
@@ -0,0 +1,133 @@
+//! An interpreter for `GrammarRust` that generates inputs without compiling
+//! the generated code first.
+//!
+//! The interpreter makes exactly the same calls to the random number
+//! generator as the code emitted by `GrammarRust::program`, so for the same
+//! grammar, `max_depth` and RNG state both produce the same output. This
+//! allows tooling to replay `BufRng` byte-seeds of a generated fuzzer.
+
+use rand::Rng;
+
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// Receives the events of an expansion. Collecting the output is one kind of
+/// visitor, tooling can also look at how inputs are generated without
+/// walking the grammar a second way.
+pub(crate) trait Visitor {
+    /// Fragment `id` is about to be expanded at `depth`, also if the depth
+    /// limit cuts it off
+    fn enter(&mut self, _id: FragmentId, _depth: usize) {}
+
+    /// Terminal `id` appends `value` to the input
+    fn emit(&mut self, id: FragmentId, value: &[u8]);
+}
+
+impl Visitor for Vec<u8> {
+    fn emit(&mut self, _id: FragmentId, value: &[u8]) {
+        self.extend_from_slice(value);
+    }
+}
+
+impl GrammarRust {
+    /// Generate an input from the start node into `out`, which is cleared
+    /// first
+    pub fn generate_into(&self, out: &mut Vec<u8>, max_depth: usize, rng: &mut impl Rng) {
+        self.generate_complete(out, max_depth, rng);
+    }
+
+    /// Generate a new input from the start node
+    pub fn generate_new(&self, max_depth: usize, rng: &mut impl Rng) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.generate_into(&mut out, max_depth, rng);
+        out
+    }
+
+    /// Generate an input from the fragment `root` (see `fragment_id`) into
+    /// `out`, which is cleared first
+    pub fn generate_rule_into(
+        &self,
+        root: FragmentId,
+        out: &mut Vec<u8>,
+        max_depth: usize,
+        rng: &mut impl Rng,
+    ) {
+        out.clear();
+        self.expand(root, 0, max_depth, out, rng);
+    }
+
+    /// Generate an input from the start node into `out` like
+    /// `generate_into`, returning `false` if the input was cut short because
+    /// `max_depth` was reached
+    pub(crate) fn generate_complete(
+        &self,
+        out: &mut Vec<u8>,
+        max_depth: usize,
+        rng: &mut impl Rng,
+    ) -> bool {
+        out.clear();
+        self.expand(self.start.unwrap(), 0, max_depth, out, rng)
+    }
+
+    /// Expand the fragment `root` like `generate_rule_into`, handing every
+    /// event to `visitor` instead of collecting the output. Returns `false`
+    /// if the input was cut short because `max_depth` was reached.
+    pub(crate) fn visit(
+        &self,
+        root: FragmentId,
+        max_depth: usize,
+        visitor: &mut impl Visitor,
+        rng: &mut impl Rng,
+    ) -> bool {
+        self.expand(root, 0, max_depth, visitor, rng)
+    }
+
+    /// Expand a fragment, returning `false` if any part of it was not
+    /// expanded because of the depth limit
+    fn expand(
+        &self,
+        id: FragmentId,
+        depth: usize,
+        max_depth: usize,
+        visitor: &mut impl Visitor,
+        rng: &mut impl Rng,
+    ) -> bool {
+        visitor.enter(id, depth);
+        if depth >= max_depth {
+            return false;
+        }
+
+        match &self.fragments[id.0] {
+            Fragment::NonTerminal(options) => {
+                // The generated code matches on an `i32` range, which
+                // determines how many bytes of randomness are consumed
+                let option = rng.gen_range(0..options.len() as i32);
+                self.expand(options[option as usize], depth + 1, max_depth, visitor, rng)
+            }
+            Fragment::Expression(expr) => {
+                let mut complete = true;
+                for &exp in expr.iter() {
+                    complete &= self.expand(exp, depth + 1, max_depth, visitor, rng);
+                }
+                complete
+            }
+            &Fragment::Repeat { fragment, min, max } => {
+                let count = if min == max {
+                    min as u32
+                } else {
+                    rng.gen_range(min as u32..=max as u32)
+                };
+                let mut complete = true;
+                for _ in 0..count {
+                    complete &= self.expand(fragment, depth + 1, max_depth, visitor, rng);
+                }
+                complete
+            }
+            Fragment::Terminal(value) => {
+                visitor.emit(id, value);
+                true
+            }
+            Fragment::Nop => true,
+            Fragment::Unreachable => unreachable!("unreachable fragment reached"),
+        }
+    }
+}
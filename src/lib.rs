@@ -1,22 +1,307 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
+pub mod build;
 mod builtins;
+pub mod coverage;
+pub mod diff;
+pub mod drive;
+pub mod enumerate;
+pub mod export;
+pub mod frontend;
+mod interpreter;
+pub mod optimize;
+mod parse;
+pub mod reduce;
+pub mod sample;
+pub mod stats;
+
+use optimize::PassManager;
+pub use optimize::{OptLevel, OptimizeStats};
 
 /// Representation of a grammar file in a Rust structure. This allows us to
 /// use Serde to serialize and deserialize the json grammar files
 #[derive(Serialize, Deserialize, Default, Debug)]
-pub struct Grammar(BTreeMap<String, Vec<Vec<String>>>);
+pub struct Grammar(pub(crate) BTreeMap<String, Vec<Vec<String>>>);
+
+impl Grammar {
+    /// Check if the grammar defines a rule, e.g. `<start>`
+    pub fn contains_rule(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+}
+
+/// Upper bound on the number of additional iterations for the unbounded
+/// repetition operators `*`, `+` and `{m,}`
+pub const UNBOUNDED_REPEAT_SPAN: usize = 16;
+
+/// Largest bound of a repetition, the generated code counts iterations in a
+/// `u32`
+pub const MAX_REPEAT: usize = u32::MAX as usize;
+
+/// Terminals of at most this many bytes are emitted inline at the place they
+/// are used instead of getting a function of their own
+pub const INLINE_TERMINAL_LEN: usize = 16;
+
+/// Split a grammar symbol with an EBNF-style repetition suffix into the base
+/// symbol and the repetition bounds.
+///
+/// Supported suffixes are `?`, `*`, `+`, `{n}`, `{m,n}`, `{m,}` and `{,n}`.
+/// Only symbols of the form `<name>` can carry a repetition suffix, such that
+/// literal terminals like `"*"` or `"a?"` are left untouched and `Ok(None)`
+/// is returned for them. Bounds with `m > n` or above `MAX_REPEAT` are an
+/// error.
+pub fn split_repetition(symbol: &str) -> Result<Option<(&str, usize, usize)>, String> {
+    match parse_repetition(symbol) {
+        Some((base, bounds)) => bounds.map(|(min, max)| Some((base, min, max))),
+        None => Ok(None),
+    }
+}
+
+/// The `min` and `max` bounds of a repetition, or why they are invalid
+type Bounds = Result<(usize, usize), String>;
+
+/// The base symbol of a symbol with a repetition suffix, and its bounds.
+/// Whether invalid bounds are an error depends on whether the base is a rule
+/// at all.
+fn parse_repetition(symbol: &str) -> Option<(&str, Bounds)> {
+    if !symbol.starts_with('<') {
+        return None;
+    }
+
+    let (base, bounds) = if let Some(base) = symbol.strip_suffix('?') {
+        (base, Ok((0, 1)))
+    } else if let Some(base) = symbol.strip_suffix('*') {
+        (base, Ok((0, UNBOUNDED_REPEAT_SPAN)))
+    } else if let Some(base) = symbol.strip_suffix('+') {
+        (base, Ok((1, 1 + UNBOUNDED_REPEAT_SPAN)))
+    } else if symbol.ends_with('}') {
+        let open = symbol.rfind('{')?;
+        let (base, bounds) = (&symbol[..open], &symbol[open + 1..symbol.len() - 1]);
+        if !bounds
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == ',' || c == ' ')
+        {
+            return None;
+        }
+
+        let bound = |bound: &str| -> Result<usize, String> {
+            match bound.trim().parse::<usize>() {
+                Ok(bound) if bound <= MAX_REPEAT => Ok(bound),
+                _ => Err(format!(
+                    "repetition bound {:?} of {} is not a number up to {}",
+                    bound.trim(),
+                    symbol,
+                    MAX_REPEAT
+                )),
+            }
+        };
+        let bounds = match bounds.split_once(',') {
+            Some((min, max)) => {
+                let min = if min.trim().is_empty() {
+                    Ok(0)
+                } else {
+                    bound(min)
+                };
+                let max = if max.trim().is_empty() {
+                    min.clone().map(|min| min + UNBOUNDED_REPEAT_SPAN)
+                } else {
+                    bound(max)
+                };
+                min.and_then(|min| Ok((min, max?)))
+            }
+            None => bound(bounds).map(|count| (count, count)),
+        };
+        (base, bounds)
+    } else {
+        return None;
+    };
+
+    if base.len() <= 2 || !base.ends_with('>') {
+        return None;
+    }
+    Some((
+        base,
+        bounds.and_then(|(min, max)| check_bounds(symbol, min, max)),
+    ))
+}
+
+/// Check the bounds of the repetition `symbol`
+fn check_bounds(symbol: &str, min: usize, max: usize) -> Bounds {
+    if min > max {
+        return Err(format!(
+            "repetition {} has a lower bound larger than its upper bound",
+            symbol
+        ));
+    }
+    if max > MAX_REPEAT {
+        return Err(format!(
+            "repetition {} repeats more than {} times",
+            symbol, MAX_REPEAT
+        ));
+    }
+    Ok((min, max))
+}
+
+/// Names that can not be used for the functions of the generated code
+const RESERVED_LABELS: &[&str] = &[
+    "as",
+    "async",
+    "await",
+    "break",
+    "const",
+    "continue",
+    "crate",
+    "dyn",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "for",
+    "if",
+    "impl",
+    "in",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "move",
+    "mut",
+    "pub",
+    "ref",
+    "return",
+    "self",
+    "static",
+    "struct",
+    "super",
+    "trait",
+    "true",
+    "type",
+    "unsafe",
+    "use",
+    "where",
+    "while",
+    "abstract",
+    "become",
+    "box",
+    "do",
+    "final",
+    "macro",
+    "override",
+    "priv",
+    "try",
+    "typeof",
+    "unsized",
+    "virtual",
+    "yield",
+    "generate_into",
+    "generate_new",
+    "generate_rule_into",
+    "generate_rule_new",
+    "terminals",
+];
+
+/// Turn a rule name like `<http-version>` or `<!numbers.digit>` into an
+/// identifier usable as a function name in the generated code
+fn rule_label(name: &str) -> String {
+    let name = name.trim_start_matches('<').trim_end_matches('>');
+    let mut label: String = name
+        .trim_start_matches('!')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if label.is_empty() || label.starts_with(|c: char| c.is_ascii_digit()) {
+        label.insert_str(0, "r_");
+    }
+    if RESERVED_LABELS.contains(&label.as_str()) {
+        label.push('_');
+    }
+
+    label
+}
+
+/// Turn a rule name into a variant name of the generated `Rule` enum, e.g.
+/// `<http-version>` into `HttpVersion`
+fn rule_variant(name: &str) -> String {
+    let mut variant: String = rule_label(name)
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect();
+
+    if variant == "Self" {
+        variant.push('_');
+    }
+
+    variant
+}
+
+/// The place in the grammar a fragment was created for
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Origin {
+    /// Name of the rule, e.g. `<http-version>`. This is empty for fragments
+    /// that were not created from a grammar.
+    pub rule: String,
+
+    /// Index of the alternative of the rule
+    pub alternative: Option<usize>,
+
+    /// Index of the symbol in the alternative
+    pub symbol: Option<usize>,
+}
+
+impl Origin {
+    fn new(rule: &str, alternative: Option<usize>, symbol: Option<usize>) -> Self {
+        Self {
+            rule: rule.to_string(),
+            alternative,
+            symbol,
+        }
+    }
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.rule)?;
+        if let Some(alternative) = self.alternative {
+            write!(f, " alternative {}", alternative)?;
+        }
+        if let Some(symbol) = self.symbol {
+            write!(f, " symbol {}", symbol)?;
+        }
+        Ok(())
+    }
+}
 
 /// A strongly typed wrapper around a `usize` which selects different fragment
 /// identifiers
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FragmentId(usize);
 
+impl FragmentId {
+    /// Index of the fragment in the fragment list
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
 /// A fragment which is specified by the grammar file
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Fragment {
     /// A non-terminal fragment which refers to a list of `FragmentId`s to
     /// randomly select from for expansion
@@ -29,6 +314,15 @@ pub enum Fragment {
     /// contained vector of bytes
     Terminal(Vec<u8>),
 
+    /// A fragment that is expanded a random number of times in the range
+    /// `min..=max`. All iterations are expanded at the same depth, so long
+    /// repetitions do not run into the `max_depth` limit.
+    Repeat {
+        fragment: FragmentId,
+        min: usize,
+        max: usize,
+    },
+
     /// A fragment which does nothing. This is used during optimization passes
     /// to remove fragments with no effect.
     Nop,
@@ -37,9 +331,55 @@ pub enum Fragment {
     Unreachable,
 }
 
+impl Fragment {
+    /// The fragments this fragment expands to
+    pub(crate) fn children(&self) -> &[FragmentId] {
+        match self {
+            Fragment::NonTerminal(options) => options,
+            Fragment::Expression(expr) => expr,
+            Fragment::Repeat { fragment, .. } => std::slice::from_ref(fragment),
+            Fragment::Terminal(_) | Fragment::Nop | Fragment::Unreachable => &[],
+        }
+    }
+
+    /// Mutable access to the fragments this fragment expands to
+    pub(crate) fn children_mut(&mut self) -> &mut [FragmentId] {
+        match self {
+            Fragment::NonTerminal(options) => options,
+            Fragment::Expression(expr) => expr,
+            Fragment::Repeat { fragment, .. } => std::slice::from_mut(fragment),
+            Fragment::Terminal(_) | Fragment::Nop | Fragment::Unreachable => &mut [],
+        }
+    }
+}
+
+/// The shape of the code generated by `GrammarRust::program`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// One function per fragment, which is the fastest for most grammars
+    #[default]
+    Functions,
+
+    /// Static tables of operations for all fragments and a single loop that
+    /// interprets them. This compiles much faster and to a smaller binary
+    /// for huge grammars.
+    Table,
+}
+
+impl Backend {
+    /// Parse a backend as given with `--backend` on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "functions" => Some(Backend::Functions),
+            "table" => Some(Backend::Table),
+            _ => None,
+        }
+    }
+}
+
 /// A grammar representation in Rust that is designed to be easy to work with
 /// in-memory and optimized for code generation.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct GrammarRust {
     /// All types
     fragments: Vec<Fragment>,
@@ -50,31 +390,96 @@ pub struct GrammarRust {
     /// Mapping of non-terminal names to fragment identifers
     name_to_fragment: BTreeMap<String, FragmentId>,
 
+    /// Stable name for every fragment, derived from the rule, alternative
+    /// and position it was created for. These are used as function names in
+    /// the generated code, so editing one rule does not rename the functions
+    /// generated for all others.
+    labels: Vec<String>,
+
+    /// The rule, alternative and symbol every fragment was created for. This
+    /// is kept through optimization, so the generated code can be traced
+    /// back to the grammar.
+    origins: Vec<Origin>,
+
+    /// Names of rules that can be generated on their own, in addition to the
+    /// start node. These are kept during optimization.
+    roots: BTreeSet<String>,
+
+    /// Fragments that were replaced by another fragment during optimization,
+    /// used to translate their ids when the fragment list is compacted
+    redirects: BTreeMap<usize, FragmentId>,
+
     /// If this is `true` then the output file we generate will not emit any
     /// unsafe code. I'm not aware of any bugs with the unsafe code that I use and
     /// thus this is by default set to `false`. Feel free to set it to `true` if
     /// you are concerned.
     pub safe_only: bool,
+
+    /// If this is `true` the generated code contains a `RULE_NAMES` table
+    /// with the grammar name of every variant of the `Rule` enum, which is
+    /// also available through `Rule::name`
+    pub rule_names: bool,
+
+    /// If this is `true` the generated code counts how often every
+    /// alternative of every rule is chosen, see `coverage`
+    pub coverage: bool,
+
+    /// If this is `true` the generated code can also generate adaptively,
+    /// favouring alternatives that were chosen less often or led to new
+    /// behaviour of the target, see `GrammarGenerator::generate_adaptive_into`
+    pub adaptive: bool,
+
+    /// If this is `Some(max_size)` the generated code can also draw inputs
+    /// uniformly among the derivations of a size up to `max_size`, see
+    /// `sample::SizedSampler` and `GrammarGenerator::generate_sized_into`
+    pub sized_sampling: Option<usize>,
+
+    /// If this is `true` the generated code can also write inputs straight
+    /// to an `io::Write` in chunks, without holding a whole input in memory,
+    /// see `GrammarGenerator::generate_to_writer`
+    pub streaming: bool,
+
+    /// Shape of the generated code
+    pub backend: Backend,
 }
 
 impl GrammarRust {
     /// Create a new Rust version of a `Grammar` which was loaded via a
     /// grammar json specification.
+    ///
+    /// Panics if the grammar is invalid, see `try_new`.
     pub fn new(grammar: &Grammar, start_fragment: Option<&str>) -> Self {
+        Self::try_new(grammar, start_fragment).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Create a new Rust version of a `Grammar` like `new`, returning an
+    /// error if the start rule is missing or a repetition of a rule has
+    /// invalid bounds
+    pub fn try_new(grammar: &Grammar, start_fragment: Option<&str>) -> Result<Self, String> {
         let start_fragment = start_fragment.unwrap_or("<start>");
 
-        let mut ret = Self::construct(grammar);
+        let mut ret = Self::construct(grammar)?;
 
         // Resolve the start node
-        ret.start = Some(ret.name_to_fragment[start_fragment]);
+        match ret.name_to_fragment.get(start_fragment) {
+            Some(&start) => ret.start = Some(start),
+            None => return Err(format!("the grammar has no rule {}", start_fragment)),
+        }
 
-        ret
+        Ok(ret)
     }
 
-    fn construct(grammar: &Grammar) -> Self {
+    fn construct(grammar: &Grammar) -> Result<Self, String> {
         // Create a new grammar structure
-        let mut ret = GrammarRust::default();
-        ret.safe_only = false;
+        let mut ret = GrammarRust {
+            safe_only: false,
+            rule_names: false,
+            coverage: false,
+            adaptive: false,
+            sized_sampling: None,
+            streaming: false,
+            ..Default::default()
+        };
 
         // Parse the input grammar to resolve all fragment names
         for (non_term, _) in grammar.0.iter() {
@@ -85,44 +490,74 @@ impl GrammarRust {
             );
 
             // Create a new, empty fragment
-            let fragment_id = ret.allocate_fragment(Fragment::NonTerminal(Vec::new()));
+            let fragment_id = ret.allocate_labeled(
+                Fragment::NonTerminal(Vec::new()),
+                rule_label(non_term),
+                Origin::new(non_term, None, None),
+            );
 
             // Add the name resolution for the fragment
             ret.name_to_fragment.insert(non_term.clone(), fragment_id);
         }
 
+        // References to builtins, which are resolved after all rules of the
+        // grammar have been allocated. This way the fragment identifiers of
+        // the grammar do not depend on which builtins are used.
+        let mut builtin_refs = Vec::new();
+
         // Parse the input grammar
         for (non_term, fragments) in grammar.0.iter() {
             // Get the non-terminal fragment identifier
             let fragment_id = ret.name_to_fragment[non_term];
+            let rule = ret.labels[fragment_id.0].clone();
 
             // Create a vector to hold all of the variants possible under this
             // non-terminal fragment
             let mut variants = Vec::new();
 
             // Go through all sub-fragments
-            for js_sub_fragment in fragments {
+            for (alt, js_sub_fragment) in fragments.iter().enumerate() {
                 // Different options for this sub-fragment
                 let mut options = Vec::new();
 
                 // Go through each option in the sub-fragment
-                for option in js_sub_fragment {
+                for (idx, option) in js_sub_fragment.iter().enumerate() {
+                    let label = format!("{}_alt{}_{}", rule, alt, idx);
+                    let origin = Origin::new(non_term, Some(alt), Some(idx));
+
                     let fragment_id = if let Some(&non_terminal) = ret.name_to_fragment.get(option)
                     {
                         // If we can resolve the name of this fragment, it is a
                         // non-terminal fragment and should be allocated as
                         // such
-                        ret.allocate_fragment(Fragment::NonTerminal(vec![non_terminal]))
-                    } else if let Some(id) = builtins::load_if_builtin(option, &mut ret) {
+                        ret.allocate_labeled(
+                            Fragment::NonTerminal(vec![non_terminal]),
+                            label,
+                            origin,
+                        )
+                    } else if builtins::builtin_module(option).is_some() {
+                        // Placeholder that refers to the builtin once it is
+                        // imported
+                        let id =
+                            ret.allocate_labeled(Fragment::NonTerminal(Vec::new()), label, origin);
+                        builtin_refs.push((id, option.clone()));
+                        id
+                    } else if let Some(id) =
+                        ret.resolve_repetition(option, &label, &origin, &mut builtin_refs)?
+                    {
                         id
                     } else {
-                        if option.starts_with("<") && option.ends_with(">") {
+                        if option.starts_with('<') && option.ends_with('>') {
                             log::warn!("using a string that looks like a rule identifier ({:?}) as byte literal; check whether your grammar is correct!", option);
                         }
 
                         // Convert the terminal bytes into a vector and
                         // create a new fragment containing it
-                        ret.allocate_fragment(Fragment::Terminal(option.as_bytes().to_vec()))
+                        ret.allocate_labeled(
+                            Fragment::Terminal(option.as_bytes().to_vec()),
+                            label,
+                            origin,
+                        )
                     };
 
                     // Push this fragment as an option
@@ -130,7 +565,11 @@ impl GrammarRust {
                 }
 
                 // Create a new fragment of all the options
-                variants.push(ret.allocate_fragment(Fragment::Expression(options)));
+                variants.push(ret.allocate_labeled(
+                    Fragment::Expression(options),
+                    format!("{}_alt{}", rule, alt),
+                    Origin::new(non_term, Some(alt), None),
+                ));
             }
 
             // Get access to the fragment we want to update based on the
@@ -141,122 +580,685 @@ impl GrammarRust {
             *fragment = Fragment::NonTerminal(variants);
         }
 
-        ret
+        // Import the used builtin modules in a fixed order and point the
+        // placeholders at the builtin rules
+        let modules: BTreeSet<&str> = builtin_refs
+            .iter()
+            .filter_map(|(_, name)| builtins::builtin_module(name))
+            .collect();
+        for module in modules {
+            builtins::import(&mut ret, module);
+        }
+        for (id, name) in builtin_refs {
+            let target = match ret.name_to_fragment.get(&name) {
+                Some(&target) => target,
+                None => panic!("attempted to call invalid builtin {}", name),
+            };
+            ret.fragments[id.0] = Fragment::NonTerminal(vec![target]);
+        }
+
+        ret.deduplicate_labels();
+
+        Ok(ret)
+    }
+
+    /// Lower a symbol with a repetition suffix (e.g. `<digit>{1,10}`) to a
+    /// `Fragment::Repeat` of the non-terminal or builtin it refers to.
+    /// Symbols whose base is neither are terminals, whatever their suffix,
+    /// and `None` is returned for them.
+    fn resolve_repetition(
+        &mut self,
+        option: &str,
+        label: &str,
+        origin: &Origin,
+        builtin_refs: &mut Vec<(FragmentId, String)>,
+    ) -> Result<Option<FragmentId>, String> {
+        let (base, bounds) = match parse_repetition(option) {
+            Some(repetition) => repetition,
+            None => return Ok(None),
+        };
+        if !self.name_to_fragment.contains_key(base) && builtins::builtin_module(base).is_none() {
+            return Ok(None);
+        }
+        let (min, max) =
+            bounds.map_err(|err| format!("invalid repetition in rule {}: {}", origin.rule, err))?;
+
+        let fragment = if let Some(&non_terminal) = self.name_to_fragment.get(base) {
+            non_terminal
+        } else {
+            let id = self.allocate_labeled(
+                Fragment::NonTerminal(Vec::new()),
+                format!("{}_ref", label),
+                origin.clone(),
+            );
+            builtin_refs.push((id, base.to_string()));
+            id
+        };
+
+        Ok(Some(self.allocate_labeled(
+            Fragment::Repeat { fragment, min, max },
+            label.to_string(),
+            origin.clone(),
+        )))
     }
 
     /// Allocate a new fragment identifier and add it to the fragment list
     pub fn allocate_fragment(&mut self, fragment: Fragment) -> FragmentId {
+        let label = format!("fragment_{}", self.fragments.len());
+        self.allocate_labeled(fragment, label, Origin::default())
+    }
+
+    /// Allocate a new fragment with the given label and origin
+    pub(crate) fn allocate_labeled(
+        &mut self,
+        fragment: Fragment,
+        label: String,
+        origin: Origin,
+    ) -> FragmentId {
         // Get a unique fragment identifier
         let fragment_id = FragmentId(self.fragments.len());
 
         // Store the fragment
         self.fragments.push(fragment);
+        self.labels.push(label);
+        self.origins.push(origin);
 
         fragment_id
     }
 
-    /// Optimize to remove fragments with non-random effects
-    pub fn optimize(&mut self) {
-        // Keeps track of fragment identifiers which resolve to nops
-        let mut nop_fragments = BTreeSet::new();
-
-        // Track if a optimization had an effect
-        let mut changed = true;
-        while changed {
-            // Start off assuming no effect from optimzation
-            changed = false;
-
-            // Go through each fragment, looking for potential optimizations
-            for idx in 0..self.fragments.len() {
-                // Clone the fragment such that we can inspect it, but we also
-                // can mutate it in place.
-                match self.fragments[idx].clone() {
-                    Fragment::NonTerminal(options) => {
-                        // If this non-terminal only has one option, replace
-                        // itself with the only option it resolves to
-                        if options.len() == 1 {
-                            self.fragments[idx] = self.fragments[options[0].0].clone();
-                            changed = true;
-                        }
-                    }
-                    Fragment::Expression(expr) => {
-                        // If this expression doesn't have anything to do at
-                        // all. Then simply replace it with a `Nop`
-                        if expr.len() == 0 {
-                            self.fragments[idx] = Fragment::Nop;
-                            changed = true;
-
-                            // Track that this fragment identifier now resolves
-                            // to a nop
-                            nop_fragments.insert(idx);
-                        }
+    /// Make all fragment labels unique by appending a counter to later
+    /// duplicates
+    fn deduplicate_labels(&mut self) {
+        let mut used = HashSet::new();
+        for idx in 0..self.labels.len() {
+            let label = self.labels[idx].clone();
+            let mut unique = label.clone();
+            let mut counter = 1;
+            while !used.insert(unique.clone()) {
+                counter += 1;
+                unique = format!("{}_{}", label, counter);
+            }
+            self.labels[idx] = unique;
+        }
+    }
 
-                        // If this expression only does one thing, then replace
-                        // the expression with the thing that it does.
-                        if expr.len() == 1 {
-                            self.fragments[idx] = self.fragments[expr[0].0].clone();
-                            changed = true;
-                        }
+    /// Number of fragments that code is generated for
+    pub fn fragment_count(&self) -> usize {
+        self.fragments
+            .iter()
+            .filter(|fragment| !matches!(fragment, Fragment::Unreachable))
+            .count()
+    }
 
-                        // Remove all `Nop`s from this expression, as they
-                        // wouldn't result in anything occuring.
-                        if let Fragment::Expression(exprs) = &mut self.fragments[idx] {
-                            // Only retain fragments which are not nops
-                            exprs.retain(|x| {
-                                if nop_fragments.contains(&x.0) {
-                                    // Fragment was a nop, remove it
-                                    changed = true;
-                                    false
-                                } else {
-                                    // Fragment was fine, keep it
-                                    true
-                                }
-                            });
-                        }
-                    }
-                    Fragment::Terminal(_) | Fragment::Nop | Fragment::Unreachable => {
-                        // Already maximally optimized
-                    }
+    /// Optimize to remove fragments with non-random effects, using all
+    /// optimization passes
+    pub fn optimize(&mut self) -> OptimizeStats {
+        self.optimize_level(OptLevel::default())
+    }
+
+    /// Optimize with the passes of the given optimization level
+    pub fn optimize_level(&mut self, level: OptLevel) -> OptimizeStats {
+        let mut stats = PassManager::for_level(level).run(self);
+        if level > OptLevel::None {
+            stats.remap = optimize::compact(self);
+        }
+        stats
+    }
+
+    /// Keep the rule `name` (e.g. `<url>`) during optimization, so it can be
+    /// generated on its own with `generate_rule_into`
+    pub fn add_root(&mut self, name: &str) {
+        assert!(
+            self.name_to_fragment.contains_key(name),
+            "unknown rule {}",
+            name
+        );
+        self.roots.insert(name.to_string());
+    }
+
+    /// The rule, alternative and symbol a fragment was created for
+    pub fn origin(&self, id: FragmentId) -> &Origin {
+        &self.origins[id.0]
+    }
+
+    /// Look up the fragment of a rule by its name, e.g. `<url>`
+    pub fn fragment_id(&self, name: &str) -> Option<FragmentId> {
+        self.name_to_fragment.get(name).copied()
+    }
+
+    /// Generate the source code of the generator without its inner
+    /// attributes, so it can be placed into a module by a macro or with
+    /// `include!`
+    pub fn module_source(&self, max_depth: usize) -> String {
+        self.source(max_depth)
+            .replacen("#![allow(unused)]\n", "", 1)
+    }
+
+    /// Generate a new Rust program that can be built and will generate random
+    /// inputs and benchmark them
+    pub fn program<P: AsRef<Path>>(&self, path: P, max_depth: usize) {
+        // Write out the test application
+        std::fs::write(path, self.source(max_depth))
+            .expect("Failed to create output Rust application");
+    }
+
+    /// The values of the options of a non-terminal whose options are all
+    /// terminals of the same non-zero length. These are generated as a
+    /// lookup into a static table instead of a `match`.
+    fn choice_table(&self, options: &[FragmentId]) -> Option<Vec<&[u8]>> {
+        let values = options
+            .iter()
+            .map(|option| match &self.fragments[option.0] {
+                Fragment::Terminal(value) => Some(&value[..]),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let len = values.first()?.len();
+        if len == 0 || values.iter().any(|value| value.len() != len) {
+            return None;
+        }
+
+        Some(values)
+    }
+
+    /// The value of a fragment that is emitted inline where it is used
+    /// instead of being called
+    fn inline_terminal(&self, id: FragmentId) -> Option<&[u8]> {
+        match &self.fragments[id.0] {
+            Fragment::Terminal(value) if value.len() <= INLINE_TERMINAL_LEN => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Code that expands fragment `id` one level deeper than the current
+    /// function. Tiny terminals are appended directly, with the depth check
+    /// their function would have done.
+    fn expand_code(&self, id: FragmentId) -> String {
+        match self.inline_terminal(id) {
+            Some([byte]) => format!("if depth + 1 < max_depth {{ buf.push({:?}); }}", byte),
+            Some(value) => {
+                let as_str = String::from_utf8_lossy(value);
+                let comment = if as_str.contains('*') {
+                    String::new()
+                } else {
+                    format!("/* {:?} */ ", as_str)
+                };
+                format!(
+                    "if depth + 1 < max_depth {{ {}buf.extend_from_slice(&{:?}); }}",
+                    comment, value
+                )
+            }
+            None => format!(
+                "Self::{}(depth + 1, max_depth, buf, rng);",
+                self.labels[id.0]
+            ),
+        }
+    }
+
+    /// The rule and alternative of every coverage counter, and the index of
+    /// the first counter of every fragment that has counters. Only the
+    /// choices between the alternatives of a rule are counted.
+    pub(crate) fn coverage_points(&self) -> (Vec<(String, usize)>, Vec<Option<usize>>) {
+        let mut points = Vec::new();
+        let mut offsets = vec![None; self.fragments.len()];
+        for (id, fragment) in self.fragments.iter().enumerate() {
+            let origin = &self.origins[id];
+            if let Fragment::NonTerminal(options) = fragment {
+                if !origin.rule.is_empty() && origin.alternative.is_none() {
+                    offsets[id] = Some(points.len());
+                    points.extend((0..options.len()).map(|alt| (origin.rule.clone(), alt)));
                 }
             }
         }
+        (points, offsets)
+    }
 
-        // only keep reachable fragments around
-        let mut new_fragments = Vec::with_capacity(self.fragments.len());
-        // initialize all fragments as Nop fragments
-        new_fragments.resize(self.fragments.len(), Fragment::Unreachable);
-        let mut seen_fragments = BTreeSet::new();
-        let mut worklist = vec![self.start.unwrap()];
+    /// Code that counts the `choice` of a fragment whose first coverage
+    /// counter is at `offset`, if coverage is enabled
+    fn count_code(&self, offset: Option<usize>) -> String {
+        match offset {
+            Some(offset) if self.coverage => format!(
+                "        COVERAGE[{} + choice as usize].fetch_add(1, std::sync::atomic::Ordering::Relaxed);\n",
+                offset
+            ),
+            _ => String::new(),
+        }
+    }
 
-        // iterate over all fragments and only keep the ones that are reachable
-        while !worklist.is_empty() {
-            let idx = worklist.pop().unwrap().0;
-            if seen_fragments.contains(&idx) {
-                continue;
+    /// Start of a call that expands fragment `id` from depth 0, which is
+    /// completed with the `max_depth, out` arguments and `entry_rng`
+    fn entry_call(&self, id: FragmentId) -> String {
+        match self.backend {
+            Backend::Functions => format!("Self::{}(0, ", self.labels[id.0]),
+            Backend::Table => format!("Self::run({}, ", id.0),
+        }
+    }
+
+    /// The random number generator passed from the public functions of the
+    /// generated code. Adaptive code picks alternatives through `Choose`,
+    /// which makes uniform choices for a plain `Rng`.
+    fn entry_rng(&self) -> &'static str {
+        if self.adaptive {
+            "&mut Uniform(rng)"
+        } else {
+            "rng"
+        }
+    }
+
+    /// Trait bound of the random number generator of the generated functions
+    fn rng_bound(&self) -> &'static str {
+        if self.adaptive {
+            "Choose"
+        } else {
+            "Rng"
+        }
+    }
+
+    /// Expression that picks one of `options` alternatives of a fragment
+    /// whose first choice point is at `offset`
+    fn choice_code(&self, offset: Option<usize>, options: usize) -> String {
+        match offset {
+            Some(offset) if self.adaptive => format!("rng.choose({}, {}) as i32", offset, options),
+            _ => format!("rng.gen_range(0..{})", options),
+        }
+    }
+
+    /// The operation tables of the `Table` backend and of sized sampling.
+    /// Fragment `N` is the operation `OPS[N]`.
+    fn op_tables(&self) -> String {
+        let mut ops = String::new();
+        let mut children: Vec<usize> = Vec::new();
+        let mut data: Vec<u8> = Vec::new();
+        let mut data_offsets: HashMap<Vec<u8>, usize> = HashMap::new();
+        let mut add_data = |value: &[u8]| match data_offsets.get(value) {
+            Some(&offset) => offset,
+            None => {
+                data_offsets.insert(value.to_vec(), data.len());
+                data.extend_from_slice(value);
+                data.len() - value.len()
             }
-            new_fragments[idx] = self.fragments[idx].clone();
-            seen_fragments.insert(idx);
-            match &self.fragments[idx] {
+        };
+
+        for (id, fragment) in self.fragments.iter().enumerate() {
+            let op = match fragment {
+                Fragment::NonTerminal(options) if self.choice_table(options).is_some() => {
+                    let values = self.choice_table(options).unwrap();
+                    let offset = add_data(&values.concat());
+                    format!(
+                        "Op::Table({}, {}, {})",
+                        offset,
+                        values.len(),
+                        values[0].len()
+                    )
+                }
                 Fragment::NonTerminal(options) => {
-                    worklist.extend(options.iter().cloned());
+                    children.extend(options.iter().map(|option| option.0));
+                    format!(
+                        "Op::Choose({}, {})",
+                        children.len() - options.len(),
+                        options.len()
+                    )
                 }
                 Fragment::Expression(expr) => {
-                    worklist.extend(expr.iter().cloned());
+                    children.extend(expr.iter().map(|exp| exp.0));
+                    format!(
+                        "Op::Sequence({}, {})",
+                        children.len() - expr.len(),
+                        expr.len()
+                    )
+                }
+                Fragment::Repeat { fragment, min, max } => {
+                    format!("Op::Repeat({}, {}, {})", fragment.0, min, max)
                 }
-                Fragment::Terminal(_) | Fragment::Nop => {
-                    // do nothing
+                Fragment::Terminal(value) => {
+                    format!("Op::Emit({}, {})", add_data(value), value.len())
                 }
+                Fragment::Nop | Fragment::Unreachable => "Op::Nop".to_string(),
+            };
+            ops += &format!("\n    {}, // {}", op, self.labels[id]);
+        }
+
+        format!(
+            r#"
+/// How a fragment is expanded, the values are offsets and lengths into
+/// `CHILDREN` and `DATA`
+#[derive(Clone, Copy)]
+enum Op {{
+    /// Expand one of the children
+    Choose(u32, u32),
+
+    /// Expand all children in order
+    Sequence(u32, u32),
+
+    /// Expand a fragment between `min` and `max` times
+    Repeat(u32, u32, u32),
+
+    /// Append bytes to the output
+    Emit(u32, u32),
+
+    /// Append one of `len` entries of `width` bytes
+    Table(u32, u32, u32),
+
+    /// Do nothing
+    Nop,
+}}
 
-                Fragment::Unreachable => unreachable!("unreachable fragment reached!!!"),
+static OPS: [Op; {}] = [{}
+];
+
+static CHILDREN: [u32; {}] = {:?};
+
+static DATA: [u8; {}] = {:?};
+"#,
+            self.fragments.len(),
+            ops,
+            children.len(),
+            children,
+            data.len(),
+            data
+        )
+    }
+
+    /// The interpreter loop of the `Table` backend, which makes exactly the
+    /// same random choices as the functions of the `Functions` backend. The
+    /// choice points of the operations are added to `items`.
+    fn table_source(&self, items: &mut String) -> String {
+        // Choice points of the operations, for coverage and adaptive choices
+        let mut choose =
+            "\n                    let choice = rng.gen_range(0..len as i32);".to_string();
+        if self.coverage || self.adaptive {
+            let offsets: Vec<u32> = self
+                .coverage_points()
+                .1
+                .iter()
+                .map(|offset| offset.map_or(u32::MAX, |offset| offset as u32))
+                .collect();
+            *items += &format!(
+                "\nstatic POINT_OFFSETS: [u32; {}] = {:?};\n",
+                offsets.len(),
+                offsets
+            );
+
+            choose = "\n                    let offset = POINT_OFFSETS[node as usize];".to_string();
+            if self.adaptive {
+                choose += r#"
+                    let choice = if offset != u32::MAX {
+                        rng.choose(offset as usize, len as usize) as i32
+                    } else {
+                        rng.gen_range(0..len as i32)
+                    };"#;
+            } else {
+                choose += "\n                    let choice = rng.gen_range(0..len as i32);";
+            }
+            if self.coverage {
+                choose += r#"
+                    if offset != u32::MAX {
+                        COVERAGE[offset as usize + choice as usize].fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    }"#;
             }
         }
 
-        self.fragments = new_fragments;
+        format!(
+            r#"
+    fn run(node: u32, max_depth: usize, buf: &mut Vec<u8>, rng: &mut impl {}) {{
+        // A repetition pushes a single frame that runs its child `count` times
+        let mut stack: Vec<(u32, usize, u32)> = Vec::with_capacity(64);
+        stack.push((node, 0, 1));
+        while let Some((node, depth, count)) = stack.pop() {{
+            if depth >= max_depth {{ continue; }}
+            if count > 1 {{ stack.push((node, depth, count - 1)); }}
+            match OPS[node as usize] {{
+                Op::Choose(start, len) => {{{}
+                    stack.push((CHILDREN[start as usize + choice as usize], depth + 1, 1));
+                }}
+                Op::Sequence(start, len) => {{
+                    for &child in CHILDREN[start as usize..(start + len) as usize].iter().rev() {{
+                        stack.push((child, depth + 1, 1));
+                    }}
+                }}
+                Op::Repeat(child, min, max) => {{
+                    let count = if min == max {{ min }} else {{ rng.gen_range(min..=max) }};
+                    if count > 0 {{ stack.push((child, depth + 1, count)); }}
+                }}
+                Op::Emit(start, len) => {{
+                    buf.extend_from_slice(&DATA[start as usize..(start + len) as usize]);
+                }}
+                Op::Table(start, len, width) => {{{}
+                    if depth + 1 < max_depth {{
+                        let start = (start + choice as u32 * width) as usize;
+                        buf.extend_from_slice(&DATA[start..start + width as usize]);
+                    }}
+                }}
+                Op::Nop => {{}}
+            }}
+        }}
+    }}
+"#,
+            self.rng_bound(),
+            choose,
+            choose
+        )
     }
 
-    /// Generate a new Rust program that can be built and will generate random
-    /// inputs and benchmark them
-    pub fn program<P: AsRef<Path>>(&self, path: P, max_depth: usize) {
+    /// The counting tables and sampler of sized sampling, which walks the
+    /// operation tables and makes exactly the same random choices as
+    /// `SizedSampler::sample_into`. The counting tables and helpers are added
+    /// to `items`.
+    fn sized_source(&self, max_size: usize, items: &mut String) -> String {
+        let sampler = crate::sample::SizedSampler::new(self, max_size);
+        let counts: Vec<f64> = sampler.counts.concat();
+        let mut partial_offsets = Vec::new();
+        let mut partials: Vec<f64> = Vec::new();
+        for partial in sampler.partials.iter() {
+            partial_offsets.push(partials.len() as u32);
+            for part in partial.iter() {
+                partials.extend_from_slice(part);
+            }
+        }
+
+        *items += &format!(
+            r#"
+/// Largest size `GrammarGenerator::generate_sized_into` can generate
+pub const MAX_SIZE: usize = {};
+
+/// Number of derivations of every fragment by size
+static SIZE_COUNTS: [f64; {}] = {:?};
+
+/// Offsets into `PARTIALS` of every fragment
+static PARTIAL_OFFSETS: [u32; {}] = {:?};
+
+/// Number of derivations of the first `j` elements of a sequence, or of `j`
+/// iterations of a repetition, by size
+static PARTIALS: [f64; {}] = {:?};
+
+fn size_count(node: u32, size: usize) -> f64 {{
+    SIZE_COUNTS[node as usize * (MAX_SIZE + 1) + size]
+}}
+
+fn partial_count(node: u32, j: usize, size: usize) -> f64 {{
+    PARTIALS[PARTIAL_OFFSETS[node as usize] as usize + j * (MAX_SIZE + 1) + size]
+}}
+
+/// Pick an index with probability proportional to its weight
+fn pick_weighted(rng: &mut impl Rng, weights: impl Iterator<Item = f64> + Clone) -> usize {{
+    let total: f64 = weights.clone().sum();
+    let mut target = rng.gen::<f64>() * total;
+    let mut last = 0;
+    for (idx, weight) in weights.enumerate() {{
+        if weight > 0.0 {{
+            last = idx;
+            if target < weight {{
+                return idx;
+            }}
+            target -= weight;
+        }}
+    }}
+    last
+}}
+
+/// Split `size` among the children of `node`, choosing from the last one
+fn split_sizes(node: u32, children: &[u32], size: usize, rng: &mut impl Rng) -> Vec<usize> {{
+    let mut sizes = vec![0; children.len()];
+    let mut rem = size;
+    for j in (1..children.len()).rev() {{
+        let child = children[j];
+        let s = 1 + pick_weighted(rng, (1..=rem).map(|s| partial_count(node, j, rem - s) * size_count(child, s)));
+        sizes[j] = s;
+        rem -= s;
+    }}
+    if let Some(first) = sizes.first_mut() {{
+        *first = rem;
+    }}
+    sizes
+}}
+"#,
+            sampler.max_size(),
+            counts.len(),
+            counts,
+            partial_offsets.len(),
+            partial_offsets,
+            partials.len(),
+            partials
+        );
+
+        format!(
+            r#"
+    /// Number of derivations of the start rule of a size
+    pub fn sized_count(size: usize) -> f64 {{
+        if size > MAX_SIZE {{
+            return 0.0;
+        }}
+        size_count({}, size)
+    }}
+
+    /// Generate an input whose derivation has `size` fragments, uniformly
+    /// among all such derivations. Returns `false` if there is none.
+    pub fn generate_sized_into(out: &mut Vec<u8>, size: usize, rng: &mut impl Rng) -> bool {{
+        out.clear();
+        if Self::sized_count(size) == 0.0 {{
+            return false;
+        }}
+        Self::sample({}, size, out, rng);
+        true
+    }}
+
+    /// Generate an input like `generate_sized_into`, returning `None` if
+    /// there is no derivation of `size` fragments
+    pub fn generate_sized_new(size: usize, rng: &mut impl Rng) -> Option<Vec<u8>> {{
+        let mut out = Vec::new();
+        if Self::generate_sized_into(&mut out, size, rng) {{
+            Some(out)
+        }} else {{
+            None
+        }}
+    }}
+
+    fn sample(node: u32, size: usize, buf: &mut Vec<u8>, rng: &mut impl Rng) {{
+        let rem = size - 1;
+        match OPS[node as usize] {{
+            Op::Choose(start, len) => {{
+                let options = &CHILDREN[start as usize..(start + len) as usize];
+                let choice = pick_weighted(rng, options.iter().map(|&option| size_count(option, rem)));
+                Self::sample(options[choice], rem, buf, rng);
+            }}
+            Op::Sequence(start, len) => {{
+                let children = &CHILDREN[start as usize..(start + len) as usize];
+                for (&child, size) in children.iter().zip(split_sizes(node, children, rem, rng)) {{
+                    Self::sample(child, size, buf, rng);
+                }}
+            }}
+            Op::Repeat(child, min, max) => {{
+                let max = (max as usize).min(MAX_SIZE);
+                let count = min as usize + pick_weighted(rng, (min as usize..=max).map(|j| partial_count(node, j, rem)));
+                for size in split_sizes(node, &vec![child; count], rem, rng) {{
+                    Self::sample(child, size, buf, rng);
+                }}
+            }}
+            Op::Emit(start, len) => {{
+                buf.extend_from_slice(&DATA[start as usize..(start + len) as usize]);
+            }}
+            Op::Table(start, len, width) => {{
+                let choice = pick_weighted(rng, (0..len).map(|_| 1.0)) as u32;
+                let start = (start + choice * width) as usize;
+                buf.extend_from_slice(&DATA[start..start + width as usize]);
+            }}
+            Op::Nop => {{}}
+        }}
+    }}
+"#,
+            self.start.unwrap().0,
+            self.start.unwrap().0
+        )
+    }
+
+    /// `GrammarGenerator::generate_to_writer`, which walks the operation
+    /// tables like the `Table` backend and makes exactly the same random
+    /// choices as `generate_into`, but hands the output to an `io::Write`
+    /// whenever a chunk is full
+    fn stream_source(&self, max_depth: usize) -> String {
+        format!(
+            r#"
+    /// Size of the chunks `generate_to_writer` writes at once
+    pub const STREAM_CHUNK: usize = 1 << 16;
+
+    /// Generate the same input as `generate_into` with the same `rng`, but
+    /// write it to `w` in chunks of about `STREAM_CHUNK` bytes instead of
+    /// collecting it, so inputs of any size can be generated. Choices are not
+    /// counted for coverage. Returns the size of the input.
+    pub fn generate_to_writer(w: &mut impl std::io::Write, max_depth: Option<usize>, rng: &mut impl Rng) -> std::io::Result<u64> {{
+        let max_depth = max_depth.unwrap_or({} as usize);
+        let mut buf: Vec<u8> = Vec::with_capacity(Self::STREAM_CHUNK);
+        let mut written = 0u64;
+        // A repetition pushes a single frame that runs its child `count` times
+        let mut stack: Vec<(u32, usize, u32)> = Vec::with_capacity(64);
+        stack.push(({}, 0, 1));
+        while let Some((node, depth, count)) = stack.pop() {{
+            if depth >= max_depth {{ continue; }}
+            if count > 1 {{ stack.push((node, depth, count - 1)); }}
+            match OPS[node as usize] {{
+                Op::Choose(start, len) => {{
+                    let choice = rng.gen_range(0..len as i32);
+                    stack.push((CHILDREN[start as usize + choice as usize], depth + 1, 1));
+                }}
+                Op::Sequence(start, len) => {{
+                    for &child in CHILDREN[start as usize..(start + len) as usize].iter().rev() {{
+                        stack.push((child, depth + 1, 1));
+                    }}
+                }}
+                Op::Repeat(child, min, max) => {{
+                    let count = if min == max {{ min }} else {{ rng.gen_range(min..=max) }};
+                    if count > 0 {{ stack.push((child, depth + 1, count)); }}
+                }}
+                Op::Emit(start, len) => {{
+                    buf.extend_from_slice(&DATA[start as usize..(start + len) as usize]);
+                }}
+                Op::Table(start, len, width) => {{
+                    let choice = rng.gen_range(0..len as i32);
+                    if depth + 1 < max_depth {{
+                        let start = (start + choice as u32 * width) as usize;
+                        buf.extend_from_slice(&DATA[start..start + width as usize]);
+                    }}
+                }}
+                Op::Nop => {{}}
+            }}
+            if buf.len() >= Self::STREAM_CHUNK {{
+                w.write_all(&buf)?;
+                written += buf.len() as u64;
+                buf.clear();
+            }}
+        }}
+        w.write_all(&buf)?;
+        Ok(written + buf.len() as u64)
+    }}
+"#,
+            max_depth,
+            self.start.unwrap().0
+        )
+    }
+
+    /// Generate the source code of the generator written by `program`
+    pub fn source(&self, max_depth: usize) -> String {
         let mut program = String::new();
 
         let mut terminal_count = 0usize;
@@ -266,13 +1268,44 @@ impl GrammarRust {
             if let Fragment::Terminal(data) = fragment {
                 let s = String::from_utf8_lossy(data);
                 if !seen_terminals.contains(&s) {
-                    terminal_list += &format!("{:?}, ", s);
+                    // One terminal per line keeps diffs of the generated
+                    // code small when terminals are added or removed
+                    terminal_list += &format!("\n    {:?},", s);
                     terminal_count += 1;
                     seen_terminals.insert(s);
                 }
             }
         }
 
+        // Every rule that is still around can be generated on its own
+        let mut rule_variants = String::new();
+        let mut rule_arms = String::new();
+        let mut rule_name_list = String::new();
+        let mut rule_count = 0usize;
+        let mut seen_variants = HashSet::new();
+        for (name, id) in self.name_to_fragment.iter() {
+            if matches!(self.fragments[id.0], Fragment::Unreachable) {
+                continue;
+            }
+
+            let variant = rule_variant(name);
+            let mut unique = variant.clone();
+            let mut counter = 1;
+            while !seen_variants.insert(unique.clone()) {
+                counter += 1;
+                unique = format!("{}{}", variant, counter);
+            }
+            rule_variants += &format!("\n    {},", unique);
+            rule_arms += &format!(
+                "\n            Rule::{} => {}max_depth, out, {}),",
+                unique,
+                self.entry_call(*id),
+                self.entry_rng()
+            );
+            rule_name_list += &format!("\n    {:?},", name);
+            rule_count += 1;
+        }
+
         // Construct the base of the application. This is a profiling loop that
         // is used for testing.
         program += &format!(
@@ -283,7 +1316,12 @@ use rand::Rng;
 
 pub struct GrammarGenerator;
 
-pub static TERMINALS: [&'static str; {}] = [{}];
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {{{}
+}}
+
+pub static TERMINALS: [&'static str; {}] = [{}
+];
 
 impl GrammarGenerator {{
 
@@ -293,7 +1331,7 @@ impl GrammarGenerator {{
 
     pub fn generate_into(out: &mut Vec<u8>, max_depth: Option<usize>, rng: &mut impl Rng) {{
         out.clear();
-        Self::fragment_{}(0, max_depth.unwrap_or({} as usize), out, rng);
+        {}max_depth.unwrap_or({} as usize), out, {});
     }}
 
     pub fn generate_new(max_depth: Option<usize>, rng: &mut impl Rng) -> Vec<u8> {{
@@ -301,49 +1339,379 @@ impl GrammarGenerator {{
         Self::generate_into(&mut out, max_depth, rng);
         out
     }}
+
+    pub fn generate_rule_into(rule: Rule, out: &mut Vec<u8>, max_depth: Option<usize>, rng: &mut impl Rng) {{
+        out.clear();
+        let max_depth = max_depth.unwrap_or({} as usize);
+        match rule {{{}
+        }}
+    }}
+
+    pub fn generate_rule_new(rule: Rule, max_depth: Option<usize>, rng: &mut impl Rng) -> Vec<u8> {{
+        let mut out = Vec::new();
+        Self::generate_rule_into(rule, &mut out, max_depth, rng);
+        out
+    }}
 "#,
+            rule_variants,
             terminal_count,
             terminal_list,
-            self.start.unwrap().0,
-            max_depth
+            self.entry_call(self.start.unwrap()),
+            max_depth,
+            self.entry_rng(),
+            max_depth,
+            rule_arms
         );
 
+        // Statics, types and functions outside of `GrammarGenerator`, written
+        // after its impl block
+        let mut items = String::new();
+
+        if self.rule_names {
+            // The variants of `Rule` are in the same order as the names
+            items += &format!(
+                r#"
+pub static RULE_NAMES: [&'static str; {}] = [{}
+];
+
+impl Rule {{
+    pub fn name(self) -> &'static str {{
+        RULE_NAMES[self as usize]
+    }}
+}}
+"#,
+                rule_count, rule_name_list
+            );
+        }
+
+        if self.coverage {
+            let points = self.coverage_points().0;
+            let point_list: String = points
+                .iter()
+                .map(|(rule, alt)| format!("\n    ({:?}, {}),", rule, alt))
+                .collect();
+            items += &format!(
+                r#"
+#[allow(clippy::declare_interior_mutable_const)]
+const COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Number of times every alternative was chosen
+pub static COVERAGE: [std::sync::atomic::AtomicU64; {}] = [COUNTER; {}];
+
+/// The rule and alternative of every counter in `COVERAGE`
+pub static COVERAGE_POINTS: [(&'static str, usize); {}] = [{}
+];
+"#,
+                points.len(),
+                points.len(),
+                points.len(),
+                point_list
+            );
+            program += r#"
+    /// Write the coverage counters in the format read by
+    /// `fzero_gen::coverage::Coverage::parse`
+    pub fn dump_coverage(out: &mut impl std::io::Write) -> std::io::Result<()> {
+        for ((rule, alternative), count) in COVERAGE_POINTS.iter().zip(COVERAGE.iter()) {
+            let count = count.load(std::sync::atomic::Ordering::Relaxed);
+            writeln!(out, "{}\t{}\t{}", rule, alternative, count)?;
+        }
+        Ok(())
+    }
+
+    /// Set all coverage counters to zero
+    pub fn reset_coverage() {
+        for count in COVERAGE.iter() {
+            count.store(0, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+"#;
+        }
+
+        if self.adaptive {
+            items += &format!(
+                r#"
+/// Picks the alternatives of rules
+pub trait Choose: Rng {{
+    /// Pick one of `options` alternatives, whose choice points start at
+    /// `point`
+    fn choose(&mut self, point: usize, options: usize) -> usize;
+}}
+
+/// Uniform choices, exactly like the code generated without adaptive mode
+struct Uniform<'a, R>(&'a mut R);
+
+impl<'a, R: Rng> rand::RngCore for Uniform<'a, R> {{
+    fn next_u32(&mut self) -> u32 {{ self.0.next_u32() }}
+    fn next_u64(&mut self) -> u64 {{ self.0.next_u64() }}
+    fn fill_bytes(&mut self, dest: &mut [u8]) {{ self.0.fill_bytes(dest) }}
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {{ self.0.try_fill_bytes(dest) }}
+}}
+
+impl<'a, R: Rng> Choose for Uniform<'a, R> {{
+    fn choose(&mut self, _point: usize, options: usize) -> usize {{
+        self.0.gen_range(0..options as i32) as usize
+    }}
+}}
+
+/// Number of choice points of adaptive generation, one per alternative of
+/// every rule
+pub const CHOICE_POINTS: usize = {};
+
+/// State of adaptive generation. The weight of an alternative is
+/// `(1 + reward) / (1 + count)`, where `count` is the number of times it was
+/// chosen and `reward` the novelty reported with `feedback` for the inputs it
+/// was chosen for.
+#[derive(Clone, Debug)]
+pub struct Adaptive {{
+    counts: Vec<u64>,
+    rewards: Vec<f64>,
+    trace: Vec<usize>,
+}}
+
+impl Default for Adaptive {{
+    fn default() -> Self {{
+        Self::new()
+    }}
+}}
+
+impl Adaptive {{
+    pub fn new() -> Self {{
+        Self {{
+            counts: vec![0; CHOICE_POINTS],
+            rewards: vec![0.0; CHOICE_POINTS],
+            trace: Vec::new(),
+        }}
+    }}
+
+    /// Number of times every choice point was chosen
+    pub fn counts(&self) -> &[u64] {{
+        &self.counts
+    }}
+
+    /// Reward the alternatives chosen for the last generated input with
+    /// `novelty`, e.g. the number of new edges the target covered with it
+    pub fn feedback(&mut self, novelty: f64) {{
+        for &point in self.trace.iter() {{
+            self.rewards[point] += novelty;
+        }}
+    }}
+
+    /// Scale down all rewards, so old feedback is slowly forgotten
+    pub fn decay(&mut self, factor: f64) {{
+        for reward in self.rewards.iter_mut() {{
+            *reward *= factor;
+        }}
+    }}
+
+    fn weight(&self, point: usize) -> f64 {{
+        (1.0 + self.rewards[point]) / (1.0 + self.counts[point] as f64)
+    }}
+}}
+
+/// Weighted choices according to an `Adaptive` state
+struct AdaptiveRng<'a, R> {{
+    rng: &'a mut R,
+    state: &'a mut Adaptive,
+}}
+
+impl<'a, R: Rng> rand::RngCore for AdaptiveRng<'a, R> {{
+    fn next_u32(&mut self) -> u32 {{ self.rng.next_u32() }}
+    fn next_u64(&mut self) -> u64 {{ self.rng.next_u64() }}
+    fn fill_bytes(&mut self, dest: &mut [u8]) {{ self.rng.fill_bytes(dest) }}
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {{ self.rng.try_fill_bytes(dest) }}
+}}
+
+impl<'a, R: Rng> Choose for AdaptiveRng<'a, R> {{
+    fn choose(&mut self, point: usize, options: usize) -> usize {{
+        let total: f64 = (point..point + options).map(|p| self.state.weight(p)).sum();
+        let mut target = self.rng.gen::<f64>() * total;
+        let mut choice = options - 1;
+        for alt in 0..options {{
+            let weight = self.state.weight(point + alt);
+            if target < weight {{
+                choice = alt;
+                break;
+            }}
+            target -= weight;
+        }}
+        self.state.counts[point + choice] += 1;
+        self.state.trace.push(point + choice);
+        choice
+    }}
+}}
+"#,
+                self.coverage_points().0.len()
+            );
+            program += &format!(
+                r#"
+    /// Generate an input, favouring alternatives that were chosen less often
+    /// or rewarded with `Adaptive::feedback`
+    pub fn generate_adaptive_into(out: &mut Vec<u8>, max_depth: Option<usize>, rng: &mut impl Rng, state: &mut Adaptive) {{
+        out.clear();
+        state.trace.clear();
+        {}max_depth.unwrap_or({} as usize), out, &mut AdaptiveRng {{ rng, state }});
+    }}
+
+    pub fn generate_adaptive_new(max_depth: Option<usize>, rng: &mut impl Rng, state: &mut Adaptive) -> Vec<u8> {{
+        let mut out = Vec::new();
+        Self::generate_adaptive_into(&mut out, max_depth, rng, state);
+        out
+    }}
+"#,
+                self.entry_call(self.start.unwrap()),
+                max_depth
+            );
+        }
+
+        if let Some(max_size) = self.sized_sampling {
+            program += &self.sized_source(max_size, &mut items);
+        }
+
+        if self.streaming {
+            program += &self.stream_source(max_depth);
+        }
+
+        if self.backend == Backend::Table {
+            program += &self.table_source(&mut items);
+        } else {
+            program += &self.functions_source();
+        }
+        program += "}\n";
+
+        // Sized sampling and streaming walk the operation tables of the
+        // `Table` backend
+        if self.backend == Backend::Table || self.sized_sampling.is_some() || self.streaming {
+            items += &self.op_tables();
+        }
+
+        program += &items;
+        program
+    }
+
+    /// The functions of the `Functions` backend, one for every fragment that
+    /// is called somewhere
+    fn functions_source(&self) -> String {
+        let mut program = String::new();
+        let coverage_offsets = self.coverage_points().1;
+
+        // Only fragments that are called somewhere need a function, tiny
+        // terminals are inlined into their callers
+        let mut called = vec![false; self.fragments.len()];
+        called[self.start.unwrap().0] = true;
+        for id in self.name_to_fragment.values() {
+            if !matches!(self.fragments[id.0], Fragment::Unreachable) {
+                called[id.0] = true;
+            }
+        }
+        for fragment in self.fragments.iter() {
+            let children = match fragment {
+                Fragment::NonTerminal(options) if self.choice_table(options).is_some() => &[],
+                _ => fragment.children(),
+            };
+            for &child in children {
+                if self.inline_terminal(child).is_none() {
+                    called[child.0] = true;
+                }
+            }
+        }
+
         // Go through each fragment in the list of fragments
         for (id, fragment) in self.fragments.iter().enumerate() {
-            if matches!(fragment, Fragment::Unreachable) {
+            if matches!(fragment, Fragment::Unreachable) || !called[id] {
                 continue;
             }
 
+            // Note the place in the grammar this fragment was created for,
+            // the label alone does not survive forwarding and merging
+            if !self.origins[id].rule.is_empty() {
+                program += &format!("    // {}\n", self.origins[id]);
+            }
+
             // Create a new function for this fragment
-            program += &format!("    fn fragment_{}(depth: usize, max_depth: usize, buf: &mut Vec<u8>, rng: &mut impl Rng) {{\n", id);
+            program += &format!(
+                "    fn {}(depth: usize, max_depth: usize, buf: &mut Vec<u8>, rng: &mut impl {}) {{\n",
+                self.labels[id],
+                self.rng_bound()
+            );
 
             // Add depth checking to terminate on depth exhaustion
             program.push_str("        if depth >= max_depth { return; }\n");
 
             match fragment {
+                Fragment::NonTerminal(options) if self.choice_table(options).is_some() => {
+                    // Pick from a table of equally long terminals. The
+                    // random choice is made before the depth check of the
+                    // option, just like for the `match` below.
+                    let values = self.choice_table(options).unwrap();
+                    program += &format!(
+                        "        let choice = {};\n",
+                        self.choice_code(coverage_offsets[id], options.len())
+                    );
+                    program += &self.count_code(coverage_offsets[id]);
+                    program += "        if depth + 1 < max_depth {\n";
+                    if values[0].len() == 1 {
+                        let table: Vec<u8> = values.iter().map(|value| value[0]).collect();
+                        program += &format!(
+                            "            static TABLE: [u8; {}] = {:?};\n",
+                            table.len(),
+                            table
+                        );
+                        program += "            buf.push(TABLE[choice as usize]);\n";
+                    } else {
+                        program += &format!(
+                            "            static TABLE: [[u8; {}]; {}] = {:?};\n",
+                            values[0].len(),
+                            values.len(),
+                            values
+                        );
+                        program += "            buf.extend_from_slice(&TABLE[choice as usize]);\n";
+                    }
+                    program += "        }\n";
+                }
                 Fragment::NonTerminal(options) => {
                     // For non-terminal cases pick a random variant to select
                     // and invoke that fragment's routine
-                    program += &format!("        match rng.gen_range(0..{}) {{\n", options.len());
+                    let count = self.count_code(coverage_offsets[id]);
+                    let choice = self.choice_code(coverage_offsets[id], options.len());
+                    if !count.is_empty() || self.adaptive {
+                        program += &format!("        let choice = {};\n", choice);
+                        program += &count;
+                        program += "        match choice {\n";
+                    } else {
+                        program +=
+                            &format!("        match rng.gen_range(0..{}) {{\n", options.len());
+                    }
 
-                    for (option_id, option) in options.iter().enumerate() {
+                    for (option_id, &option) in options.iter().enumerate() {
                         program += &format!(
-                            "            {} => Self::fragment_{}(depth + 1, max_depth, buf, rng),\n",
-                            option_id, option.0
+                            "            {} => {{ {} }}\n",
+                            option_id,
+                            self.expand_code(option)
                         );
                     }
-                    program += &format!("            _ => unreachable!(),\n");
+                    program += "            _ => unreachable!(),\n";
 
-                    program += &format!("        }}\n");
+                    program += "        }\n";
                 }
                 Fragment::Expression(expr) => {
                     // Invoke all of the expression's routines in order
                     for &exp in expr.iter() {
+                        program += &format!("        {}\n", self.expand_code(exp));
+                    }
+                }
+                Fragment::Repeat { fragment, min, max } => {
+                    // Expand the repeated fragment a random number of times,
+                    // every iteration at the same depth
+                    if min == max {
+                        program += &format!("        for _ in 0..{} {{\n", min);
+                    } else {
                         program += &format!(
-                            "        Self::fragment_{}(depth + 1, max_depth, buf, rng);\n",
-                            exp.0
+                            "        for _ in 0..rng.gen_range({}u32..={}u32) {{\n",
+                            min, max
                         );
                     }
+                    program += &format!("            {}\n", self.expand_code(*fragment));
+                    program += "        }\n";
                 }
                 Fragment::Terminal(value) => {
                     let as_str = String::from_utf8_lossy(value);
@@ -389,10 +1757,8 @@ impl GrammarGenerator {{
 
             program += "    }\n";
         }
-        program += "}\n";
 
-        // Write out the test application
-        std::fs::write(path, program).expect("Failed to create output Rust application");
+        program
     }
 }
 
@@ -408,3 +1774,360 @@ pub fn generate_lib_from_grammar(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repetition_suffixes() {
+        assert_eq!(split_repetition("<digit>?"), Ok(Some(("<digit>", 0, 1))));
+        assert_eq!(
+            split_repetition("<digit>*"),
+            Ok(Some(("<digit>", 0, UNBOUNDED_REPEAT_SPAN)))
+        );
+        assert_eq!(
+            split_repetition("<digit>+"),
+            Ok(Some(("<digit>", 1, 1 + UNBOUNDED_REPEAT_SPAN)))
+        );
+        assert_eq!(
+            split_repetition("<digit>{1,10}"),
+            Ok(Some(("<digit>", 1, 10)))
+        );
+        assert_eq!(split_repetition("<digit>{4}"), Ok(Some(("<digit>", 4, 4))));
+        assert_eq!(
+            split_repetition("<digit>{2,}"),
+            Ok(Some(("<digit>", 2, 2 + UNBOUNDED_REPEAT_SPAN)))
+        );
+        assert_eq!(
+            split_repetition("<!numbers.digit>{,3}"),
+            Ok(Some(("<!numbers.digit>", 0, 3)))
+        );
+
+        assert_eq!(split_repetition("*"), Ok(None));
+        assert_eq!(split_repetition("a?"), Ok(None));
+        assert_eq!(split_repetition("<digit>{x}"), Ok(None));
+        assert!(split_repetition("<digit>{3,1}").is_err());
+        assert!(split_repetition("<digit>{0,99999999999}").is_err());
+        assert!(split_repetition("<digit>{99999999999999999999999}").is_err());
+        assert_eq!(
+            split_repetition("<digit>{0,4294967295}"),
+            Ok(Some(("<digit>", 0, MAX_REPEAT)))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid repetition in rule <start>")]
+    fn invalid_repetitions_are_rejected() {
+        let grammar: Grammar =
+            serde_json::from_str(r#"{"<start>": [["<digit>{3,1}"]], "<digit>": [["0"]]}"#).unwrap();
+        GrammarRust::new(&grammar, None);
+    }
+
+    #[test]
+    fn repetitions_of_literals_are_terminals() {
+        // `<b>` is no rule, so this is a literal that only looks like a
+        // repetition
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<b>{5,2}", "<i>{99999999999}", "<digit>{2}"]], "<digit>": [["0"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::try_new(&grammar, None).unwrap();
+        let mut rng = bufrng::BufRng::new(&[]);
+        assert_eq!(
+            gram.generate_new(16, &mut rng),
+            b"<b>{5,2}<i>{99999999999}00"
+        );
+
+        let grammar: Grammar =
+            serde_json::from_str(r#"{"<start>": [["<digit>{5,2}"]], "<digit>": [["0"]]}"#).unwrap();
+        let err = GrammarRust::try_new(&grammar, None).unwrap_err();
+        assert!(
+            err.starts_with("invalid repetition in rule <start>"),
+            "{}",
+            err
+        );
+        assert!(GrammarRust::try_new(&grammar, Some("<missing>")).is_err());
+    }
+
+    #[test]
+    fn labels_do_not_depend_on_builtins() {
+        let plain: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<http-version>"]], "<http-version>": [["HTTP/", "1.1"]]}"#,
+        )
+        .unwrap();
+        let with_builtin: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<!numbers.digit>", "<http-version>"]], "<http-version>": [["HTTP/", "1.1"]]}"#,
+        )
+        .unwrap();
+        let plain = GrammarRust::new(&plain, None);
+        let with_builtin = GrammarRust::new(&with_builtin, None);
+
+        let id = plain.name_to_fragment["<http-version>"];
+        assert_eq!(id.0, with_builtin.name_to_fragment["<http-version>"].0);
+        assert_eq!(plain.labels[id.0], "http_version");
+        assert!(with_builtin.labels.contains(&"start_alt0_0".to_string()));
+        assert!(with_builtin.labels.contains(&"numbers_digit".to_string()));
+
+        let unique: HashSet<_> = with_builtin.labels.iter().collect();
+        assert_eq!(unique.len(), with_builtin.labels.len());
+    }
+
+    #[test]
+    fn repetition_is_lowered_to_repeat() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>{1,10}", "<!numbers.digit>?"]], "<digit>": [["0"], ["1"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let digit = gram.name_to_fragment["<digit>"];
+        let repeats: Vec<_> = gram
+            .fragments
+            .iter()
+            .filter_map(|f| match f {
+                Fragment::Repeat { fragment, min, max } => Some((fragment.0, *min, *max)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(repeats.len(), 2);
+        assert!(repeats.contains(&(digit.0, 1, 10)));
+
+        // Builtins are referenced through a placeholder that is resolved once
+        // the builtin module is imported
+        let (placeholder, _, _) = repeats.iter().find(|r| r.1 == 0).unwrap();
+        match &gram.fragments[*placeholder] {
+            Fragment::NonTerminal(options) => {
+                assert_eq!(options[0].0, gram.name_to_fragment["<!numbers.digit>"].0)
+            }
+            other => panic!("unexpected placeholder {:?}", other),
+        }
+    }
+
+    #[test]
+    fn adjacent_terminals_are_merged() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<version>", "\r\n"]], "<version>": [["HTTP/", "1", ".", "1"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+
+        let stats = gram.optimize();
+        assert!(stats.passes["flatten"] > 0);
+        assert_eq!(stats.flattened, stats.passes["flatten"]);
+        assert!(stats.merged_terminals > 0);
+        assert!(stats.fragments_after < stats.fragments_before);
+        match &gram.fragments[gram.start.unwrap().0] {
+            Fragment::Terminal(value) => assert_eq!(value, b"HTTP/1.1\r\n"),
+            other => panic!("start was not merged: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn single_byte_alternatives_use_a_table() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>", "<hex>"]], "<digit>": [["0"], ["1"], ["2"]], "<hex>": [["0x"], ["ff"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("static TABLE: [u8; 3] = [48, 49, 50];"));
+        assert!(source.contains("static TABLE: [[u8; 2]; 2] = [[48, 120], [102, 102]];"));
+        assert!(!source.contains("match rng"));
+    }
+
+    #[test]
+    fn extra_roots_are_kept() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<header-name>"]], "<header-name>": [["Host"]], "<url>": [["http://", "<header-name>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.add_root("<url>");
+        gram.optimize();
+
+        let url = gram.fragment_id("<url>").unwrap();
+        let out = {
+            let mut out = Vec::new();
+            let mut rng = bufrng::BufRng::new(&[]);
+            gram.generate_rule_into(url, &mut out, 16, &mut rng);
+            out
+        };
+        assert_eq!(out, b"http://Host");
+
+        let source = gram.source(16);
+        assert!(source.contains("    HeaderName,\n    Start,\n    Url,\n"));
+        assert!(source.contains("Rule::Url => Self::"));
+    }
+
+    #[test]
+    fn identical_recursive_rules_are_deduplicated() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>", "<b>"]], "<a>": [["x", "<a>"], ["y"]], "<b>": [["x", "<b>"], ["y"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+
+        let stats = gram.optimize();
+        assert!(stats.passes["deduplicate"] > 0);
+        assert_eq!(stats.deduplicated, stats.passes["deduplicate"]);
+        assert_eq!(gram.name_to_fragment["<a>"], gram.name_to_fragment["<b>"]);
+    }
+
+    #[test]
+    fn table_backend_emits_operations() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>{2}", "<word>"]], "<digit>": [["0"], ["1"]], "<word>": [["ab"], ["c", "<word>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.backend = Backend::Table;
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("Op::Table("));
+        assert!(source.contains("Op::Repeat("));
+        assert!(source.contains("Op::Choose("));
+        assert!(source.contains(&format!(
+            "Self::run({}, max_depth.unwrap_or(16 as usize), out, rng);",
+            gram.start.unwrap().0
+        )));
+        assert!(!source.contains("fn start("));
+    }
+
+    #[test]
+    fn coverage_counts_rule_choices() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<a>", "<a>"]], "<a>": [["x"], ["y", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.coverage = true;
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("[(&'static str, usize); 4] = [\n    (\"<a>\", 0),"));
+        assert_eq!(source.matches("].fetch_add(1, ").count(), 2);
+
+        gram.backend = Backend::Table;
+        let source = gram.source(16);
+        assert!(source.contains("static POINT_OFFSETS: [u32; "));
+    }
+
+    #[test]
+    fn adaptive_choices_go_through_choose() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<a>", "<a>"]], "<a>": [["x"], ["y", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.adaptive = true;
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("pub const CHOICE_POINTS: usize = 4;"));
+        assert_eq!(source.matches("let choice = rng.choose(").count(), 2);
+        assert!(source.contains("out, &mut Uniform(rng));"));
+        assert!(!source.contains("rng: &mut impl Rng) {\n        if depth"));
+    }
+
+    #[test]
+    fn sized_sampling_walks_op_tables() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<a>", "<a>"]], "<a>": [["x"], ["y", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.sized_sampling = Some(32);
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("pub const MAX_SIZE: usize = 32;"));
+        assert!(source.contains("static OPS: ["));
+        assert!(source.contains("pub fn generate_sized_into("));
+
+        // The functions are still generated for the other entry points
+        assert!(source.contains("rng: &mut impl Rng) {\n        if depth"));
+    }
+
+    #[test]
+    fn streaming_walks_op_tables() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<a>", "<a>"]], "<a>": [["x"], ["y", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.streaming = true;
+        gram.optimize();
+
+        let source = gram.source(16);
+        assert!(source.contains("pub fn generate_to_writer("));
+        assert!(source.contains("let max_depth = max_depth.unwrap_or(16 as usize);"));
+        assert!(source.contains("static OPS: ["));
+        assert!(source.contains("rng: &mut impl Rng) {\n        if depth"));
+
+        // The table backend already has the tables
+        gram.backend = Backend::Table;
+        assert_eq!(gram.source(16).matches("static OPS: [").count(), 1);
+    }
+
+    #[test]
+    fn options_share_one_impl_block() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<a>"], ["<a>", "<a>"]], "<a>": [["x"], ["y", "<a>"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.rule_names = true;
+        gram.coverage = true;
+        gram.adaptive = true;
+        gram.sized_sampling = Some(8);
+        gram.streaming = true;
+        gram.optimize();
+
+        for &backend in [Backend::Functions, Backend::Table].iter() {
+            gram.backend = backend;
+            let source = gram.source(16);
+            assert_eq!(source.matches("impl GrammarGenerator {").count(), 1);
+            assert_eq!(source.matches("static OPS: [").count(), 1);
+
+            // Everything after the impl block is at module level
+            let start = source.find("impl GrammarGenerator {").unwrap();
+            let items = &source[start + source[start..].find("\n}\n").unwrap()..];
+            assert!(items.contains("pub static COVERAGE: ["));
+            assert!(items.contains("pub trait Choose: Rng {"));
+            assert!(items.contains("static SIZE_COUNTS: ["));
+        }
+    }
+
+    #[test]
+    fn origins_survive_optimization() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["GET ", "<url>"]], "<url>": [["/", "<digit>{1,4}"], ["*"]], "<digit>": [["0"], ["1"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.rule_names = true;
+        gram.optimize();
+
+        let url = gram.fragment_id("<url>").unwrap();
+        assert_eq!(gram.origin(url).to_string(), "<url>");
+        let repeat = gram
+            .fragments
+            .iter()
+            .position(|fragment| matches!(fragment, Fragment::Repeat { .. }))
+            .unwrap();
+        assert_eq!(
+            gram.origin(FragmentId(repeat)).to_string(),
+            "<url> alternative 0 symbol 1"
+        );
+
+        let source = gram.source(16);
+        assert!(source.contains("    // <url>\n    fn url("));
+        assert!(source.contains("    // <url> alternative 0 symbol 1\n    fn "));
+        assert!(source.contains("\"<digit>\",\n    \"<start>\",\n    \"<url>\","));
+        assert!(source.contains("RULE_NAMES[self as usize]"));
+    }
+}
@@ -0,0 +1,732 @@
+//! Optimization passes over the fragments of a `GrammarRust`.
+//!
+//! Every pass rewrites the fragment graph in place and reports how many
+//! changes it made. The `PassManager` runs its passes in order until a full
+//! round makes no more changes. Passes never copy the contents of one
+//! fragment into another; instead references are redirected, so a pass never
+//! observes a stale copy of a fragment that is optimized later in the same
+//! round.
+
+use std::collections::{BTreeMap, HashMap};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::parse::Recognizer;
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// Upper bound on the number of rounds the `PassManager` runs its passes
+const MAX_ROUNDS: usize = 1000;
+
+/// Longest input that is checked by `verify`. Parsing is cubic in the input
+/// length for ambiguous grammars, so longer inputs are skipped.
+pub const MAX_VERIFY_LEN: usize = 256;
+
+/// How much the grammar is optimized before code is generated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Generate code for the grammar as it was constructed
+    None,
+
+    /// Remove fragments without effect, forward trivial fragments to the
+    /// fragment they expand to and drop unreachable fragments
+    Basic,
+
+    /// Additionally flatten expressions, merge adjacent terminals and
+    /// deduplicate identical fragments
+    #[default]
+    Full,
+}
+
+impl OptLevel {
+    /// Parse an optimization level as given with `-O` on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "0" => Some(OptLevel::None),
+            "1" => Some(OptLevel::Basic),
+            "2" => Some(OptLevel::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Statistics about the effect of a `PassManager` run
+#[derive(Clone, Debug, Default)]
+pub struct OptimizeStats {
+    /// Number of fragments code was generated for before optimizing
+    pub fragments_before: usize,
+
+    /// Number of fragments code is generated for after optimizing
+    pub fragments_after: usize,
+
+    /// Number of nested expressions that were flattened into their parent
+    pub flattened: usize,
+
+    /// Number of runs of adjacent terminals that were merged into one
+    pub merged_terminals: usize,
+
+    /// Number of fragments that were replaced by an identical fragment
+    pub deduplicated: usize,
+
+    /// Number of rounds until no pass made a change
+    pub rounds: usize,
+
+    /// Number of changes made by every pass, keyed by the name of the pass
+    pub passes: BTreeMap<&'static str, usize>,
+
+    /// Translation of fragment ids from before optimizing to the compacted
+    /// fragment list
+    pub remap: IdRemap,
+}
+
+/// Maps the ids of fragments before compaction to their ids afterwards
+#[derive(Clone, Debug, Default)]
+pub struct IdRemap(Vec<Option<FragmentId>>);
+
+impl IdRemap {
+    /// The id of the fragment that `old` was compacted to. Fragments that
+    /// were replaced by an equivalent fragment map to that fragment, and
+    /// fragments that were removed map to `None`.
+    pub fn get(&self, old: FragmentId) -> Option<FragmentId> {
+        self.0.get(old.0).copied().flatten()
+    }
+
+    /// Number of fragment ids before compaction
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there were no fragments before compaction
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A transformation of the fragment graph that does not change the language
+/// of the grammar
+pub trait Pass {
+    /// Name of the pass as shown in statistics
+    fn name(&self) -> &'static str;
+
+    /// Run the pass once, returning the number of changes it made
+    fn run(&self, gram: &mut GrammarRust) -> usize;
+}
+
+/// Runs a list of passes until none of them changes the grammar anymore
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    /// Create a pass manager without any passes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a pass manager with the passes of an optimization level
+    pub fn for_level(level: OptLevel) -> Self {
+        let mut manager = Self::new();
+        if level >= OptLevel::Basic {
+            manager.add(RemoveNops);
+            manager.add(Forward);
+        }
+        if level >= OptLevel::Full {
+            manager.add(Flatten);
+            manager.add(MergeTerminals);
+            manager.add(Deduplicate);
+        }
+        if level >= OptLevel::Basic {
+            manager.add(RemoveUnreachable);
+        }
+        manager
+    }
+
+    /// Add a pass to run after all passes added so far
+    pub fn add(&mut self, pass: impl Pass + 'static) -> &mut Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Run all passes until a fixed point is reached
+    pub fn run(&self, gram: &mut GrammarRust) -> OptimizeStats {
+        let mut stats = OptimizeStats {
+            fragments_before: gram.fragment_count(),
+            ..Default::default()
+        };
+        for pass in self.passes.iter() {
+            stats.passes.insert(pass.name(), 0);
+        }
+
+        while stats.rounds < MAX_ROUNDS {
+            stats.rounds += 1;
+
+            let mut changes = 0;
+            for pass in self.passes.iter() {
+                let count = pass.run(gram);
+                *stats.passes.get_mut(pass.name()).unwrap() += count;
+                changes += count;
+            }
+            if changes == 0 {
+                break;
+            }
+        }
+
+        // Merged terminals may have been given the same label as an earlier
+        // merge at the same position
+        gram.deduplicate_labels();
+
+        let count = |name| stats.passes.get(name).copied().unwrap_or(0);
+        let (flattened, merged_terminals, deduplicated) = (
+            count("flatten"),
+            count("merge-terminals"),
+            count("deduplicate"),
+        );
+        stats.flattened = flattened;
+        stats.merged_terminals = merged_terminals;
+        stats.deduplicated = deduplicated;
+        stats.fragments_after = gram.fragment_count();
+        stats
+    }
+}
+
+/// Redirect every reference to a fragment, including the start node and the
+/// named rules, according to `target`
+fn redirect(gram: &mut GrammarRust, target: impl Fn(FragmentId) -> FragmentId) -> usize {
+    // Remember which fragments were replaced, so their ids can still be
+    // translated after compaction
+    for (idx, fragment) in gram.fragments.iter().enumerate() {
+        let new = target(FragmentId(idx));
+        if new.0 != idx && !matches!(fragment, Fragment::Unreachable) {
+            gram.redirects.insert(idx, new);
+        }
+    }
+
+    let mut changes = 0;
+    let mut update = |id: &mut FragmentId| {
+        let new = target(*id);
+        if new != *id {
+            *id = new;
+            changes += 1;
+        }
+    };
+
+    for fragment in gram.fragments.iter_mut() {
+        fragment.children_mut().iter_mut().for_each(&mut update);
+    }
+    gram.name_to_fragment.values_mut().for_each(&mut update);
+    if let Some(start) = gram.start.as_mut() {
+        update(start);
+    }
+
+    changes
+}
+
+/// Replaces fragments that have no effect by `Nop` and removes `Nop`s from
+/// expressions
+pub struct RemoveNops;
+
+impl Pass for RemoveNops {
+    fn name(&self) -> &'static str {
+        "remove-nops"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        let mut changes = 0;
+        for idx in 0..gram.fragments.len() {
+            let is_nop = |id: &FragmentId| matches!(gram.fragments[id.0], Fragment::Nop);
+
+            let replacement = match &gram.fragments[idx] {
+                // Expressions and repetitions of nothing do nothing
+                Fragment::Expression(expr) if expr.is_empty() => Some(Fragment::Nop),
+                Fragment::Expression(expr) if expr.iter().any(is_nop) => Some(
+                    Fragment::Expression(expr.iter().copied().filter(|x| !is_nop(x)).collect()),
+                ),
+                Fragment::Repeat { fragment, max, .. } if *max == 0 || is_nop(fragment) => {
+                    Some(Fragment::Nop)
+                }
+
+                // Choosing between options that all do nothing does nothing
+                Fragment::NonTerminal(options)
+                    if !options.is_empty() && options.iter().all(is_nop) =>
+                {
+                    Some(Fragment::Nop)
+                }
+                _ => None,
+            };
+
+            if let Some(replacement) = replacement {
+                gram.fragments[idx] = replacement;
+                changes += 1;
+            }
+        }
+        changes
+    }
+}
+
+/// Redirects references to fragments that expand to exactly one other
+/// fragment (non-terminals with one option, expressions with one element and
+/// repetitions of exactly one iteration) to that fragment
+pub struct Forward;
+
+impl Pass for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        let forward = |fragment: &Fragment| match fragment {
+            Fragment::NonTerminal(options) if options.len() == 1 => Some(options[0]),
+            Fragment::Expression(expr) if expr.len() == 1 => Some(expr[0]),
+            &Fragment::Repeat {
+                fragment,
+                min: 1,
+                max: 1,
+            } => Some(fragment),
+            _ => None,
+        };
+
+        // Follow chains of forwarded fragments to the fragment that actually
+        // does something. Cycles of forwarding fragments are left alone.
+        let target: Vec<FragmentId> = (0..gram.fragments.len())
+            .map(|idx| {
+                let mut current = FragmentId(idx);
+                let mut steps = 0;
+                while let Some(next) = forward(&gram.fragments[current.0]) {
+                    current = next;
+                    steps += 1;
+                    if steps > gram.fragments.len() {
+                        return FragmentId(idx);
+                    }
+                }
+                current
+            })
+            .collect();
+
+        redirect(gram, |id| target[id.0])
+    }
+}
+
+/// Flattens nested expressions into their parent expression, as expanding
+/// them in order is the same as expanding their elements in order
+pub struct Flatten;
+
+impl Pass for Flatten {
+    fn name(&self) -> &'static str {
+        "flatten"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        let mut changes = 0;
+        for idx in 0..gram.fragments.len() {
+            // Only expressions without nested expressions of their own are
+            // flattened, which makes sure recursive expressions terminate
+            let is_flat = |id: &FragmentId| match &gram.fragments[id.0] {
+                Fragment::Expression(inner) => inner
+                    .iter()
+                    .all(|x| !matches!(gram.fragments[x.0], Fragment::Expression(_))),
+                _ => false,
+            };
+
+            let flattened = match &gram.fragments[idx] {
+                Fragment::Expression(expr) if expr.iter().any(is_flat) => {
+                    let mut flattened = Vec::new();
+                    for x in expr.iter() {
+                        match &gram.fragments[x.0] {
+                            Fragment::Expression(inner) if is_flat(x) => {
+                                flattened.extend(inner.iter().copied());
+                                changes += 1;
+                            }
+                            _ => flattened.push(*x),
+                        }
+                    }
+                    flattened
+                }
+                _ => continue,
+            };
+            gram.fragments[idx] = Fragment::Expression(flattened);
+        }
+        changes
+    }
+}
+
+/// Merges runs of adjacent terminals in expressions into a single terminal,
+/// so they are appended to the output at once
+pub struct MergeTerminals;
+
+impl Pass for MergeTerminals {
+    fn name(&self) -> &'static str {
+        "merge-terminals"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        let mut changes = 0;
+        for idx in 0..gram.fragments.len() {
+            let terminal = |id: FragmentId| match &gram.fragments[id.0] {
+                Fragment::Terminal(data) => Some(&data[..]),
+                _ => None,
+            };
+
+            // Group the elements into runs of terminals and single other
+            // fragments, together with the value of the run
+            let mut runs: Vec<(Vec<FragmentId>, Vec<u8>)> = Vec::new();
+            let expr = match &gram.fragments[idx] {
+                Fragment::Expression(expr) => expr,
+                _ => continue,
+            };
+            for &x in expr.iter() {
+                match (runs.last_mut(), terminal(x)) {
+                    (Some((run, value)), Some(data)) if terminal(run[0]).is_some() => {
+                        run.push(x);
+                        value.extend_from_slice(data);
+                    }
+                    (_, data) => runs.push((vec![x], data.unwrap_or_default().to_vec())),
+                }
+            }
+            if runs.len() == expr.len() {
+                continue;
+            }
+
+            let mut merged = Vec::new();
+            for (run, value) in runs {
+                if run.len() == 1 {
+                    merged.push(run[0]);
+                    continue;
+                }
+
+                let label = format!("{}_cat{}", gram.labels[idx], merged.len());
+                let origin = gram.origins[idx].clone();
+                merged.push(gram.allocate_labeled(Fragment::Terminal(value), label, origin));
+                changes += 1;
+            }
+            gram.fragments[idx] = Fragment::Expression(merged);
+        }
+        changes
+    }
+}
+
+/// Replaces all references to structurally identical fragments with
+/// references to the first of them.
+///
+/// Fragments are partitioned into classes of identical fragments by
+/// splitting classes until all members of a class refer to the same classes.
+/// Unlike comparing fragments bottom-up, this also merges identical recursive
+/// rules.
+pub struct Deduplicate;
+
+impl Pass for Deduplicate {
+    fn name(&self) -> &'static str {
+        "deduplicate"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        // Replace all references of a fragment with the given ids
+        let remap = |fragment: &Fragment, ids: &[usize]| {
+            let mut fragment = fragment.clone();
+            for id in fragment.children_mut() {
+                *id = FragmentId(ids[id.0]);
+            }
+            fragment
+        };
+
+        // Start with all fragments in one class and split classes until the
+        // number of classes no longer changes
+        let mut class = vec![0; gram.fragments.len()];
+        let mut class_count = 1;
+        loop {
+            let mut classes = HashMap::new();
+            let new_class: Vec<usize> = gram
+                .fragments
+                .iter()
+                .enumerate()
+                .map(|(idx, fragment)| {
+                    let key = (class[idx], remap(fragment, &class));
+                    let next = classes.len();
+                    *classes.entry(key).or_insert(next)
+                })
+                .collect();
+
+            class = new_class;
+            if classes.len() == class_count {
+                break;
+            }
+            class_count = classes.len();
+        }
+
+        // The first fragment of every class represents all of them
+        let mut representative = vec![usize::MAX; class_count];
+        for (idx, &c) in class.iter().enumerate() {
+            representative[c] = representative[c].min(idx);
+        }
+        // Removed fragments are all identical, but must not be merged with
+        // anything
+        let unreachable: Vec<bool> = gram
+            .fragments
+            .iter()
+            .map(|fragment| matches!(fragment, Fragment::Unreachable))
+            .collect();
+        redirect(gram, |id| {
+            if unreachable[id.0] {
+                id
+            } else {
+                FragmentId(representative[class[id.0]])
+            }
+        })
+    }
+}
+
+/// Replaces all fragments that can not be reached from the start node or one
+/// of the extra roots with `Unreachable`, so no code is generated for them
+pub struct RemoveUnreachable;
+
+impl Pass for RemoveUnreachable {
+    fn name(&self) -> &'static str {
+        "remove-unreachable"
+    }
+
+    fn run(&self, gram: &mut GrammarRust) -> usize {
+        let mut reachable = vec![false; gram.fragments.len()];
+        let mut worklist = vec![gram.start.unwrap()];
+        worklist.extend(gram.roots.iter().map(|name| gram.name_to_fragment[name]));
+        while let Some(id) = worklist.pop() {
+            if reachable[id.0] {
+                continue;
+            }
+            reachable[id.0] = true;
+            worklist.extend_from_slice(gram.fragments[id.0].children());
+        }
+
+        let mut changes = 0;
+        for (fragment, reachable) in gram.fragments.iter_mut().zip(reachable) {
+            if !reachable && !matches!(fragment, Fragment::Unreachable) {
+                *fragment = Fragment::Unreachable;
+                changes += 1;
+            }
+        }
+        changes
+    }
+}
+
+/// Remove all `Unreachable` fragments from the fragment list, renumbering
+/// the remaining fragments in order. Rules that are no longer reachable are
+/// removed from the rule names.
+pub(crate) fn compact(gram: &mut GrammarRust) -> IdRemap {
+    let mut remap = vec![None; gram.fragments.len()];
+    let mut next = 0;
+    for (idx, fragment) in gram.fragments.iter().enumerate() {
+        if !matches!(fragment, Fragment::Unreachable) {
+            remap[idx] = Some(FragmentId(next));
+            next += 1;
+        }
+    }
+
+    // Replaced fragments map to the fragment that replaced them, following
+    // chains of replacements
+    for idx in 0..remap.len() {
+        let mut current = idx;
+        let mut steps = 0;
+        while remap[current].is_none() && steps <= remap.len() {
+            match gram.redirects.get(&current) {
+                Some(target) => current = target.0,
+                None => break,
+            }
+            steps += 1;
+        }
+        remap[idx] = remap[current];
+    }
+
+    let fragments = std::mem::take(&mut gram.fragments);
+    let labels = std::mem::take(&mut gram.labels);
+    let origins = std::mem::take(&mut gram.origins);
+    for ((mut fragment, label), origin) in fragments.into_iter().zip(labels).zip(origins) {
+        if matches!(fragment, Fragment::Unreachable) {
+            continue;
+        }
+        for id in fragment.children_mut() {
+            *id = remap[id.0].unwrap();
+        }
+        gram.fragments.push(fragment);
+        gram.labels.push(label);
+        gram.origins.push(origin);
+    }
+
+    gram.name_to_fragment = std::mem::take(&mut gram.name_to_fragment)
+        .into_iter()
+        .filter_map(|(name, id)| Some((name, remap[id.0]?)))
+        .collect();
+    gram.start = gram.start.map(|id| remap[id.0].unwrap());
+    gram.redirects.clear();
+
+    IdRemap(remap)
+}
+
+/// An input for which the original and the optimized grammar disagree
+#[derive(Debug)]
+pub struct VerifyError {
+    /// Seed of the `StdRng` the input was generated with
+    pub seed: u64,
+
+    /// The generated input
+    pub input: Vec<u8>,
+
+    /// Which grammar generated the input that the other one rejected
+    pub message: String,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "seed {}: {} ({:?})",
+            self.seed,
+            self.message,
+            String::from_utf8_lossy(&self.input)
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Check that `original` and `optimized` generate the same language by
+/// generating inputs from both grammars with `samples` seeds and parsing them
+/// with the respective other grammar. Inputs that were cut short by
+/// `max_depth` are skipped, as they are not part of the language, and so are
+/// inputs longer than `MAX_VERIFY_LEN`. Returns the number of inputs that
+/// were checked.
+pub fn verify(
+    original: &GrammarRust,
+    optimized: &GrammarRust,
+    samples: u64,
+    max_depth: usize,
+) -> Result<usize, VerifyError> {
+    let grammars = [
+        (
+            original,
+            optimized,
+            "generated by the original grammar but rejected by the optimized one",
+        ),
+        (
+            optimized,
+            original,
+            "generated by the optimized grammar but rejected by the original one",
+        ),
+    ];
+
+    let mut checked = 0;
+    for (generator, parser, message) in grammars.iter() {
+        let recognizer = Recognizer::new(parser);
+
+        let mut input = Vec::new();
+        for seed in 0..samples {
+            let mut rng = StdRng::seed_from_u64(seed);
+            if !generator.generate_complete(&mut input, max_depth, &mut rng)
+                || input.len() > MAX_VERIFY_LEN
+            {
+                continue;
+            }
+
+            if !recognizer.accepts(&input) {
+                return Err(VerifyError {
+                    seed,
+                    input,
+                    message: message.to_string(),
+                });
+            }
+            checked += 1;
+        }
+    }
+
+    Ok(checked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    fn grammar(json: &str) -> GrammarRust {
+        let grammar: Grammar = serde_json::from_str(json).unwrap();
+        GrammarRust::new(&grammar, None)
+    }
+
+    #[test]
+    fn forwarding_keeps_the_language() {
+        // `<a>` forwards to `<b>`, which is only optimized after `<a>`
+        let original = grammar(
+            r#"{"<start>": [["<a>", "<a>"]], "<a>": [["<b>"]], "<b>": [["<c>"]], "<c>": [["x"], ["y", "<c>"], ["<empty>"]], "<empty>": [[]]}"#,
+        );
+        let mut optimized = original.clone();
+        let stats = optimized.optimize();
+
+        assert!(stats.passes["forward"] > 0);
+        assert!(verify(&original, &optimized, 100, 32).unwrap() > 0);
+    }
+
+    #[test]
+    fn compaction_remaps_ids() {
+        let mut gram = grammar(
+            r#"{"<start>": [["<a>", "<b>"]], "<a>": [["<digit>"]], "<b>": [["<digit>", "<digit>"]], "<digit>": [["0"], ["1"]], "<unused>": [["x"]]}"#,
+        );
+        let before = gram.fragments.len();
+        let digit = gram.fragment_id("<digit>").unwrap();
+        let a = gram.fragment_id("<a>").unwrap();
+        let unused = gram.fragment_id("<unused>").unwrap();
+
+        let stats = gram.optimize();
+        assert_eq!(stats.remap.len(), before);
+        assert_eq!(gram.fragments.len(), stats.fragments_after);
+        assert!(!gram
+            .fragments
+            .iter()
+            .any(|fragment| matches!(fragment, Fragment::Unreachable)));
+
+        // `<a>` only forwards to `<digit>`, and `<unused>` is gone
+        let new_digit = stats.remap.get(digit).unwrap();
+        assert_eq!(gram.fragment_id("<digit>"), Some(new_digit));
+        assert_eq!(stats.remap.get(a), Some(new_digit));
+        assert_eq!(stats.remap.get(unused), None);
+        assert_eq!(gram.fragment_id("<unused>"), None);
+    }
+
+    #[test]
+    fn choices_between_nops_are_removed() {
+        let mut gram = grammar(
+            r#"{"<start>": [["x", "<nothing>", "y"]], "<nothing>": [["<empty>"], ["<empty>", "<empty>"]], "<empty>": [[]]}"#,
+        );
+        gram.optimize();
+
+        match &gram.fragments[gram.start.unwrap().0] {
+            Fragment::Terminal(value) => assert_eq!(value, b"xy"),
+            other => panic!("start was not reduced to a terminal: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verifier_detects_changed_language() {
+        struct Break;
+
+        impl Pass for Break {
+            fn name(&self) -> &'static str {
+                "break"
+            }
+
+            fn run(&self, gram: &mut GrammarRust) -> usize {
+                for fragment in gram.fragments.iter_mut() {
+                    if let Fragment::Terminal(value) = fragment {
+                        if value == b"y" {
+                            *value = b"z".to_vec();
+                            return 1;
+                        }
+                    }
+                }
+                0
+            }
+        }
+
+        let original = grammar(r#"{"<start>": [["x"], ["y"]]}"#);
+        let mut broken = original.clone();
+        PassManager::new().add(Break).run(&mut broken);
+
+        assert!(verify(&original, &broken, 100, 32).is_err());
+    }
+}
@@ -0,0 +1,525 @@
+//! An Earley recognizer for the language generated by a `GrammarRust`.
+//!
+//! Every fragment becomes a rule of a context-free grammar over bytes. This
+//! is used to check that optimizations do not change the language of a
+//! grammar, by parsing inputs generated by one version of a grammar with the
+//! other, and to parse inputs into derivation trees for reducing them.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// A symbol on the right hand side of a rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Symbol {
+    /// A single byte of the input
+    Byte(u8),
+
+    /// The rule with the given index
+    Rule(usize),
+}
+
+/// The right hand side of a rule
+#[derive(Clone, Debug)]
+enum Rule {
+    /// Alternatives of symbol sequences
+    Alternatives(Vec<Vec<Symbol>>),
+
+    /// Between `min` and `max` iterations of `rule`. The dot of an item
+    /// counts the iterations, leaving out empty ones, so `min` is 0 if `rule`
+    /// is nullable. The number of items thus stays bounded by the input
+    /// length, whatever the bounds are.
+    Repeat { rule: usize, min: usize, max: usize },
+}
+
+impl Rule {
+    fn alternatives(&self) -> usize {
+        match self {
+            Rule::Alternatives(alternatives) => alternatives.len(),
+            Rule::Repeat { .. } => 1,
+        }
+    }
+
+    /// The symbol after the dot of `item`, if any
+    fn next(&self, item: &Item) -> Option<Symbol> {
+        match *self {
+            Rule::Alternatives(ref alternatives) => alternatives[item.alt].get(item.dot).copied(),
+            Rule::Repeat { rule, max, .. } => (item.dot < max).then_some(Symbol::Rule(rule)),
+        }
+    }
+
+    /// The symbol before the dot of `item`, which must not be at the start
+    fn before(&self, item: &Item) -> Symbol {
+        match *self {
+            Rule::Alternatives(ref alternatives) => alternatives[item.alt][item.dot - 1],
+            Rule::Repeat { rule, .. } => Symbol::Rule(rule),
+        }
+    }
+
+    /// Whether `item` recognized a complete derivation of the rule. Items of
+    /// a repetition may be complete and still take more iterations.
+    fn is_complete(&self, item: &Item) -> bool {
+        match *self {
+            Rule::Alternatives(ref alternatives) => item.dot == alternatives[item.alt].len(),
+            Rule::Repeat { min, .. } => item.dot >= min,
+        }
+    }
+}
+
+/// A partially recognized alternative of a rule, which started at input
+/// position `origin`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Item {
+    rule: usize,
+    alt: usize,
+    dot: usize,
+    origin: usize,
+}
+
+/// Recognizes the inputs a grammar can generate
+pub(crate) struct Recognizer {
+    /// The rule of every fragment, with the same index
+    rules: Vec<Rule>,
+
+    /// Whether a rule can derive the empty input
+    nullable: Vec<bool>,
+
+    /// Rule of the start node
+    start: usize,
+}
+
+impl Recognizer {
+    /// Create a recognizer for the language of `gram`, ignoring any depth
+    /// limit
+    pub(crate) fn new(gram: &GrammarRust) -> Self {
+        let mut rules: Vec<Rule> = gram
+            .fragments
+            .iter()
+            .map(|fragment| match fragment {
+                Fragment::NonTerminal(options) => Rule::Alternatives(
+                    options
+                        .iter()
+                        .map(|option| vec![Symbol::Rule(option.0)])
+                        .collect(),
+                ),
+                Fragment::Expression(expr) => {
+                    Rule::Alternatives(vec![expr.iter().map(|x| Symbol::Rule(x.0)).collect()])
+                }
+                Fragment::Terminal(value) => {
+                    Rule::Alternatives(vec![value.iter().map(|&b| Symbol::Byte(b)).collect()])
+                }
+                &Fragment::Repeat { fragment, min, max } => Rule::Repeat {
+                    rule: fragment.0,
+                    min,
+                    max,
+                },
+                Fragment::Nop => Rule::Alternatives(vec![vec![]]),
+                Fragment::Unreachable => Rule::Alternatives(vec![]),
+            })
+            .collect();
+
+        // Find the nullable rules by iterating to a fixed point
+        let mut nullable = vec![false; rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, rule) in rules.iter().enumerate() {
+                if nullable[idx] {
+                    continue;
+                }
+                let is_nullable = match *rule {
+                    Rule::Alternatives(ref alternatives) => alternatives.iter().any(|alt| {
+                        alt.iter().all(|symbol| match symbol {
+                            Symbol::Byte(_) => false,
+                            Symbol::Rule(rule) => nullable[*rule],
+                        })
+                    }),
+                    Rule::Repeat { rule, min, .. } => min == 0 || nullable[rule],
+                };
+                if is_nullable {
+                    nullable[idx] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        // Empty iterations are not counted, they can make up any missing ones
+        for rule in rules.iter_mut() {
+            if let Rule::Repeat { rule, min, .. } = rule {
+                if nullable[*rule] {
+                    *min = 0;
+                }
+            }
+        }
+
+        Self {
+            rules,
+            nullable,
+            start: gram.start.unwrap().0,
+        }
+    }
+
+    /// Check if the grammar can generate `input`
+    pub(crate) fn accepts(&self, input: &[u8]) -> bool {
+        let chart = self.chart(input);
+        chart.sets[input.len()].iter().any(|item| {
+            item.rule == self.start && item.origin == 0 && self.rules[item.rule].is_complete(item)
+        })
+    }
+
+    /// Parse `input` into a derivation tree of the fragments of the grammar,
+    /// or `None` if the grammar can not generate it. Ambiguous inputs get
+    /// any of their derivation trees.
+    pub(crate) fn parse(&self, input: &[u8]) -> Option<Tree> {
+        let chart = self.chart(input);
+        let mut parser = TreeBuilder {
+            rules: &self.rules,
+            chart: &chart,
+            input,
+            visiting: HashSet::new(),
+            cut: false,
+            failed: HashSet::new(),
+            failed_prefixes: HashSet::new(),
+        };
+        let tree = parser.derive(self.start, 0, input.len())?;
+        Some(Self::fragment_tree(tree))
+    }
+
+    /// Turn a derivation tree of the rules into one of the fragments
+    fn fragment_tree(tree: RuleTree) -> Tree {
+        // The bytes of a terminal are part of the fragment itself
+        let children = tree
+            .children
+            .into_iter()
+            .filter_map(|child| match child {
+                RuleChild::Byte => None,
+                RuleChild::Rule(child) => Some(Self::fragment_tree(child)),
+            })
+            .collect();
+
+        Tree {
+            fragment: FragmentId(tree.rule),
+            children,
+        }
+    }
+
+    /// Run the Earley algorithm on `input`
+    fn chart(&self, input: &[u8]) -> Chart {
+        let mut chart = Chart::new(input.len());
+        for alt in 0..self.rules[self.start].alternatives() {
+            chart.add(
+                0,
+                Item {
+                    rule: self.start,
+                    alt,
+                    dot: 0,
+                    origin: 0,
+                },
+                &self.rules,
+            );
+        }
+
+        for pos in 0..=input.len() {
+            let mut idx = 0;
+            while idx < chart.sets[pos].len() {
+                let item = chart.sets[pos][idx];
+                idx += 1;
+
+                let advanced = Item {
+                    dot: item.dot + 1,
+                    ..item
+                };
+                let rule = &self.rules[item.rule];
+
+                // Completed the rule, advance all items that were waiting
+                // for it, except repetitions on an empty iteration
+                if rule.is_complete(&item) {
+                    let waiting = chart.waiting[item.origin]
+                        .get(&item.rule)
+                        .map_or(0, Vec::len);
+                    for k in 0..waiting {
+                        let parent = chart.waiting[item.origin][&item.rule][k];
+                        if item.origin == pos && self.is_repeat(parent.rule) {
+                            continue;
+                        }
+                        chart.add(
+                            pos,
+                            Item {
+                                dot: parent.dot + 1,
+                                ..parent
+                            },
+                            &self.rules,
+                        );
+                    }
+                }
+
+                match rule.next(&item) {
+                    Some(Symbol::Byte(byte)) if input.get(pos) == Some(&byte) => {
+                        chart.add(pos + 1, advanced, &self.rules);
+                    }
+                    Some(Symbol::Rule(rule)) => {
+                        for alt in 0..self.rules[rule].alternatives() {
+                            chart.add(
+                                pos,
+                                Item {
+                                    rule,
+                                    alt,
+                                    dot: 0,
+                                    origin: pos,
+                                },
+                                &self.rules,
+                            );
+                        }
+
+                        // A nullable rule may complete without consuming
+                        // input, possibly before this item was added
+                        if self.nullable[rule] && !self.is_repeat(item.rule) {
+                            chart.add(pos, advanced, &self.rules);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        chart
+    }
+
+    fn is_repeat(&self, rule: usize) -> bool {
+        matches!(self.rules[rule], Rule::Repeat { .. })
+    }
+}
+
+/// A derivation tree of the fragments of a grammar. A repetition has one
+/// child per iteration, a choice the option that was chosen. Empty
+/// iterations of a fragment that can derive the empty input may be left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Tree {
+    pub(crate) fragment: FragmentId,
+    pub(crate) children: Vec<Tree>,
+}
+
+impl Tree {
+    /// Append the input this tree derives to `out`
+    pub(crate) fn write(&self, gram: &GrammarRust, out: &mut Vec<u8>) {
+        if let Fragment::Terminal(value) = &gram.fragments[self.fragment.0] {
+            out.extend_from_slice(value);
+        }
+        for child in self.children.iter() {
+            child.write(gram, out);
+        }
+    }
+}
+
+/// A derivation tree of the rules of a `Recognizer`
+struct RuleTree {
+    rule: usize,
+    children: Vec<RuleChild>,
+}
+
+enum RuleChild {
+    Byte,
+    Rule(RuleTree),
+}
+
+/// Extracts a derivation tree from a completed chart
+struct TreeBuilder<'a> {
+    rules: &'a [Rule],
+    chart: &'a Chart,
+    input: &'a [u8],
+
+    /// Rules and spans being derived, which must not be derived again below
+    /// themselves, or cyclic rules would recurse forever
+    visiting: HashSet<(usize, usize, usize)>,
+
+    /// Whether a derivation was refused because of `visiting` since the
+    /// current derivation started. Failures that do not depend on it can
+    /// not succeed elsewhere either.
+    cut: bool,
+
+    /// Rules and spans that can not be derived
+    failed: HashSet<(usize, usize, usize)>,
+
+    /// Items whose symbols before the dot can not be derived up to a
+    /// position
+    failed_prefixes: HashSet<(Item, usize)>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    /// Derive `rule` for the input from `start` to `end`
+    fn derive(&mut self, rule: usize, start: usize, end: usize) -> Option<RuleTree> {
+        if self.failed.contains(&(rule, start, end)) {
+            return None;
+        }
+        if !self.visiting.insert((rule, start, end)) {
+            self.cut = true;
+            return None;
+        }
+        let outer_cut = std::mem::replace(&mut self.cut, false);
+        // The complete items of the rule, trying more iterations of a
+        // repetition first. There are no more than one per input byte.
+        let complete: Vec<Item> = match self.rules[rule] {
+            Rule::Alternatives(ref alternatives) => alternatives
+                .iter()
+                .enumerate()
+                .map(|(alt, symbols)| Item {
+                    rule,
+                    alt,
+                    dot: symbols.len(),
+                    origin: start,
+                })
+                .collect(),
+            Rule::Repeat { min, max, .. } => (min..=max.min(end - start))
+                .rev()
+                .map(|dot| Item {
+                    rule,
+                    alt: 0,
+                    dot,
+                    origin: start,
+                })
+                .collect(),
+        };
+
+        let mut tree = None;
+        for item in complete {
+            if !self.chart.seen[end].contains(&item) {
+                continue;
+            }
+            let mut children = Vec::new();
+            if self.derive_prefix(item, end, &mut children) {
+                children.reverse();
+                tree = Some(RuleTree { rule, children });
+                break;
+            }
+        }
+        self.visiting.remove(&(rule, start, end));
+        if tree.is_none() && !self.cut {
+            self.failed.insert((rule, start, end));
+        }
+        self.cut |= outer_cut;
+        tree
+    }
+
+    /// Derive the symbols before the dot of `item`, which is in the set at
+    /// `end`, pushing their trees from the last to the first
+    fn derive_prefix(&mut self, item: Item, end: usize, children: &mut Vec<RuleChild>) -> bool {
+        if item.dot == 0 {
+            return end == item.origin;
+        }
+        if self.failed_prefixes.contains(&(item, end)) {
+            return false;
+        }
+        let outer_cut = std::mem::replace(&mut self.cut, false);
+        let derived = self.derive_symbol(item, end, children);
+        if !derived && !self.cut {
+            self.failed_prefixes.insert((item, end));
+        }
+        self.cut |= outer_cut;
+        derived
+    }
+
+    /// Derive the symbol before the dot of `item` and the ones before it,
+    /// for `derive_prefix`
+    fn derive_symbol(&mut self, item: Item, end: usize, children: &mut Vec<RuleChild>) -> bool {
+        let before = Item {
+            dot: item.dot - 1,
+            ..item
+        };
+        match self.rules[item.rule].before(&item) {
+            Symbol::Byte(byte) => {
+                end > item.origin
+                    && self.input[end - 1] == byte
+                    && self.chart.seen[end - 1].contains(&before)
+                    && {
+                        children.push(RuleChild::Byte);
+                        self.derive_prefix(before, end - 1, children)
+                    }
+            }
+            Symbol::Rule(rule) => {
+                for split in (item.origin..=end).rev() {
+                    if !self.chart.seen[split].contains(&before) {
+                        continue;
+                    }
+                    let tree = match self.derive(rule, split, end) {
+                        Some(tree) => tree,
+                        None => continue,
+                    };
+                    let len = children.len();
+                    children.push(RuleChild::Rule(tree));
+                    if self.derive_prefix(before, split, children) {
+                        return true;
+                    }
+                    children.truncate(len);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// The Earley sets for every position of the input
+struct Chart {
+    /// Items in the order they were added
+    sets: Vec<Vec<Item>>,
+
+    /// Items already present in a set
+    seen: Vec<HashSet<Item>>,
+
+    /// Items of a set that wait for a rule to be completed
+    waiting: Vec<HashMap<usize, Vec<Item>>>,
+}
+
+impl Chart {
+    fn new(len: usize) -> Self {
+        Self {
+            sets: vec![Vec::new(); len + 1],
+            seen: vec![HashSet::new(); len + 1],
+            waiting: vec![HashMap::new(); len + 1],
+        }
+    }
+
+    fn add(&mut self, pos: usize, item: Item, rules: &[Rule]) {
+        if !self.seen[pos].insert(item) {
+            return;
+        }
+        if let Some(Symbol::Rule(rule)) = rules[item.rule].next(&item) {
+            self.waiting[pos].entry(rule).or_default().push(item);
+        }
+        self.sets[pos].push(item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    #[test]
+    fn large_repetition_bounds() {
+        // Used to create one rule per optional iteration
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<item>{2,4000000000}", "<pad>{3000000000,4000000000}", "<item>{3}"]], "<item>": [["a"], ["bc"]], "<pad>": [[""], ["-"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+        let recognizer = Recognizer::new(&gram);
+
+        for input in [&b"aabcaaa"[..], b"bca--abca", b"aa-aaa"] {
+            assert!(recognizer.accepts(input), "{:?}", input);
+        }
+        for input in [&b"aaaa"[..], b"aaa-aa", b"aabca", b"aa-b"] {
+            assert!(!recognizer.accepts(input), "{:?}", input);
+        }
+
+        // Empty iterations are left out of the tree
+        let mut tree = recognizer.parse(b"bca--abca").unwrap();
+        let mut out = Vec::new();
+        tree.write(&gram, &mut out);
+        assert_eq!(out, b"bca--abca");
+        while tree.children.len() == 1 {
+            tree = tree.children.remove(0);
+        }
+        let iterations: Vec<usize> = tree.children.iter().map(|x| x.children.len()).collect();
+        assert_eq!(iterations, [2, 2, 3]);
+    }
+}
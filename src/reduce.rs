@@ -0,0 +1,281 @@
+//! Reducing inputs along their derivation tree.
+//!
+//! An input that still has to be interesting (e.g. crash a target) is parsed
+//! into a derivation tree, whose subtrees are then replaced with smaller
+//! derivations of the same fragment: the smallest one the grammar has, a
+//! smaller subtree below them, or, for repetitions, fewer iterations. Every
+//! reduced input is generated by the grammar again.
+
+use std::collections::HashSet;
+
+use crate::parse::{Recognizer, Tree};
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// The result of `reduce`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reduction {
+    /// The smallest interesting input that was found
+    pub input: Vec<u8>,
+
+    /// Number of candidates that were tested
+    pub tests: usize,
+}
+
+/// Reduce `input` to a smaller input of the grammar for which
+/// `interesting` still returns `true`. `input` itself is expected to be
+/// interesting. Returns `None` if the grammar can not generate `input`.
+///
+/// Reductions closer to the root are tried first, and after every successful
+/// one the tree is searched again, until no candidate is interesting.
+pub fn reduce(
+    gram: &GrammarRust,
+    input: &[u8],
+    mut interesting: impl FnMut(&[u8]) -> bool,
+) -> Option<Reduction> {
+    let mut tree = Recognizer::new(gram).parse(input)?;
+    let minimal = Minimal::new(gram);
+    let mut tested: HashSet<Vec<u8>> = HashSet::new();
+    let mut best = input.to_vec();
+
+    'search: loop {
+        let mut paths = Vec::new();
+        collect_paths(&tree, &mut Vec::new(), &mut paths);
+        for path in paths.iter() {
+            for candidate in candidates(gram, &minimal, &tree, path) {
+                let mut output = Vec::new();
+                candidate.write(gram, &mut output);
+                if output.len() >= best.len() || !tested.insert(output.clone()) {
+                    continue;
+                }
+                if interesting(&output) {
+                    tree = candidate;
+                    best = output;
+                    continue 'search;
+                }
+            }
+        }
+        break;
+    }
+
+    Some(Reduction {
+        input: best,
+        tests: tested.len(),
+    })
+}
+
+/// Paths from the root to every inner node of a tree, in pre-order
+fn collect_paths(tree: &Tree, path: &mut Vec<usize>, paths: &mut Vec<Vec<usize>>) {
+    if tree.children.is_empty() {
+        return;
+    }
+    paths.push(path.clone());
+    for (idx, child) in tree.children.iter().enumerate() {
+        path.push(idx);
+        collect_paths(child, path, paths);
+        path.pop();
+    }
+}
+
+fn node_mut<'a>(tree: &'a mut Tree, path: &[usize]) -> &'a mut Tree {
+    path.iter().fold(tree, |node, &idx| &mut node.children[idx])
+}
+
+/// Trees with the node at `path` replaced by smaller derivations of the same
+/// fragment, most promising first
+fn candidates(gram: &GrammarRust, minimal: &Minimal, tree: &Tree, path: &[usize]) -> Vec<Tree> {
+    let mut root = tree.clone();
+    let node = node_mut(&mut root, path).clone();
+    let mut replacements = vec![minimal.tree(gram, node.fragment)];
+
+    // Subtrees of the same fragment, the smallest first
+    let mut below = Vec::new();
+    for child in node.children.iter() {
+        collect_fragment(child, node.fragment, &mut below);
+    }
+    below.sort_by_key(|tree| tree_len(gram, tree));
+    replacements.extend(below);
+
+    // Fewer iterations of a repetition, removing large chunks first
+    if let Fragment::Repeat { fragment, min, .. } = gram.fragments[node.fragment.0] {
+        let min = minimal.iterations(fragment, min);
+        let count = node.children.len();
+        let mut chunk = count.saturating_sub(min);
+        while chunk > 0 {
+            for start in (0..=count - chunk).step_by(chunk) {
+                let mut fewer = node.clone();
+                fewer.children.drain(start..start + chunk);
+                replacements.push(fewer);
+            }
+            chunk /= 2;
+        }
+    }
+
+    replacements
+        .into_iter()
+        .map(|replacement| {
+            let mut candidate = root.clone();
+            *node_mut(&mut candidate, path) = replacement;
+            candidate
+        })
+        .collect()
+}
+
+/// All subtrees of `tree` derived from `fragment`
+fn collect_fragment(tree: &Tree, fragment: FragmentId, found: &mut Vec<Tree>) {
+    if tree.fragment == fragment {
+        found.push(tree.clone());
+    }
+    for child in tree.children.iter() {
+        collect_fragment(child, fragment, found);
+    }
+}
+
+fn tree_len(gram: &GrammarRust, tree: &Tree) -> usize {
+    let mut out = Vec::new();
+    tree.write(gram, &mut out);
+    out.len()
+}
+
+/// The shortest derivation of every fragment
+struct Minimal {
+    /// Length of the shortest input of every fragment, `None` if it can not
+    /// generate anything
+    len: Vec<Option<usize>>,
+
+    /// The option of a choice its shortest input is derived from
+    best: Vec<usize>,
+}
+
+impl Minimal {
+    fn new(gram: &GrammarRust) -> Self {
+        // The best option is only updated when a fragment gets strictly
+        // shorter, which can not form a cycle
+        let mut len: Vec<Option<usize>> = vec![None; gram.fragments.len()];
+        let mut best = vec![0; gram.fragments.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, fragment) in gram.fragments.iter().enumerate() {
+                let (new_len, new_best) = match fragment {
+                    Fragment::NonTerminal(options) => options
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(option_id, option)| Some((len[option.0]?, option_id)))
+                        .min()
+                        .map_or((None, 0), |(len, option_id)| (Some(len), option_id)),
+                    Fragment::Expression(expr) => (
+                        expr.iter()
+                            .try_fold(0, |acc, exp| len[exp.0].map(|len| acc + len)),
+                        0,
+                    ),
+                    Fragment::Repeat { fragment, min, .. } => (
+                        if *min == 0 {
+                            Some(0)
+                        } else {
+                            len[fragment.0].map(|len| len.saturating_mul(*min))
+                        },
+                        0,
+                    ),
+                    Fragment::Terminal(value) => (Some(value.len()), 0),
+                    Fragment::Nop => (Some(0), 0),
+                    Fragment::Unreachable => (None, 0),
+                };
+                if new_len.is_some() && (len[idx].is_none() || new_len < len[idx]) {
+                    len[idx] = new_len;
+                    best[idx] = new_best;
+                    changed = true;
+                }
+            }
+        }
+
+        Self { len, best }
+    }
+
+    /// Number of iterations a repetition of `fragment` needs in a tree,
+    /// where empty ones can be left out
+    fn iterations(&self, fragment: FragmentId, min: usize) -> usize {
+        if self.len[fragment.0] == Some(0) {
+            0
+        } else {
+            min
+        }
+    }
+
+    /// The shortest derivation tree of a fragment
+    fn tree(&self, gram: &GrammarRust, fragment: FragmentId) -> Tree {
+        debug_assert!(self.len[fragment.0].is_some());
+        let children = match &gram.fragments[fragment.0] {
+            Fragment::NonTerminal(options) => {
+                vec![self.tree(gram, options[self.best[fragment.0]])]
+            }
+            Fragment::Expression(expr) => expr.iter().map(|&exp| self.tree(gram, exp)).collect(),
+            &Fragment::Repeat { fragment, min, .. } => (0..self.iterations(fragment, min))
+                .map(|_| self.tree(gram, fragment))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Tree { fragment, children }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+
+    #[test]
+    fn reduces_to_the_interesting_part() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<list>"]], "<list>": [["[", "<item>{0,8}", "]"]], "<item>": [["<list>"], ["<word>", ","]], "<word>": [["a"], ["crash"], ["b", "<word>"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let input = b"[bba,[[a,][bcrash,ba,]bba,]a,]";
+        let reduction = reduce(&gram, input, |input| {
+            input.windows(5).any(|window| window == b"crash")
+        })
+        .unwrap();
+        assert_eq!(reduction.input, b"[crash,]");
+        assert!(reduction.tests > 0);
+
+        // Inputs the grammar can not generate are not reduced
+        assert!(reduce(&gram, b"[crash]", |_| true).is_none());
+    }
+
+    #[test]
+    fn removes_iterations_of_ambiguous_inputs() {
+        // Runs of `a` can be split into items in many ways
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<item>{0,200}"]], "<item>": [["a"], ["aa"], ["b"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let mut input = vec![b'a'; 60];
+        input.push(b'b');
+        input.extend_from_slice(&[b'a'; 60]);
+        let reduction = reduce(&gram, &input, |input| {
+            input.windows(2).any(|window| window == b"ab")
+        })
+        .unwrap();
+        assert_eq!(reduction.input, b"ab");
+    }
+
+    #[test]
+    fn large_repetition_bounds() {
+        // Neither parsing nor the minimal trees depend on the bounds
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<item>{2,4000000000}", "<pad>{3000000000,4000000000}"]], "<item>": [["a"], ["crash"]], "<pad>": [[""], ["-"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+
+        let reduction = reduce(&gram, b"aacrasha--", |input| {
+            input.windows(5).any(|window| window == b"crash")
+        })
+        .unwrap();
+        assert_eq!(reduction.input, b"crasha");
+        assert!(reduce(&gram, b"crash", |_| true).is_none());
+    }
+}
@@ -0,0 +1,292 @@
+//! Sampling inputs uniformly by the size of their derivation.
+//!
+//! Picking every alternative uniformly at random makes most inputs of
+//! recursive grammars tiny, while a few grow until they reach the depth
+//! limit. `SizedSampler` instead counts the derivations of every fragment by
+//! size, and draws uniformly among all derivations of a requested size. The
+//! size of a derivation is the number of fragments in its tree.
+//!
+//! With `GrammarRust::sized_sampling`, the generated code contains the same
+//! counts as precomputed tables and `generate_sized_into` makes the same
+//! random choices as `SizedSampler::sample_into`.
+
+use rand::Rng;
+
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// Pick an index with probability proportional to its weight. Both the
+/// sampler and the generated code pick this way, to make the same choices.
+fn pick(rng: &mut impl Rng, weights: impl Iterator<Item = f64> + Clone) -> usize {
+    let total: f64 = weights.clone().sum();
+    let mut target = rng.gen::<f64>() * total;
+    let mut last = 0;
+    for (idx, weight) in weights.enumerate() {
+        if weight > 0.0 {
+            last = idx;
+            if target < weight {
+                return idx;
+            }
+            target -= weight;
+        }
+    }
+    last
+}
+
+/// Draws inputs uniformly among the derivations of a given size
+pub struct SizedSampler<'a> {
+    gram: &'a GrammarRust,
+
+    /// Largest size that can be sampled
+    max_size: usize,
+
+    /// Number of derivations of every fragment by size
+    pub(crate) counts: Vec<Vec<f64>>,
+
+    /// Number of derivations of the first `j` elements of an expression, or
+    /// of `j` iterations of a repetition, by size
+    pub(crate) partials: Vec<Vec<Vec<f64>>>,
+}
+
+impl<'a> SizedSampler<'a> {
+    /// Count the derivations of every fragment of `gram` up to `max_size`.
+    /// Sizes whose counts exceed the range of `f64` are left out.
+    pub fn new(gram: &'a GrammarRust, max_size: usize) -> Self {
+        let mut counts = vec![vec![0.0; max_size + 1]; gram.fragments.len()];
+        let mut partials: Vec<Vec<Vec<f64>>> = gram
+            .fragments
+            .iter()
+            .map(|fragment| {
+                // Every iteration adds at least one to the size, so more
+                // than `max_size` iterations never fit
+                let parts = match fragment {
+                    Fragment::Expression(expr) => expr.len() + 1,
+                    Fragment::Repeat { max, .. } => (*max).min(max_size) + 1,
+                    _ => 0,
+                };
+                let mut partial = vec![vec![0.0; max_size + 1]; parts];
+                if let Some(first) = partial.first_mut() {
+                    first[0] = 1.0;
+                }
+                partial
+            })
+            .collect();
+
+        // Every fragment adds one to the size of its children, so the counts
+        // of a size only depend on the counts of smaller sizes
+        let mut size = 1;
+        while size <= max_size {
+            let rem = size - 1;
+            for (idx, fragment) in gram.fragments.iter().enumerate() {
+                let extend = |partial: &mut Vec<Vec<f64>>, child: FragmentId, j: usize| {
+                    partial[j][rem] = (1..=rem)
+                        .map(|s| partial[j - 1][rem - s] * counts[child.0][s])
+                        .sum();
+                };
+                counts[idx][size] = match fragment {
+                    Fragment::NonTerminal(options) => {
+                        options.iter().map(|option| counts[option.0][rem]).sum()
+                    }
+                    Fragment::Expression(expr) => {
+                        for (j, &exp) in expr.iter().enumerate() {
+                            extend(&mut partials[idx], exp, j + 1);
+                        }
+                        partials[idx][expr.len()][rem]
+                    }
+                    &Fragment::Repeat { fragment, min, max } => {
+                        let max = max.min(max_size);
+                        for j in 1..=max {
+                            extend(&mut partials[idx], fragment, j);
+                        }
+                        (min..=max).map(|j| partials[idx][j][rem]).sum()
+                    }
+                    Fragment::Terminal(_) | Fragment::Nop => {
+                        if size == 1 {
+                            1.0
+                        } else {
+                            0.0
+                        }
+                    }
+                    Fragment::Unreachable => 0.0,
+                };
+            }
+
+            let finite = counts.iter().all(|count| count[size].is_finite())
+                && partials
+                    .iter()
+                    .flatten()
+                    .all(|partial| partial[rem].is_finite());
+            if !finite {
+                break;
+            }
+            size += 1;
+        }
+
+        let max_size = size - 1;
+        for count in counts.iter_mut() {
+            count.truncate(max_size + 1);
+        }
+        for partial in partials.iter_mut().flatten() {
+            partial.truncate(max_size + 1);
+        }
+
+        Self {
+            gram,
+            max_size,
+            counts,
+            partials,
+        }
+    }
+
+    /// Largest size that can be sampled
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Number of derivations of the start node of a size
+    pub fn count(&self, size: usize) -> f64 {
+        self.counts[self.gram.start.unwrap().0]
+            .get(size)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Generate an input whose derivation has `size` fragments into `out`,
+    /// which is cleared first. Returns `false` if there is no such
+    /// derivation.
+    pub fn sample_into(&self, size: usize, out: &mut Vec<u8>, rng: &mut impl Rng) -> bool {
+        out.clear();
+        if self.count(size) == 0.0 {
+            return false;
+        }
+        self.sample(self.gram.start.unwrap(), size, out, rng);
+        true
+    }
+
+    /// Split `rem` among `children`, choosing from the last one, where the
+    /// `j`th partial of `id` counts the derivations of the first `j` children
+    fn split(
+        &self,
+        id: FragmentId,
+        children: &[FragmentId],
+        rem: usize,
+        rng: &mut impl Rng,
+    ) -> Vec<usize> {
+        let partial = &self.partials[id.0];
+        let mut sizes = vec![0; children.len()];
+        let mut rem = rem;
+        for j in (1..children.len()).rev() {
+            let child = &self.counts[children[j].0];
+            let s = 1 + pick(rng, (1..=rem).map(|s| partial[j][rem - s] * child[s]));
+            sizes[j] = s;
+            rem -= s;
+        }
+        if let Some(first) = sizes.first_mut() {
+            *first = rem;
+        }
+        sizes
+    }
+
+    fn sample(&self, id: FragmentId, size: usize, out: &mut Vec<u8>, rng: &mut impl Rng) {
+        let rem = size - 1;
+        match &self.gram.fragments[id.0] {
+            Fragment::NonTerminal(options) => {
+                let choice = pick(rng, options.iter().map(|option| self.counts[option.0][rem]));
+                self.sample(options[choice], rem, out, rng);
+            }
+            Fragment::Expression(expr) => {
+                let sizes = self.split(id, expr, rem, rng);
+                for (&exp, size) in expr.iter().zip(sizes) {
+                    self.sample(exp, size, out, rng);
+                }
+            }
+            &Fragment::Repeat { fragment, min, max } => {
+                let partial = &self.partials[id.0];
+                let max = max.min(self.max_size);
+                let count = min + pick(rng, (min..=max).map(|j| partial[j][rem]));
+                let children = vec![fragment; count];
+                for size in self.split(id, &children, rem, rng) {
+                    self.sample(fragment, size, out, rng);
+                }
+            }
+            Fragment::Terminal(value) => out.extend_from_slice(value),
+            Fragment::Nop | Fragment::Unreachable => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::Recognizer;
+    use crate::Grammar;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
+
+    #[test]
+    fn samples_uniformly_by_size() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<tree>"]], "<tree>": [["x"], ["(", "<tree>", "<tree>", ")"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+        let sampler = SizedSampler::new(&gram, 64);
+        let recognizer = Recognizer::new(&gram);
+
+        // All sizes with derivations are sampled at exactly that size
+        let sizes: Vec<usize> = (1..=64).filter(|&size| sampler.count(size) > 0.0).collect();
+        assert!(!sizes.is_empty());
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut out = Vec::new();
+        assert!(!sampler.sample_into(sizes[0] - 1, &mut out, &mut rng));
+
+        // The binary trees with 3 inner nodes are drawn equally often
+        let size = sizes[3];
+        assert_eq!(sampler.count(size), 5.0);
+        let mut seen: HashMap<Vec<u8>, usize> = HashMap::new();
+        for _ in 0..5000 {
+            assert!(sampler.sample_into(size, &mut out, &mut rng));
+            assert!(recognizer.accepts(&out));
+            *seen.entry(out.clone()).or_default() += 1;
+        }
+        assert_eq!(seen.len(), 5);
+        assert!(seen.values().all(|&count| (800..1200).contains(&count)));
+    }
+
+    #[test]
+    fn overflowing_sizes_are_left_out() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>"], ["<digit>", "<start>"]], "<digit>": [["0"], ["1"], ["2"], ["3"], ["4"], ["5"], ["6"], ["7"], ["8"], ["9"]]}"#,
+        )
+        .unwrap();
+        let mut gram = GrammarRust::new(&grammar, None);
+        gram.optimize();
+
+        let sampler = SizedSampler::new(&gram, 100_000);
+        assert!(sampler.max_size() < 100_000);
+        assert!(sampler.count(sampler.max_size()).is_finite());
+    }
+
+    #[test]
+    fn long_repetitions_are_capped() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<digit>{1,4000000000}"]], "<digit>": [["0"], ["1"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+        let sampler = SizedSampler::new(&gram, 32);
+        assert!(sampler.partials.iter().all(|partial| partial.len() <= 33));
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut out = Vec::new();
+        let mut longest = 0;
+        for size in (1..=32).filter(|&size| sampler.count(size) > 0.0) {
+            assert!(sampler.sample_into(size, &mut out, &mut rng));
+            assert!(out.iter().all(|&b| b == b'0' || b == b'1'));
+            longest = longest.max(out.len());
+        }
+        assert!(longest > 1);
+    }
+}
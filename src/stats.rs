@@ -0,0 +1,304 @@
+//! Metrics of a grammar and statistics of the inputs it generates, to see
+//! the effect of a grammar change before committing it.
+
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use crate::interpreter::Visitor;
+use crate::{Fragment, FragmentId, GrammarRust};
+
+/// Size of a grammar
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Shape {
+    /// Number of fragments code is generated for
+    pub fragments: usize,
+
+    /// Number of rules that are still around
+    pub rules: usize,
+
+    /// Number of fragments that choose between alternatives
+    pub choices: usize,
+
+    /// Number of alternatives of all choices
+    pub alternatives: usize,
+
+    /// Number of terminal fragments
+    pub terminals: usize,
+}
+
+impl Shape {
+    /// Measure the fragments and rules `gram` generates code for
+    pub fn of(gram: &GrammarRust) -> Self {
+        let mut shape = Shape {
+            fragments: gram.fragment_count(),
+            rules: gram
+                .name_to_fragment
+                .values()
+                .filter(|id| !matches!(gram.fragments[id.0], Fragment::Unreachable))
+                .count(),
+            ..Default::default()
+        };
+        for fragment in gram.fragments.iter() {
+            match fragment {
+                Fragment::NonTerminal(options) => {
+                    shape.choices += 1;
+                    shape.alternatives += options.len();
+                }
+                Fragment::Terminal(_) => shape.terminals += 1,
+                _ => {}
+            }
+        }
+        shape
+    }
+}
+
+/// The groups of rules that recurse through each other. A group of a single
+/// rule uses itself directly or through fragments of no other rule. Groups
+/// are sorted by size, largest first, and rules by name.
+pub fn recursion(gram: &GrammarRust) -> Vec<Vec<String>> {
+    let mut names: Vec<Vec<&str>> = vec![Vec::new(); gram.fragments.len()];
+    for (name, id) in gram.name_to_fragment.iter() {
+        names[id.0].push(name);
+    }
+
+    let mut groups: Vec<Vec<String>> = components(gram)
+        .into_iter()
+        .filter(|component| {
+            component.len() > 1
+                || gram.fragments[component[0].0]
+                    .children()
+                    .contains(&component[0])
+        })
+        .map(|component| {
+            let mut rules: Vec<String> = component
+                .iter()
+                .flat_map(|id| names[id.0].iter().map(|name| name.to_string()))
+                .collect();
+            rules.sort();
+            rules
+        })
+        .filter(|rules| !rules.is_empty())
+        .collect();
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    groups
+}
+
+/// Strongly connected components of the fragment graph, with Tarjan's
+/// algorithm. It is iterative, as grammars can nest deeper than the stack.
+fn components(gram: &GrammarRust) -> Vec<Vec<FragmentId>> {
+    let count = gram.fragments.len();
+    let mut index = vec![usize::MAX; count];
+    let mut lowlink = vec![0; count];
+    let mut on_stack = vec![false; count];
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let mut next_index = 0;
+
+    for root in 0..count {
+        if index[root] != usize::MAX {
+            continue;
+        }
+
+        // Fragments being visited and the next child to visit of each
+        let mut work = vec![(root, 0)];
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&mut (node, ref mut child)) = work.last_mut() {
+            let children = gram.fragments[node].children();
+            if let Some(next) = children.get(*child) {
+                *child += 1;
+                let next = next.0;
+                if index[next] == usize::MAX {
+                    index[next] = next_index;
+                    lowlink[next] = next_index;
+                    next_index += 1;
+                    stack.push(next);
+                    on_stack[next] = true;
+                    work.push((next, 0));
+                } else if on_stack[next] {
+                    lowlink[node] = lowlink[node].min(index[next]);
+                }
+                continue;
+            }
+
+            work.pop();
+            if let Some(&(parent, _)) = work.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[node]);
+            }
+            if lowlink[node] == index[node] {
+                let mut component = Vec::new();
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack[member] = false;
+                    component.push(FragmentId(member));
+                    if member == node {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+
+    components
+}
+
+/// Statistics of inputs generated from a grammar
+#[derive(Clone, Debug, Default)]
+pub struct Sampled {
+    /// Number of generated inputs
+    pub samples: usize,
+
+    /// Length of the shortest input
+    pub min_len: usize,
+
+    /// Length of the longest input
+    pub max_len: usize,
+
+    /// Total length of all inputs
+    pub total_len: u64,
+
+    /// Number of inputs that expanded a fragment at depth `d`, for every `d`
+    /// up to the maximum depth. These inputs are cut short by a maximum
+    /// depth of `d`.
+    pub reached: Vec<usize>,
+
+    /// Number of times every terminal value was emitted
+    pub terminals: HashMap<Vec<u8>, u64>,
+}
+
+impl Sampled {
+    /// Average length of the inputs, 0 if none were generated
+    pub fn avg_len(&self) -> f64 {
+        self.total_len as f64 / self.samples.max(1) as f64
+    }
+
+    /// Fraction of inputs the depth limit cuts short with a maximum depth of
+    /// `depth`. No input reached a depth beyond the one that was sampled
+    /// with, so the rate is 0 for those.
+    pub fn truncation_rate(&self, depth: usize) -> f64 {
+        let reached = self.reached.get(depth).copied().unwrap_or(0);
+        reached as f64 / self.samples.max(1) as f64
+    }
+
+    /// The `count` most often emitted terminal values, most used first
+    pub fn top_terminals(&self, count: usize) -> Vec<(&[u8], u64)> {
+        let mut terminals: Vec<(&[u8], u64)> = self
+            .terminals
+            .iter()
+            .map(|(value, &uses)| (&value[..], uses))
+            .collect();
+        terminals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        terminals.truncate(count);
+        terminals
+    }
+}
+
+/// Generate `samples` inputs with a maximum depth of `max_depth`, exactly
+/// like `GrammarRust::generate_into`, and collect their statistics
+pub fn sample(gram: &GrammarRust, samples: usize, max_depth: usize, rng: &mut impl Rng) -> Sampled {
+    let mut sampled = Sampled {
+        samples,
+        min_len: usize::MAX,
+        reached: vec![0; max_depth + 1],
+        ..Default::default()
+    };
+    let mut walk = Walk::default();
+    for _ in 0..samples {
+        walk.len = 0;
+        walk.deepest = 0;
+        gram.visit(gram.start.unwrap(), max_depth, &mut walk, rng);
+
+        sampled.min_len = sampled.min_len.min(walk.len);
+        sampled.max_len = sampled.max_len.max(walk.len);
+        sampled.total_len += walk.len as u64;
+        for reached in sampled.reached[..=walk.deepest].iter_mut() {
+            *reached += 1;
+        }
+    }
+    if samples == 0 {
+        sampled.min_len = 0;
+    }
+
+    // Terminals are counted by value, merged terminals may share one
+    for (id, uses) in walk.terminals {
+        if let Fragment::Terminal(value) = &gram.fragments[id.0] {
+            *sampled.terminals.entry(value.clone()).or_default() += uses;
+        }
+    }
+    sampled
+}
+
+/// What is recorded while generating inputs
+#[derive(Default)]
+struct Walk {
+    /// Length of the current input
+    len: usize,
+
+    /// Deepest depth a fragment of the current input was expanded at
+    deepest: usize,
+
+    /// Number of times every terminal was emitted
+    terminals: HashMap<FragmentId, u64>,
+}
+
+impl Visitor for Walk {
+    fn enter(&mut self, _id: FragmentId, depth: usize) {
+        self.deepest = self.deepest.max(depth);
+    }
+
+    fn emit(&mut self, id: FragmentId, value: &[u8]) {
+        self.len += value.len();
+        *self.terminals.entry(id).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Grammar;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn reports_recursion_and_truncation() {
+        let grammar: Grammar = serde_json::from_str(
+            r#"{"<start>": [["<list>"]], "<list>": [["x"], ["<item>", ",", "<list>"]], "<item>": [["(", "<list>", ")"], ["y"]], "<leaf>": [["<leaf>"]]}"#,
+        )
+        .unwrap();
+        let gram = GrammarRust::new(&grammar, None);
+        assert_eq!(Shape::of(&gram).rules, 4);
+        assert_eq!(
+            recursion(&gram),
+            vec![
+                vec!["<item>".to_string(), "<list>".to_string()],
+                vec!["<leaf>".to_string()]
+            ]
+        );
+
+        let mut optimized = gram.clone();
+        optimized.optimize();
+        let sampled = sample(&optimized, 1000, 16, &mut StdRng::seed_from_u64(0));
+        assert_eq!(sampled.truncation_rate(0), 1.0);
+        assert!(sampled.truncation_rate(16) > 0.0);
+        assert!(sampled.truncation_rate(16) < 1.0);
+        assert_eq!(sampled.truncation_rate(17), 0.0);
+        assert!(sampled.min_len >= 1);
+        assert!(sampled.max_len as f64 >= sampled.avg_len());
+
+        // The generated inputs are the same as the interpreter's
+        let mut rng = StdRng::seed_from_u64(1);
+        let expected: Vec<Vec<u8>> = (0..10)
+            .map(|_| optimized.generate_new(16, &mut rng))
+            .collect();
+        let total: u64 = expected.iter().map(|input| input.len() as u64).sum();
+        let sampled = sample(&optimized, 10, 16, &mut StdRng::seed_from_u64(1));
+        assert_eq!(sampled.total_len, total);
+        assert_eq!(sampled.top_terminals(1)[0].0, b"x");
+    }
+}